@@ -0,0 +1,344 @@
+use crate::avi::{chunk_text, parse_icrd, riff_chunks};
+use crate::extract::Extracted;
+use crate::photo::MediaKind;
+use crate::pserror::error::{PsError, PsErrorKind};
+use crate::xmp::parse_xmp_date;
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+use log::info;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// Offsets of the description, origination date and time in a Broadcast WAVE `bext` chunk. The
+/// originator and its reference, 32 bytes each, come between description and date.
+const BEXT_DESCRIPTION: usize = 0;
+const BEXT_DATE: usize = 320;
+const BEXT_TIME: usize = 330;
+
+fn format_error(msg: String) -> PsError {
+    return PsError::new(PsErrorKind::FormatError, msg);
+}
+
+/// Digits of `text`, ignoring the separators, which the BWF specification leaves open.
+fn digits(text: &str) -> String {
+    return text.chars().filter(|c| c.is_ascii_digit()).collect();
+}
+
+/// Parses the origination date (`yyyy-mm-dd`) and time (`hh:mm:ss`) of a `bext` chunk. Recorders
+/// without a clock leave the time empty, which is midnight.
+fn parse_bext_date(date: &str, time: &str) -> Option<NaiveDateTime> {
+    let day = NaiveDate::parse_from_str(&digits(date), "%Y%m%d").ok()?;
+    let time = match digits(time).as_str() {
+        "" => NaiveTime::from_hms(0, 0, 0),
+        time => NaiveTime::parse_from_str(time, "%H%M%S").ok()?,
+    };
+    return Some(day.and_time(time));
+}
+
+/// Reads the origination date and description of a Broadcast WAVE file, then the `ICRD` date of
+/// its `INFO` list. The sample data isn't read.
+fn read_wav(path: &Path) -> Result<Extracted, PsError> {
+    let mut extracted = Extracted::default();
+    let chunks = riff_chunks(
+        &mut File::open(path)?,
+        b"WAVE",
+        &[b"INFO"],
+        &[b"bext", b"ICRD"],
+    )?;
+    for (id, data) in chunks {
+        match &id {
+            b"bext" if data.len() >= BEXT_TIME + 8 => {
+                let text = |from: usize, to: usize| chunk_text(&data[from..to]);
+                let description = text(BEXT_DESCRIPTION, BEXT_DESCRIPTION + 256);
+                if !description.is_empty() {
+                    extracted.metadata.caption = Some(description);
+                }
+                let (date, time) = (text(BEXT_DATE, BEXT_TIME), text(BEXT_TIME, BEXT_TIME + 8));
+                match parse_bext_date(&date, &time) {
+                    Some(date) => extracted
+                        .dates
+                        .push(("BWF OriginationDate".to_string(), date)),
+                    None => info!("Couldn't parse bext date {} {} in {:?}", date, time, path),
+                }
+            }
+            b"ICRD" => {
+                if let Some(date) = parse_icrd(&chunk_text(&data)) {
+                    extracted.dates.push(("WAV ICRD".to_string(), date));
+                }
+            }
+            _ => {}
+        }
+    }
+    // The origination date is what the recorder wrote, ICRD may have been set by an editor.
+    extracted
+        .dates
+        .sort_by_key(|(source, _)| source != "BWF OriginationDate");
+    return Ok(extracted);
+}
+
+/// Size in an ID3v2 header or v2.4 frame header, seven bits per byte.
+fn syncsafe(b: &[u8]) -> usize {
+    return b
+        .iter()
+        .fold(0, |size, &byte| size << 7 | (byte & 0x7F) as usize);
+}
+
+/// Text of an ID3v2 text frame: an encoding byte, then Latin-1, UTF-16 with a byte order mark,
+/// UTF-16BE or UTF-8. Only the first of several NUL-separated values is kept.
+fn frame_text(data: &[u8]) -> String {
+    let (encoding, text) = match data.split_first() {
+        Some(split) => split,
+        None => return String::new(),
+    };
+    let utf16 = |text: &[u8], big_endian: bool| {
+        let units: Vec<u16> = text
+            .chunks_exact(2)
+            .map(|b| {
+                if big_endian {
+                    u16::from_be_bytes([b[0], b[1]])
+                } else {
+                    u16::from_le_bytes([b[0], b[1]])
+                }
+            })
+            .collect();
+        String::from_utf16_lossy(&units)
+    };
+    let text = match encoding {
+        0 => text.iter().map(|&b| b as char).collect(),
+        1 if text.starts_with(b"\xff\xfe") => utf16(&text[2..], false),
+        1 if text.starts_with(b"\xfe\xff") => utf16(&text[2..], true),
+        1 | 2 => utf16(text, true),
+        _ => String::from_utf8_lossy(text).to_string(),
+    };
+    return text
+        .split('\0')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_string();
+}
+
+/// Text frames of an ID3v2.3 or v2.4 tag at the start of `data`, as id and text.
+fn id3_frames(data: &[u8]) -> Option<Vec<(String, String)>> {
+    if !data.starts_with(b"ID3") {
+        return None;
+    }
+    let version = *data.get(3)?;
+    if version != 3 && version != 4 {
+        info!("ID3v2.{} isn't supported", version);
+        return None;
+    }
+    let end = (10 + syncsafe(data.get(6..10)?)).min(data.len());
+    let mut pos = 10;
+    // An extended header, whose size includes itself in v2.4 but not in v2.3.
+    if data[5] & 0x40 != 0 {
+        let size = data.get(10..14)?;
+        pos += match version {
+            4 => syncsafe(size),
+            _ => 4 + u32::from_be_bytes([size[0], size[1], size[2], size[3]]) as usize,
+        };
+    }
+
+    let mut frames = Vec::new();
+    while pos + 10 <= end {
+        let header = &data[pos..pos + 10];
+        // Padding follows the last frame.
+        if header[0] == 0 {
+            break;
+        }
+        let size = match version {
+            4 => syncsafe(&header[4..8]),
+            _ => u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize,
+        };
+        let frame = data.get(pos + 10..pos + 10 + size)?;
+        if header[0] == b'T' {
+            let id = String::from_utf8_lossy(&header[..4]).to_string();
+            frames.push((id, frame_text(frame)));
+        }
+        pos += 10 + size;
+    }
+    return Some(frames);
+}
+
+/// Recording date of an ID3v2 tag: `TDRC` of v2.4, or the year, `DDMM` day and `HHMM` time of
+/// `TYER`, `TDAT` and `TIME` in v2.3. A date with only a year or month isn't enough to sort by.
+fn id3_date(frames: &[(String, String)]) -> Option<(&'static str, NaiveDateTime)> {
+    let frame = |id: &str| {
+        frames
+            .iter()
+            .find(|(frame_id, text)| frame_id == id && !text.is_empty())
+            .map(|(_, text)| text.as_str())
+    };
+    if let Some(tdrc) = frame("TDRC") {
+        // Times may stop at the hour.
+        let tdrc = match tdrc.len() {
+            13 => format!("{}:00", tdrc),
+            _ => tdrc.to_string(),
+        };
+        return parse_xmp_date(&tdrc).map(|(date, _, _)| ("ID3 TDRC", date));
+    }
+    let (year, day) = (frame("TYER")?, frame("TDAT")?);
+    let time = frame("TIME").unwrap_or("0000");
+    let date = NaiveDate::parse_from_str(&format!("{}{}", year, day), "%Y%d%m").ok()?;
+    let time = NaiveTime::parse_from_str(time, "%H%M").ok()?;
+    return Some(("ID3 TYER", date.and_time(time)));
+}
+
+/// Reads the recording date and title of an MP3 from its ID3v2 tag.
+fn read_id3(path: &Path) -> Result<Extracted, PsError> {
+    let mut header = [0u8; 10];
+    let mut file = File::open(path)?;
+    file.read_exact(&mut header)?;
+    let size = 10 + syncsafe(&header[6..10]);
+    let mut data = header.to_vec();
+    file.take(size as u64 - 10).read_to_end(&mut data)?;
+    let frames = match id3_frames(&data) {
+        Some(frames) => frames,
+        None => return Err(format_error("No ID3v2 tag".to_string())),
+    };
+    info!("ID3 of {:?}: {:?}", path, frames);
+
+    let mut extracted = Extracted::default();
+    extracted.metadata.title = frames
+        .iter()
+        .find(|(id, text)| id == "TIT2" && !text.is_empty())
+        .map(|(_, title)| title.clone());
+    if let Some((source, date)) = id3_date(&frames) {
+        extracted.dates.push((source.to_string(), date));
+    }
+    return Ok(extracted);
+}
+
+/// Reads the recording date of a WAV (Broadcast WAVE `bext` and `INFO`) or MP3 (ID3v2) file, as
+/// field recorders and phones write them. M4A files are MP4 and read as such.
+pub fn read_audio(path: &Path) -> Result<Extracted, PsError> {
+    let mut magic = [0u8; 4];
+    File::open(path)?.read_exact(&mut magic)?;
+    let mut extracted = match &magic {
+        b"RIFF" => read_wav(path)?,
+        _ if magic.starts_with(b"ID3") => read_id3(path)?,
+        _ => return Err(format_error("Neither WAV nor MP3 with ID3".to_string())),
+    };
+    extracted.metadata.kind = Some(MediaKind::Audio);
+    return Ok(extracted);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(data);
+        return chunk;
+    }
+
+    fn id3_frame(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut frame = id.to_vec();
+        frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&[0, 0]);
+        frame.extend_from_slice(data);
+        return frame;
+    }
+
+    fn id3_tag(version: u8, frames: &[Vec<u8>]) -> Vec<u8> {
+        let frames = frames.concat();
+        // Sizes are below 128, so they're the same syncsafe or not.
+        let mut tag = vec![b'I', b'D', b'3', version, 0, 0, 0, 0, 0];
+        tag.push(frames.len() as u8 + 16);
+        tag.extend(frames);
+        tag.extend_from_slice(&[0u8; 16]);
+        tag.extend_from_slice(b"\xff\xfb\x90\x00");
+        return tag;
+    }
+
+    #[test]
+    fn test_read_wav() -> Result<(), PsError> {
+        let mut bext = vec![0u8; 602];
+        bext[..11].copy_from_slice(b"Interview 1");
+        bext[256..263].copy_from_slice(b"ZOOM H5");
+        bext[BEXT_DATE..BEXT_DATE + 10].copy_from_slice(b"2021-03-06");
+        bext[BEXT_TIME..BEXT_TIME + 8].copy_from_slice(b"16:47:13");
+        let mut wave = b"WAVE".to_vec();
+        wave.extend(chunk(b"fmt ", &[0u8; 16]));
+        wave.extend(chunk(b"bext", &bext));
+        wave.extend(chunk(b"data", &[0u8; 64]));
+        let mut info = b"INFO".to_vec();
+        info.extend(chunk(b"ICRD", b"2021-03-08\0\0"));
+        wave.extend(chunk(b"LIST", &info));
+
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("ZOOM0001.WAV");
+        std::fs::write(&path, chunk(b"RIFF", &wave))?;
+        let extracted = read_audio(&path)?;
+        assert_eq!(
+            extracted.dates,
+            vec![
+                (
+                    "BWF OriginationDate".to_string(),
+                    NaiveDate::from_ymd(2021, 3, 6).and_hms(16, 47, 13)
+                ),
+                (
+                    "WAV ICRD".to_string(),
+                    NaiveDate::from_ymd(2021, 3, 8).and_hms(0, 0, 0)
+                ),
+            ]
+        );
+        assert_eq!(extracted.metadata.caption.as_deref(), Some("Interview 1"));
+        assert_eq!(extracted.metadata.kind, Some(MediaKind::Audio));
+        return Ok(());
+    }
+
+    #[test]
+    fn test_read_id3() -> Result<(), PsError> {
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("memo.mp3");
+        std::fs::write(
+            &path,
+            id3_tag(
+                4,
+                &[
+                    id3_frame(b"TIT2", b"\x01\xff\xfeM\0e\0m\0o\0"),
+                    id3_frame(b"TDRC", b"\x032021-03-06T16:47:13"),
+                ],
+            ),
+        )?;
+        let extracted = read_audio(&path)?;
+        assert_eq!(
+            extracted.dates,
+            vec![(
+                "ID3 TDRC".to_string(),
+                NaiveDate::from_ymd(2021, 3, 6).and_hms(16, 47, 13)
+            )]
+        );
+        assert_eq!(extracted.metadata.title.as_deref(), Some("Memo"));
+
+        std::fs::write(
+            &path,
+            id3_tag(
+                3,
+                &[
+                    id3_frame(b"TYER", b"\x002021"),
+                    id3_frame(b"TDAT", b"\x000603"),
+                    id3_frame(b"TIME", b"\x001647"),
+                ],
+            ),
+        )?;
+        assert_eq!(
+            read_audio(&path)?.dates,
+            vec![(
+                "ID3 TYER".to_string(),
+                NaiveDate::from_ymd(2021, 3, 6).and_hms(16, 47, 0)
+            )]
+        );
+
+        // Only a year.
+        std::fs::write(&path, id3_tag(4, &[id3_frame(b"TDRC", b"\x002021")]))?;
+        assert!(read_audio(&path)?.dates.is_empty());
+
+        std::fs::write(&path, b"\xff\xfb\x90\x00")?;
+        assert!(read_audio(&path).is_err());
+        return Ok(());
+    }
+}
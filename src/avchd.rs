@@ -0,0 +1,297 @@
+use crate::extract::Extracted;
+use crate::photo::MediaKind;
+use crate::pserror::error::{PsError, PsErrorKind};
+use chrono::{Datelike, NaiveDate, NaiveDateTime};
+use log::info;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// UUID of the H.264 SEI message holding MDPM, the "modified DV pack meta" of AVCHD cameras.
+const MDPM_UUID: [u8; 16] = [
+    0x17, 0xEE, 0x8C, 0x60, 0xF8, 0x4D, 0x11, 0xD9, 0x8C, 0xD6, 0x08, 0x00, 0x20, 0x0C, 0x9A, 0x66,
+];
+
+/// How much of the stream is searched for MDPM, which comes with the first frames.
+const MDPM_SEARCH_LEN: u64 = 4 << 20;
+
+/// Makers by the code in MDPM tag 0xE0.
+const MAKERS: [(u16, &str); 4] = [
+    (0x0103, "Panasonic"),
+    (0x0108, "Sony"),
+    (0x1011, "Canon"),
+    (0x1104, "JVC"),
+];
+
+fn format_error(msg: String) -> PsError {
+    return PsError::new(PsErrorKind::FormatError, msg);
+}
+
+fn be_u32(data: &[u8], at: usize) -> Option<usize> {
+    let b = data.get(at..at + 4)?;
+    return Some(u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize);
+}
+
+fn bcd(byte: u8) -> Option<u32> {
+    let (high, low) = (byte >> 4, byte & 0x0F);
+    if high > 9 || low > 9 {
+        return None;
+    }
+    return Some((high * 10 + low) as u32);
+}
+
+/// Date and time from BCD year (two bytes), month, day, hour, minute and second.
+fn bcd_date(b: &[u8]) -> Option<NaiveDateTime> {
+    let year = bcd(*b.first()?)? * 100 + bcd(*b.get(1)?)?;
+    return NaiveDate::from_ymd_opt(year as i32, bcd(*b.get(2)?)?, bcd(*b.get(3)?)?)?.and_hms_opt(
+        bcd(*b.get(4)?)?,
+        bcd(*b.get(5)?)?,
+        bcd(*b.get(6)?)?,
+    );
+}
+
+/// Whether `data` starts like an MPEG transport stream, with 188-byte packets or with the 192-byte
+/// packets of AVCHD's `.MTS` and `.M2TS`.
+fn is_transport_stream(data: &[u8]) -> bool {
+    return (data.first() == Some(&0x47) && data.get(188) == Some(&0x47))
+        || (data.get(4) == Some(&0x47) && data.get(196) == Some(&0x47));
+}
+
+/// Removes the emulation prevention bytes H.264 inserts after two zero bytes.
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(data.len());
+    let mut zeros = 0;
+    for &b in data {
+        if zeros >= 2 && b == 3 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if b == 0 { zeros + 1 } else { 0 };
+        result.push(b);
+    }
+    return result;
+}
+
+/// Recording time and maker from MDPM: a count and then entries of a tag and four bytes. Tag 0x18
+/// has the time zone, year and month, 0x19 the day, hour, minute and second, all BCD.
+fn read_mdpm(data: &[u8]) -> Option<(Option<NaiveDateTime>, Option<String>)> {
+    let at = data
+        .windows(MDPM_UUID.len() + 4)
+        .position(|w| w[..16] == MDPM_UUID && &w[16..] == b"MDPM")?;
+    let mdpm = unescape(data.get(at + 20..(at + 20 + 1024).min(data.len()))?);
+    let count = *mdpm.first()? as usize;
+    let mut date = [None; 7];
+    let mut maker = None;
+    for entry in mdpm.get(1..1 + count * 5)?.chunks(5) {
+        match entry[0] {
+            0x18 => {
+                date[0] = Some(entry[2]);
+                date[1] = Some(entry[3]);
+                date[2] = Some(entry[4]);
+            }
+            0x19 => {
+                for (i, b) in entry[1..].iter().enumerate() {
+                    date[3 + i] = Some(*b);
+                }
+            }
+            0xE0 => {
+                let code = u16::from_be_bytes([entry[1], entry[2]]);
+                maker = MAKERS
+                    .iter()
+                    .find(|(c, _)| *c == code)
+                    .map(|(_, name)| name.to_string());
+            }
+            _ => {}
+        }
+    }
+    let date: Option<Vec<u8>> = date.iter().cloned().collect();
+    return Some((date.and_then(|d| bcd_date(&d)), maker));
+}
+
+/// Clip names of the play items of an MPL playlist, in order.
+fn play_items(mpl: &[u8]) -> Option<Vec<String>> {
+    let mut pos = be_u32(mpl, 8)? + 6;
+    let count = u16::from_be_bytes([*mpl.get(pos)?, *mpl.get(pos + 1)?]) as usize;
+    pos += 4;
+    let mut clips = Vec::new();
+    for _ in 0..count {
+        let len = u16::from_be_bytes([*mpl.get(pos)?, *mpl.get(pos + 1)?]) as usize;
+        clips.push(String::from_utf8_lossy(mpl.get(pos + 2..pos + 7)?).to_string());
+        pos += 2 + len;
+    }
+    return Some(clips);
+}
+
+/// Recording time of `clip` from an MPL playlist. The maker extension data holds a BCD time for
+/// every play item; it's only trusted when there is exactly one for each of them.
+fn playlist_date(mpl: &[u8], clip: &str) -> Option<NaiveDateTime> {
+    if !mpl.starts_with(b"MPLS") {
+        return None;
+    }
+    let clips = play_items(mpl)?;
+    let index = clips.iter().position(|c| c == clip)?;
+    let extension = match be_u32(mpl, 16)? {
+        0 => return None,
+        start => mpl.get(start..)?,
+    };
+    let mut dates = Vec::new();
+    let mut pos = 0;
+    while pos + 7 <= extension.len() {
+        match bcd_date(&extension[pos..]).filter(|d| d.year() >= 1990) {
+            Some(date) => {
+                dates.push(date);
+                pos += 7;
+            }
+            None => pos += 1,
+        }
+    }
+    if dates.len() != clips.len() {
+        info!(
+            "{} dates for {} clips in playlist, not using them",
+            dates.len(),
+            clips.len()
+        );
+        return None;
+    }
+    return Some(dates[index]);
+}
+
+/// Playlists of the AVCHD structure a clip is in, `BDMV/PLAYLIST` next to `BDMV/STREAM`.
+fn playlists(path: &Path) -> Vec<PathBuf> {
+    let dir = match path.parent().and_then(|stream| stream.parent()) {
+        Some(bdmv) => bdmv.join("PLAYLIST"),
+        None => return Vec::new(),
+    };
+    let mut playlists: Vec<PathBuf> = match std::fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| {
+                p.extension()
+                    .map(|e| e.eq_ignore_ascii_case("mpl"))
+                    .unwrap_or(false)
+            })
+            .collect(),
+        Err(_) => return Vec::new(),
+    };
+    playlists.sort();
+    return playlists;
+}
+
+/// Reads the recording time of an AVCHD clip (`.MTS` or `.M2TS`): from MDPM in the video stream,
+/// which every frame group carries, and then from the playlists of the AVCHD structure the clip is
+/// in, for when the stream has been re-encoded or cut.
+pub fn read_avchd(path: &Path) -> Result<Extracted, PsError> {
+    let mut data = Vec::new();
+    File::open(path)?
+        .take(MDPM_SEARCH_LEN)
+        .read_to_end(&mut data)?;
+    if !is_transport_stream(&data) {
+        return Err(format_error("Not an MPEG transport stream".to_string()));
+    }
+
+    let mut extracted = Extracted::default();
+    extracted.metadata.kind = Some(MediaKind::Video);
+    if let Some((date, maker)) = read_mdpm(&data) {
+        extracted.metadata.camera.make = maker;
+        if let Some(date) = date {
+            extracted.dates.push(("AVCHD MDPM".to_string(), date));
+        }
+    }
+    let clip = path.file_stem().unwrap_or_default().to_string_lossy();
+    for playlist in playlists(path) {
+        if let Some(date) = playlist_date(&std::fs::read(&playlist)?, &clip) {
+            extracted.dates.push(("AVCHD playlist".to_string(), date));
+            break;
+        }
+    }
+    return Ok(extracted);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two 192-byte packets, the first with an SEI holding MDPM.
+    fn sample_mts() -> Vec<u8> {
+        let mut sei = vec![0, 0, 0, 1, 6, 5, 40];
+        sei.extend_from_slice(&MDPM_UUID);
+        sei.extend_from_slice(b"MDPM");
+        sei.push(3);
+        sei.extend_from_slice(&[0x18, 0x02, 0x20, 0x10, 0x07]);
+        // Midnight, with an emulation prevention byte after the zero hours and minutes.
+        sei.extend_from_slice(&[0x19, 0x14, 0x00, 0x00, 0x03, 0x02]);
+        sei.extend_from_slice(&[0xE0, 0x01, 0x08, 0x00, 0x00]);
+
+        let mut packet = vec![0, 0, 0, 0, 0x47, 0x40, 0x11, 0x10];
+        packet.extend(sei);
+        packet.resize(192, 0xFF);
+        let mut data = packet.clone();
+        data.extend(vec![0, 0, 0, 0, 0x47, 0x00, 0x11, 0x10]);
+        data.resize(384, 0xFF);
+        return data;
+    }
+
+    fn sample_mpl(clips: &[&str], dates: &[[u8; 7]]) -> Vec<u8> {
+        let mut playlist = vec![0, 0, 0, 0, 0, 0];
+        playlist.extend_from_slice(&(clips.len() as u16).to_be_bytes());
+        playlist.extend_from_slice(&[0, 0]);
+        for clip in clips {
+            playlist.extend_from_slice(&[0, 9]);
+            playlist.extend_from_slice(clip.as_bytes());
+            playlist.extend_from_slice(b"M2TS");
+        }
+        let mut extension = b"\0\0\0\0PLEX".to_vec();
+        for date in dates {
+            extension.extend_from_slice(&[0x1E]);
+            extension.extend_from_slice(date);
+        }
+
+        let mut mpl = b"MPLS0100".to_vec();
+        mpl.extend_from_slice(&40u32.to_be_bytes());
+        mpl.extend_from_slice(&0u32.to_be_bytes());
+        mpl.extend_from_slice(&((40 + playlist.len()) as u32).to_be_bytes());
+        mpl.resize(40, 0);
+        mpl.extend(playlist);
+        mpl.extend(extension);
+        return mpl;
+    }
+
+    #[test]
+    fn test_read_avchd() -> Result<(), PsError> {
+        let temp_dir = tempfile::tempdir()?;
+        let bdmv = temp_dir.path().join("PRIVATE/AVCHD/BDMV");
+        std::fs::create_dir_all(bdmv.join("STREAM"))?;
+        std::fs::create_dir_all(bdmv.join("PLAYLIST"))?;
+        let path = bdmv.join("STREAM/00001.MTS");
+        std::fs::write(&path, sample_mts())?;
+        std::fs::write(
+            bdmv.join("PLAYLIST/00000.MPL"),
+            sample_mpl(
+                &["00000", "00001"],
+                &[
+                    [0x20, 0x10, 0x07, 0x14, 0x08, 0x30, 0x00],
+                    [0x20, 0x10, 0x07, 0x14, 0x00, 0x00, 0x02],
+                ],
+            ),
+        )?;
+
+        let extracted = read_avchd(&path)?;
+        let date = NaiveDate::from_ymd(2010, 7, 14).and_hms(0, 0, 2);
+        assert_eq!(
+            extracted.dates,
+            vec![
+                ("AVCHD MDPM".to_string(), date),
+                ("AVCHD playlist".to_string(), date)
+            ]
+        );
+        assert_eq!(extracted.metadata.camera.make.as_deref(), Some("Sony"));
+
+        // A playlist with fewer dates than clips isn't used.
+        let mpl = sample_mpl(&["00000", "00001"], &[[0x20, 0x10, 0x07, 0x14, 0, 0, 0]]);
+        assert_eq!(playlist_date(&mpl, "00000"), None);
+
+        std::fs::write(&path, b"not a stream")?;
+        assert!(read_avchd(&path).is_err());
+        return Ok(());
+    }
+}
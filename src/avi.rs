@@ -0,0 +1,194 @@
+use crate::extract::Extracted;
+use crate::imagetext::parse_text_date;
+use crate::pserror::error::{PsError, PsErrorKind};
+use chrono::{NaiveDate, NaiveDateTime};
+use log::info;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+fn format_error(msg: String) -> PsError {
+    return PsError::new(PsErrorKind::FormatError, msg);
+}
+
+/// A RIFF chunk as id and data.
+type Chunk = ([u8; 4], Vec<u8>);
+
+/// Chunks with one of the `wanted` ids of a RIFF file of type `form`, at the top level or in a
+/// `LIST` of one of the `lists` types. Nothing else, in particular not the media data, is read.
+pub fn riff_chunks(
+    file: &mut File,
+    form: &[u8; 4],
+    lists: &[&[u8; 4]],
+    wanted: &[&[u8; 4]],
+) -> Result<Vec<Chunk>, PsError> {
+    let len = file.metadata()?.len();
+    let mut header = [0u8; 12];
+    file.read_exact(&mut header)?;
+    if &header[..4] != b"RIFF" || &header[8..] != form {
+        return Err(format_error(format!(
+            "Not a RIFF {} file",
+            String::from_utf8_lossy(form).trim()
+        )));
+    }
+
+    let mut chunks = Vec::new();
+    // Lists which are read into, and where each ends.
+    let mut open: Vec<u64> = vec![len];
+    let mut pos = 12;
+    while let Some(&end) = open.last() {
+        if pos + 8 > end {
+            open.pop();
+            pos = end;
+            continue;
+        }
+        let mut chunk_header = [0u8; 12];
+        file.seek(SeekFrom::Start(pos))?;
+        let read = file.read(&mut chunk_header)?;
+        if read < 8 {
+            break;
+        }
+        let mut id = [0u8; 4];
+        id.copy_from_slice(&chunk_header[..4]);
+        let size = u32::from_le_bytes([
+            chunk_header[4],
+            chunk_header[5],
+            chunk_header[6],
+            chunk_header[7],
+        ]) as u64;
+        let next = pos + 8 + size + size % 2;
+        if &id == b"LIST" && read == 12 {
+            if lists.iter().any(|kind| chunk_header[8..] == kind[..]) {
+                open.push(next.min(end));
+                pos += 12;
+                continue;
+            }
+        } else if wanted.contains(&&id) && pos + 8 + size <= end {
+            let mut data = vec![0u8; size as usize];
+            file.seek(SeekFrom::Start(pos + 8))?;
+            file.read_exact(&mut data)?;
+            chunks.push((id, data));
+        }
+        pos = next;
+    }
+    return Ok(chunks);
+}
+
+/// Text of a RIFF chunk, which is Latin-1 and often ends in NULs or a newline.
+pub fn chunk_text(data: &[u8]) -> String {
+    return data
+        .iter()
+        .map(|&b| b as char)
+        .collect::<String>()
+        .trim_matches(|c: char| c.is_whitespace() || c == '\0')
+        .to_string();
+}
+
+/// Parses the date of an `IDIT` chunk, which cameras write like C's `ctime`, e.g.
+/// `THU OCT 26 16:46:04 2006`, or EXIF style.
+pub fn parse_idit(text: &str) -> Option<NaiveDateTime> {
+    // Single-digit days may be padded with a space.
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    return NaiveDateTime::parse_from_str(&text, "%a %b %d %H:%M:%S %Y")
+        .ok()
+        .or_else(|| parse_text_date(&text));
+}
+
+/// Parses the `ICRD` creation date of a RIFF `INFO` list, which should be `YYYY-MM-DD` but is often
+/// written with a time.
+pub fn parse_icrd(text: &str) -> Option<NaiveDateTime> {
+    return parse_text_date(text).or_else(|| {
+        NaiveDate::parse_from_str(text, "%Y-%m-%d")
+            .ok()
+            .map(|day| day.and_hms(0, 0, 0))
+    });
+}
+
+/// Reads the date an AVI was recorded: the `IDIT` chunk, as cameras write it, and then the
+/// `ICRD` creation date of the `INFO` list.
+pub fn read_avi(path: &Path) -> Result<Extracted, PsError> {
+    let mut extracted = Extracted::default();
+    let chunks = riff_chunks(
+        &mut File::open(path)?,
+        b"AVI ",
+        &[b"hdrl", b"INFO"],
+        &[b"IDIT", b"ICRD"],
+    )?;
+    for (id, data) in chunks {
+        let text = chunk_text(&data);
+        let date = match &id {
+            b"IDIT" => parse_idit(&text).map(|date| ("AVI IDIT", date)),
+            b"ICRD" => parse_icrd(&text).map(|date| ("AVI ICRD", date)),
+            _ => continue,
+        };
+        match date {
+            Some((source, date)) => extracted.dates.push((source.to_string(), date)),
+            None => info!("Couldn't parse date {} in {:?}", text, path),
+        }
+    }
+    // IDIT is what the camera recorded, ICRD may have been set by an editor.
+    extracted
+        .dates
+        .sort_by_key(|(source, _)| source != "AVI IDIT");
+    return Ok(extracted);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        return chunk;
+    }
+
+    fn list(kind: &[u8], chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = kind.to_vec();
+        for c in chunks {
+            data.extend_from_slice(c);
+        }
+        return chunk(b"LIST", &data);
+    }
+
+    #[test]
+    fn test_read_avi() -> Result<(), PsError> {
+        let mut avi = b"AVI ".to_vec();
+        avi.extend(list(
+            b"hdrl",
+            &[
+                chunk(b"avih", &[0u8; 56]),
+                list(b"strl", &[chunk(b"strh", &[0u8; 56])]),
+                chunk(b"IDIT", b"THU OCT  5 16:46:04 2006\n\0"),
+            ],
+        ));
+        avi.extend(list(b"INFO", &[chunk(b"ICRD", b"2006-10-07\0")]));
+        avi.extend(list(b"movi", &[chunk(b"00dc", b"IDIT is not in here")]));
+        let file = chunk(b"RIFF", &avi);
+
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("MVI_0001.AVI");
+        std::fs::write(&path, file)?;
+        assert_eq!(
+            read_avi(&path)?.dates,
+            vec![
+                (
+                    "AVI IDIT".to_string(),
+                    NaiveDate::from_ymd(2006, 10, 5).and_hms(16, 46, 4)
+                ),
+                (
+                    "AVI ICRD".to_string(),
+                    NaiveDate::from_ymd(2006, 10, 7).and_hms(0, 0, 0)
+                ),
+            ]
+        );
+
+        std::fs::write(&path, b"RIFF\0\0\0\0WAVE")?;
+        assert!(read_avi(&path).is_err());
+        return Ok(());
+    }
+}
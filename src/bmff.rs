@@ -0,0 +1,457 @@
+use crate::discovery::discovery::parse_iso6709;
+use crate::extract::Extracted;
+use crate::photo::{Camera, MediaKind, Metadata};
+use crate::pserror::error::{PsError, PsErrorKind};
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime};
+use log::info;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// First box of a JPEG XL container.
+pub const JXL_SIGNATURE: &[u8] = b"\0\0\0\x0cJXL \r\n\x87\n";
+
+/// BE7ACFCB-97A9-42E8-9C71-999491E3AFAC, the type of `uuid` boxes holding XMP.
+pub const XMP_UUID: [u8; 16] = [
+    0xBE, 0x7A, 0xCF, 0xCB, 0x97, 0xA9, 0x42, 0xE8, 0x9C, 0x71, 0x99, 0x94, 0x91, 0xE3, 0xAF, 0xAC,
+];
+
+fn format_error(msg: String) -> PsError {
+    return PsError::new(PsErrorKind::FormatError, msg);
+}
+
+/// Box types are Latin-1, e.g. `©day`.
+fn kind_name(kind: &[u8; 4]) -> String {
+    return kind.iter().map(|&b| b as char).collect();
+}
+
+fn be_u32(data: &[u8]) -> Option<u32> {
+    let b = data.get(..4)?;
+    return Some(u32::from_be_bytes([b[0], b[1], b[2], b[3]]));
+}
+
+fn be_u64(data: &[u8]) -> Option<u64> {
+    let b = data.get(..8)?;
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(b);
+    return Some(u64::from_be_bytes(bytes));
+}
+
+/// Top-level boxes of the file as type, offset and size, failing if any runs past its end.
+fn top_level_boxes(file: &mut File) -> Result<Vec<([u8; 4], u64, u64)>, PsError> {
+    let len = file.metadata()?.len();
+    let mut boxes = Vec::new();
+    let mut pos = 0;
+    while pos < len {
+        let mut header = [0u8; 16];
+        file.seek(SeekFrom::Start(pos))?;
+        let read = file.read(&mut header)?;
+        if read < 8 {
+            return Err(format_error(format!(
+                "Truncated box header at offset {}",
+                pos
+            )));
+        }
+        let mut kind = [0u8; 4];
+        kind.copy_from_slice(&header[4..8]);
+        let (header_len, size) = match be_u32(&header).unwrap() {
+            0 => (8, len - pos),
+            1 if read == 16 => (16, be_u64(&header[8..]).unwrap()),
+            1 => {
+                return Err(format_error(format!(
+                    "Truncated box header at offset {}",
+                    pos
+                )))
+            }
+            size => (8, size as u64),
+        };
+        let end = match pos.checked_add(size) {
+            Some(end) if size >= header_len && end <= len => end,
+            _ => {
+                return Err(format_error(format!(
+                    "Box {} at offset {} runs past the end of the file",
+                    kind_name(&kind),
+                    pos
+                )))
+            }
+        };
+        boxes.push((kind, pos + header_len, size - header_len));
+        pos = end;
+    }
+    return Ok(boxes);
+}
+
+/// Checks that the file consists of whole boxes and has a `moov`, which is what a truncated
+/// download or copy usually breaks.
+pub fn check_structure(path: &Path) -> Result<(), PsError> {
+    let boxes = top_level_boxes(&mut File::open(path)?)?;
+    if !boxes.iter().any(|(kind, _, _)| kind == b"moov") {
+        return Err(format_error("No moov box".to_string()));
+    }
+    return Ok(());
+}
+
+/// Payload of the first top-level box of `kind`, read into memory.
+fn read_top_level(file: &mut File, kind: &[u8; 4]) -> Result<Option<Vec<u8>>, PsError> {
+    let (offset, size) = match top_level_boxes(file)?
+        .into_iter()
+        .find(|(k, _, _)| k == kind)
+    {
+        Some((_, offset, size)) => (offset, size),
+        None => return Ok(None),
+    };
+    let mut payload = vec![0u8; size as usize];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut payload)?;
+    return Ok(Some(payload));
+}
+
+/// Children of a box, given its payload. Stops at the first malformed one.
+fn children(data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+    let mut result = Vec::new();
+    let mut pos = 0;
+    while pos + 8 <= data.len() {
+        let mut kind = [0u8; 4];
+        kind.copy_from_slice(&data[pos + 4..pos + 8]);
+        let (header_len, size) = match be_u32(&data[pos..]).unwrap() as usize {
+            0 => (8, data.len() - pos),
+            1 => match be_u64(&data[pos + 8..]) {
+                Some(size) => (16, size as usize),
+                None => break,
+            },
+            size => (8, size),
+        };
+        let end = match pos.checked_add(size) {
+            Some(end) if size >= header_len && end <= data.len() => end,
+            _ => break,
+        };
+        result.push((kind, &data[pos + header_len..end]));
+        pos = end;
+    }
+    return result;
+}
+
+fn child<'a>(data: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    return children(data)
+        .into_iter()
+        .find(|(k, _)| k == kind)
+        .map(|(_, payload)| payload);
+}
+
+/// Creation time of a `mvhd` or `tkhd` box, None if it isn't set.
+fn creation_time(data: &[u8]) -> Option<NaiveDateTime> {
+    let seconds = match data.first()? {
+        1 => be_u64(data.get(4..)?)?,
+        _ => be_u32(data.get(4..)?)? as u64,
+    };
+    if seconds == 0 {
+        return None;
+    }
+    // Seconds since the QuickTime epoch, in UTC.
+    return Some(
+        NaiveDate::from_ymd(1904, 1, 1).and_hms(0, 0, 0) + Duration::seconds(seconds as i64),
+    );
+}
+
+/// Width and height of a video track from its `tkhd` box.
+fn track_size(data: &[u8]) -> Option<(u32, u32)> {
+    let at = match data.first()? {
+        1 => 88,
+        _ => 76,
+    };
+    let width = be_u32(data.get(at..)?)? >> 16;
+    let height = be_u32(data.get(at + 4..)?)? >> 16;
+    if width == 0 || height == 0 {
+        return None;
+    }
+    return Some((width, height));
+}
+
+/// Value of a `data` box of an `ilst` item, if it's text.
+fn item_text(item: &[u8]) -> Option<String> {
+    let data = child(item, b"data")?;
+    // Type 1 is UTF-8.
+    if be_u32(data)? & 0x00FF_FFFF != 1 {
+        return None;
+    }
+    return Some(String::from_utf8_lossy(data.get(8..)?).trim().to_string());
+}
+
+/// Reads QuickTime (`keys` and `ilst`) and iTunes-style (`ilst` only) metadata of a `meta` box.
+fn read_meta(meta: &[u8], tags: &mut HashMap<String, String>) {
+    // In MP4 `meta` is a full box with version and flags, in QuickTime it isn't.
+    let meta = match meta.get(4..8) {
+        Some(b"hdlr") => meta,
+        _ => meta.get(4..).unwrap_or(&[]),
+    };
+    // Entries in `keys` are "boxes" whose type is the namespace, e.g. mdta, and ilst items refer
+    // to them by their 1-based position.
+    let keys: Vec<String> = match child(meta, b"keys") {
+        Some(keys) => children(keys.get(8..).unwrap_or(&[]))
+            .into_iter()
+            .map(|(_, key)| String::from_utf8_lossy(key).to_string())
+            .collect(),
+        None => Vec::new(),
+    };
+    for (kind, item) in children(child(meta, b"ilst").unwrap_or(&[])) {
+        let name = match u32::from_be_bytes(kind) as usize {
+            index if index >= 1 && index <= keys.len() => keys[index - 1].clone(),
+            _ => kind_name(&kind),
+        };
+        if let Some(text) = item_text(item) {
+            tags.entry(name).or_insert(text);
+        }
+    }
+}
+
+/// Reads QuickTime user data, `©xxx` boxes holding length, language and text.
+fn read_udta(udta: &[u8], tags: &mut HashMap<String, String>) {
+    for (kind, payload) in children(udta) {
+        if kind == *b"meta" {
+            read_meta(payload, tags);
+        } else if kind[0] == 0xA9 {
+            let len = match payload.get(..2) {
+                Some(b) => u16::from_be_bytes([b[0], b[1]]) as usize,
+                None => continue,
+            };
+            if let Some(text) = payload.get(4..4 + len) {
+                tags.entry(kind_name(&kind))
+                    .or_insert_with(|| String::from_utf8_lossy(text).trim().to_string());
+            }
+        }
+    }
+}
+
+/// Parses dates such as `2021-03-06T16:47:13+0100`, keeping the local time they tell.
+pub fn parse_quicktime_date(date: &str) -> Option<NaiveDateTime> {
+    let date = date.trim();
+    for format in &["%Y-%m-%dT%H:%M:%S%z", "%Y-%m-%dT%H:%M:%S%.f%z"] {
+        if let Ok(date) = DateTime::parse_from_str(date, format) {
+            return Some(date.naive_local());
+        }
+    }
+    if let Ok(date) = DateTime::parse_from_rfc3339(date) {
+        return Some(date.naive_local());
+    }
+    return NaiveDateTime::parse_from_str(date, "%Y-%m-%dT%H:%M:%S").ok();
+}
+
+/// Reads dates and metadata of an MP4 or MOV file without ffmpeg. Only the `moov` box is read into
+/// memory, media data is skipped over. Dates are the QuickTime creation date first, as it keeps
+/// the local time, then the `©day` tag and the creation times of the movie and its tracks.
+pub fn read_bmff(path: &Path) -> Result<Extracted, PsError> {
+    let moov = match read_top_level(&mut File::open(path)?, b"moov")? {
+        Some(moov) => moov,
+        None => return Err(format_error("No moov box".to_string())),
+    };
+
+    let mut tags = HashMap::new();
+    if let Some(meta) = child(&moov, b"meta") {
+        read_meta(meta, &mut tags);
+    }
+    if let Some(udta) = child(&moov, b"udta") {
+        read_udta(udta, &mut tags);
+    }
+    info!("Metadata of {:?}: {:?}", path, tags);
+    let tag = |keys: &[&str]| {
+        keys.iter()
+            .filter_map(|key| tags.get(*key))
+            .find(|value| !value.is_empty())
+            .cloned()
+    };
+
+    let mut extracted = Extracted {
+        metadata: Metadata {
+            kind: Some(MediaKind::Video),
+            camera: Camera {
+                make: tag(&["com.apple.quicktime.make", "©mak"]),
+                model: tag(&["com.apple.quicktime.model", "©mod"]),
+                serial: None,
+            },
+            gps: tag(&["com.apple.quicktime.location.ISO6709", "©xyz"])
+                .and_then(|location| parse_iso6709(&location)),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    for (key, source) in &[
+        ("com.apple.quicktime.creationdate", "QuickTime creationdate"),
+        ("©day", "QuickTime ©day"),
+    ] {
+        if let Some(date) = tags.get(*key).and_then(|d| parse_quicktime_date(d)) {
+            extracted.dates.push((source.to_string(), date));
+        }
+    }
+    if let Some(date) = child(&moov, b"mvhd").and_then(creation_time) {
+        extracted
+            .dates
+            .push(("container creation_time".to_string(), date));
+    }
+    let tracks = children(&moov)
+        .into_iter()
+        .filter(|(kind, _)| kind == b"trak")
+        .filter_map(|(_, trak)| child(trak, b"tkhd"));
+    for (track_num, tkhd) in tracks.enumerate() {
+        if let Some(date) = creation_time(tkhd) {
+            extracted
+                .dates
+                .push((format!("stream {} creation_time", track_num), date));
+        }
+        if extracted.metadata.width.is_none() {
+            if let Some((width, height)) = track_size(tkhd) {
+                extracted.metadata.width = Some(width);
+                extracted.metadata.height = Some(height);
+            }
+        }
+    }
+    return Ok(extracted);
+}
+
+/// Embedded XMP packet of an MP4 or MOV file: a top-level `uuid` box with the XMP UUID, as MP4
+/// writers store it, or QuickTime's `XMP_` box in the movie's user data.
+pub fn read_xmp_packet(path: &Path) -> Result<Option<Vec<u8>>, PsError> {
+    let mut file = File::open(path)?;
+    for (kind, offset, size) in top_level_boxes(&mut file)? {
+        if kind == *b"uuid" && size > XMP_UUID.len() as u64 {
+            let mut uuid = [0u8; 16];
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut uuid)?;
+            if uuid == XMP_UUID {
+                let mut packet = vec![0u8; (size - 16) as usize];
+                file.read_exact(&mut packet)?;
+                return Ok(Some(packet));
+            }
+        }
+    }
+    return Ok(read_top_level(&mut file, b"moov")?
+        .as_ref()
+        .and_then(|moov| child(moov, b"udta"))
+        .and_then(|udta| child(udta, b"XMP_"))
+        .map(|packet| packet.to_vec()));
+}
+
+/// Raw EXIF (a TIFF structure) of a JPEG XL file, None if the file isn't in the JPEG XL container,
+/// which is made of ISO BMFF boxes. The `Exif` box starts with the offset of the TIFF header.
+/// Brotli-compressed `brob` boxes aren't read.
+pub fn read_jxl_exif(path: &Path) -> Result<Option<Vec<u8>>, PsError> {
+    let mut file = File::open(path)?;
+    let mut signature = [0u8; 12];
+    if file.read(&mut signature)? < 12 || &signature[..] != JXL_SIGNATURE {
+        return Ok(None);
+    }
+    let payload = match read_top_level(&mut file, b"Exif")? {
+        Some(payload) => payload,
+        None => return Err(format_error("No Exif box".to_string())),
+    };
+    let offset = be_u32(&payload).ok_or_else(|| format_error("Empty Exif box".to_string()))?;
+    return match payload.get(4 + offset as usize..) {
+        Some(tiff) => Ok(Some(tiff.to_vec())),
+        None => Err(format_error(format!("Bad TIFF header offset {}", offset))),
+    };
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    pub fn mp4_box(kind: &[u8], payload: &[u8]) -> Vec<u8> {
+        let mut data = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(kind);
+        data.extend_from_slice(payload);
+        return data;
+    }
+
+    fn data_box(text: &str) -> Vec<u8> {
+        let mut payload = vec![0, 0, 0, 1, 0, 0, 0, 0];
+        payload.extend_from_slice(text.as_bytes());
+        return mp4_box(b"data", &payload);
+    }
+
+    /// A small MOV as an iPhone writes it, without media data.
+    pub fn sample_mov() -> Vec<u8> {
+        // 2011-11-05 02:51:16 UTC since 1904.
+        let seconds: u32 = 3_403_306_276;
+        let mut mvhd = vec![0u8; 100];
+        mvhd[4..8].copy_from_slice(&seconds.to_be_bytes());
+        let mut tkhd = vec![0u8; 84];
+        tkhd[4..8].copy_from_slice(&seconds.to_be_bytes());
+        tkhd[76..80].copy_from_slice(&(1920u32 << 16).to_be_bytes());
+        tkhd[80..84].copy_from_slice(&(1080u32 << 16).to_be_bytes());
+
+        let mut keys = vec![0, 0, 0, 0, 0, 0, 0, 2];
+        keys.extend(mp4_box(b"mdta", b"com.apple.quicktime.creationdate"));
+        keys.extend(mp4_box(b"mdta", b"com.apple.quicktime.model"));
+        let mut ilst = mp4_box(&1u32.to_be_bytes(), &data_box("2011-11-05T03:51:16+0100"));
+        ilst.extend(mp4_box(&2u32.to_be_bytes(), &data_box("iPhone 4S")));
+        let mut meta = mp4_box(b"hdlr", &[0u8; 25]);
+        meta.extend(mp4_box(b"keys", &keys));
+        meta.extend(mp4_box(b"ilst", &ilst));
+
+        let mut xyz = vec![0, 18, 0x15, 0xc7];
+        xyz.extend_from_slice(b"+37.7858-122.4064/");
+
+        let mut moov = mp4_box(b"mvhd", &mvhd);
+        moov.extend(mp4_box(b"trak", &mp4_box(b"tkhd", &tkhd)));
+        moov.extend(mp4_box(b"meta", &meta));
+        moov.extend(mp4_box(b"udta", &mp4_box(b"\xa9xyz", &xyz)));
+
+        let mut file = mp4_box(b"ftyp", b"qt  \0\0\0\0qt  ");
+        file.extend(mp4_box(b"moov", &moov));
+        file.extend(mp4_box(b"mdat", &[0u8; 64]));
+        return file;
+    }
+
+    #[test]
+    fn test_read_bmff() -> Result<(), PsError> {
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("IMG_0001.MOV");
+        std::fs::write(&path, sample_mov())?;
+
+        let extracted = read_bmff(&path)?;
+        let dates: Vec<_> = extracted
+            .dates
+            .iter()
+            .map(|(source, date)| (source.as_str(), date.to_string()))
+            .collect();
+        assert_eq!(
+            dates,
+            vec![
+                ("QuickTime creationdate", "2011-11-05 03:51:16".to_string()),
+                ("container creation_time", "2011-11-05 02:51:16".to_string()),
+                ("stream 0 creation_time", "2011-11-05 02:51:16".to_string()),
+            ]
+        );
+        let metadata = extracted.metadata;
+        assert_eq!(metadata.camera.model.as_deref(), Some("iPhone 4S"));
+        assert_eq!((metadata.width, metadata.height), (Some(1920), Some(1080)));
+        assert_eq!(metadata.gps.unwrap().longitude, -122.4064);
+
+        check_structure(&path)?;
+        let data = sample_mov();
+        std::fs::write(&path, &data[..data.len() - 10])?;
+        assert!(check_structure(&path).is_err());
+
+        // A 64-bit size that would overflow the offset is a malformed box, not a panic.
+        let mut huge = mp4_box(b"free", &[]);
+        huge.extend(&[0, 0, 0, 1]);
+        huge.extend(b"mdat");
+        huge.extend(&u64::MAX.to_be_bytes());
+        std::fs::write(&path, &huge)?;
+        assert!(check_structure(&path).is_err());
+        assert_eq!(children(&huge).len(), 1);
+        return Ok(());
+    }
+
+    #[test]
+    fn test_parse_quicktime_date() {
+        let date = NaiveDate::from_ymd(2021, 3, 6).and_hms(16, 47, 13);
+        assert_eq!(parse_quicktime_date("2021-03-06T16:47:13+0100"), Some(date));
+        assert_eq!(
+            parse_quicktime_date("2021-03-06T16:47:13-05:00"),
+            Some(date)
+        );
+        assert_eq!(parse_quicktime_date("2021-03-06T16:47:13Z"), Some(date));
+        assert_eq!(parse_quicktime_date("2021"), None);
+    }
+}
@@ -0,0 +1,445 @@
+use crate::photo::{Camera, Photo};
+use crate::pserror::error::{PsError, PsErrorKind};
+use chrono::{Duration, NaiveDate, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// A clock rule as written in the rules file, e.g.
+///
+/// ```json
+/// [{"model": "Canon EOS 40D", "from": "2021-06-01", "to": "2021-06-14", "offset": "+01:13"}]
+/// ```
+///
+/// All of make, model and serial which are given must match, case-insensitively. `from` and `to`
+/// are inclusive and compared with the date the camera recorded, before correction; a day without
+/// time means its start for `from` and its end for `to`.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct ClockRuleSpec {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub make: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+    /// Added to the recorded date, `[+-]HH:MM[:SS]`.
+    pub offset: String,
+}
+
+/// A parsed clock rule: photos it matches have `offset` added to their date.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClockRule {
+    spec: ClockRuleSpec,
+    from: Option<NaiveDateTime>,
+    to: Option<NaiveDateTime>,
+    offset: Duration,
+}
+
+/// A correction which has been applied to a photo.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Correction {
+    pub original: NaiveDateTime,
+    pub corrected: NaiveDateTime,
+    pub rule: String,
+}
+
+fn rule_error(msg: String) -> PsError {
+    return PsError::new(PsErrorKind::FormatError, msg);
+}
+
+/// Parses `[+-]HH:MM[:SS]`.
+pub fn parse_offset(offset: &str) -> Result<Duration, PsError> {
+    let (sign, rest) = match offset.chars().next() {
+        Some('-') => (-1, &offset[1..]),
+        Some('+') => (1, &offset[1..]),
+        _ => (1, offset),
+    };
+    let parts: Vec<&str> = rest.split(':').collect();
+    if parts.len() < 2 || parts.len() > 3 {
+        return Err(rule_error(format!("Bad clock offset {}", offset)));
+    }
+    let mut seconds: i64 = 0;
+    for (part, unit) in parts.iter().zip(&[3600, 60, 1]) {
+        let value: i64 = part
+            .parse()
+            .map_err(|_| rule_error(format!("Bad clock offset {}", offset)))?;
+        seconds += value * unit;
+    }
+    return Ok(Duration::seconds(sign * seconds));
+}
+
+/// Formats a duration the way `parse_offset` reads it.
+pub fn format_offset(offset: Duration) -> String {
+    let sign = if offset < Duration::zero() { '-' } else { '+' };
+    let seconds = offset.num_seconds().abs();
+    return format!(
+        "{}{:02}:{:02}:{:02}",
+        sign,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    );
+}
+
+/// How proposed rules write `from` and `to`, which may also be just a day or use a space.
+const BOUND_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+
+fn parse_bound(value: &Option<String>, end_of_day: bool) -> Result<Option<NaiveDateTime>, PsError> {
+    let value = match value {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    for format in &[BOUND_FORMAT, "%Y-%m-%d %H:%M:%S"] {
+        if let Ok(date) = NaiveDateTime::parse_from_str(value, format) {
+            return Ok(Some(date));
+        }
+    }
+    return match NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        Ok(day) if end_of_day => Ok(Some(day.and_hms(23, 59, 59))),
+        Ok(day) => Ok(Some(day.and_hms(0, 0, 0))),
+        Err(_) => Err(rule_error(format!("Bad date in clock rule: {}", value))),
+    };
+}
+
+fn same(expected: &Option<String>, actual: &Option<String>) -> bool {
+    return match (expected, actual) {
+        (None, _) => true,
+        (Some(expected), Some(actual)) => expected.trim().eq_ignore_ascii_case(actual.trim()),
+        (Some(_), None) => false,
+    };
+}
+
+impl ClockRule {
+    pub fn new(spec: ClockRuleSpec) -> Result<ClockRule, PsError> {
+        return Ok(ClockRule {
+            from: parse_bound(&spec.from, false)?,
+            to: parse_bound(&spec.to, true)?,
+            offset: parse_offset(&spec.offset)?,
+            spec,
+        });
+    }
+
+    fn matches(&self, photo: &Photo, date: NaiveDateTime) -> bool {
+        let camera = photo.camera();
+        return same(&self.spec.make, &camera.make)
+            && same(&self.spec.model, &camera.model)
+            && same(&self.spec.serial, &camera.serial)
+            && !matches!(self.from, Some(from) if date < from)
+            && !matches!(self.to, Some(to) if date > to);
+    }
+}
+
+impl std::fmt::Display for ClockRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let camera: Vec<&str> = [&self.spec.make, &self.spec.model, &self.spec.serial]
+            .iter()
+            .filter_map(|v| v.as_deref())
+            .collect();
+        write!(f, "{} for {}", format_offset(self.offset), camera.join(" "))?;
+        if let Some(from) = &self.from {
+            write!(f, " from {}", from)?;
+        }
+        if let Some(to) = &self.to {
+            write!(f, " to {}", to)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads clock rules from a JSON file.
+pub fn load_rules(path: &Path) -> Result<Vec<ClockRule>, PsError> {
+    let file = std::fs::File::open(path).map_err(|e| PsError::from(e).with_path(path))?;
+    let specs: Vec<ClockRuleSpec> = serde_json::from_reader(file)
+        .map_err(|e| rule_error(format!("Couldn't read clock rules: {}", e)).with_path(path))?;
+    return specs.into_iter().map(ClockRule::new).collect();
+}
+
+/// Shifts the photo's date by the first rule which matches it, returning what has been done.
+pub fn apply(rules: &[ClockRule], photo: &mut Photo) -> Option<Correction> {
+    let date = photo.date()?;
+    let rule = rules.iter().find(|rule| rule.matches(photo, date))?;
+    let corrected = date + rule.offset;
+    photo.set_date(corrected);
+    photo.set_date_corrected(true);
+    return Some(Correction {
+        original: date,
+        corrected,
+        rule: rule.to_string(),
+    });
+}
+
+/// How one device's clock compares with the reference device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Estimate {
+    pub camera: Camera,
+    /// Whether this is the device other clocks are compared with.
+    pub reference: bool,
+    pub photos: usize,
+    /// To be added to this device's dates, None if its shots never line up with the reference.
+    pub offset: Option<Duration>,
+    /// How many pairs of shots line up at `offset`.
+    pub matches: usize,
+    /// Rule correcting this device, unless it's the reference or already right.
+    pub rule: Option<ClockRuleSpec>,
+}
+
+/// Fewer pairs of shots lining up than this are taken for a coincidence.
+const MIN_MATCHES: usize = 3;
+
+pub fn camera_name(camera: &Camera) -> String {
+    let parts: Vec<&str> = [&camera.make, &camera.model, &camera.serial]
+        .iter()
+        .filter_map(|v| v.as_deref())
+        .collect();
+    return parts.join(" ");
+}
+
+/// Returns the offset which, added to `device` times, lines up most of its shots with
+/// `reference` shots within `window`, together with the number of shot pairs lining up.
+///
+/// Every pair of shots taken less than `max_offset` apart votes for the difference between them;
+/// when both devices shot the same bursts, the votes cluster around the true skew. Votes are
+/// counted in buckets of `window`, so that memory doesn't grow with the number of pairs. Both
+/// lists must be sorted.
+fn best_offset(
+    reference: &[i64],
+    device: &[i64],
+    window: i64,
+    max_offset: i64,
+) -> Option<(i64, usize)> {
+    let width = window.max(1);
+    let for_each_diff = |f: &mut dyn FnMut(i64)| {
+        for &t in device {
+            let start = match reference.binary_search(&(t - max_offset)) {
+                Ok(i) | Err(i) => i,
+            };
+            for &r in reference[start..]
+                .iter()
+                .take_while(|&&r| r <= t + max_offset)
+            {
+                f(r - t);
+            }
+        }
+    };
+    let mut buckets: BTreeMap<i64, usize> = BTreeMap::new();
+    for_each_diff(&mut |diff| *buckets.entry(diff.div_euclid(width)).or_default() += 1);
+
+    // The densest two neighbouring buckets are where the two clocks line up best.
+    let mut best: Option<(i64, usize)> = None;
+    for (&bucket, &count) in &buckets {
+        let count = count + buckets.get(&(bucket + 1)).cloned().unwrap_or_default();
+        if !matches!(best, Some((_, c)) if count <= c) {
+            best = Some((bucket, count));
+        }
+    }
+    let (bucket, _) = best?;
+    let mut cluster = Vec::new();
+    for_each_diff(&mut |diff| {
+        if (bucket..=bucket + 1).contains(&diff.div_euclid(width)) {
+            cluster.push(diff);
+        }
+    });
+    cluster.sort_unstable();
+    return Some((cluster[cluster.len() / 2], cluster.len()));
+}
+
+/// Groups photos by the device which took them and estimates every device's clock skew against
+/// the `reference` device, which is the one with most photos unless named. Photos without
+/// camera make or model are ignored.
+pub fn estimate_offsets(
+    photos: &[Photo],
+    reference: Option<&str>,
+    window: Duration,
+    max_offset: Duration,
+) -> Result<Vec<Estimate>, PsError> {
+    let mut devices: BTreeMap<String, (Camera, Vec<NaiveDateTime>)> = BTreeMap::new();
+    for photo in photos {
+        let camera = photo.camera();
+        if camera.make.is_none() && camera.model.is_none() {
+            continue;
+        }
+        if let Some(date) = photo.date() {
+            devices
+                .entry(camera_name(camera))
+                .or_insert_with(|| (camera.clone(), Vec::new()))
+                .1
+                .push(date);
+        }
+    }
+
+    let reference_name = match reference {
+        Some(name) => devices
+            .keys()
+            .find(|key| {
+                key.eq_ignore_ascii_case(name)
+                    || matches!(&devices[*key].0.model, Some(m) if m.eq_ignore_ascii_case(name))
+            })
+            .cloned()
+            .ok_or_else(|| {
+                PsError::new(
+                    PsErrorKind::Unknown,
+                    format!("No photos taken with {}", name),
+                )
+            })?,
+        None => match devices.iter().max_by_key(|(_, (_, dates))| dates.len()) {
+            Some((name, _)) => name.clone(),
+            None => {
+                return Err(PsError::new(
+                    PsErrorKind::NoExif,
+                    "No photos with camera information".to_string(),
+                ))
+            }
+        },
+    };
+
+    let timestamps = |dates: &Vec<NaiveDateTime>| -> Vec<i64> {
+        let mut times: Vec<i64> = dates.iter().map(|d| d.timestamp()).collect();
+        times.sort_unstable();
+        return times;
+    };
+    let reference_times = timestamps(&devices[&reference_name].1);
+
+    let mut estimates = Vec::new();
+    for (name, (camera, dates)) in &devices {
+        let mut estimate = Estimate {
+            camera: camera.clone(),
+            reference: *name == reference_name,
+            photos: dates.len(),
+            offset: None,
+            matches: 0,
+            rule: None,
+        };
+        if estimate.reference {
+            estimate.offset = Some(Duration::zero());
+            estimates.push(estimate);
+            continue;
+        }
+
+        let best = best_offset(
+            &reference_times,
+            &timestamps(dates),
+            window.num_seconds(),
+            max_offset.num_seconds(),
+        );
+        if let Some((offset, matches)) = best.filter(|(_, matches)| *matches >= MIN_MATCHES) {
+            let offset = Duration::seconds(offset);
+            estimate.offset = Some(offset);
+            estimate.matches = matches;
+            if offset.num_seconds().abs() > window.num_seconds() {
+                estimate.rule = Some(ClockRuleSpec {
+                    make: camera.make.clone(),
+                    model: camera.model.clone(),
+                    serial: camera.serial.clone(),
+                    from: dates
+                        .iter()
+                        .min()
+                        .map(|d| d.format(BOUND_FORMAT).to_string()),
+                    // Rounded up to the second, so that the last photo is still within the rule.
+                    to: dates.iter().max().map(|d| {
+                        (*d + Duration::nanoseconds(999_999_999))
+                            .format(BOUND_FORMAT)
+                            .to_string()
+                    }),
+                    offset: format_offset(offset),
+                });
+            }
+        }
+        estimates.push(estimate);
+    }
+    return Ok(estimates);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn photo(model: &str, date: NaiveDateTime) -> Photo {
+        let mut photo = Photo::from(PathBuf::from("IMG_0001.JPG"), date);
+        photo.set_camera(Camera {
+            make: Some("Canon".to_string()),
+            model: Some(model.to_string()),
+            serial: None,
+        });
+        return photo;
+    }
+
+    #[test]
+    fn test_estimate_offsets() -> Result<(), PsError> {
+        let start = NaiveDate::from_ymd(2021, 6, 3).and_hms(9, 0, 0);
+        let skew = Duration::minutes(73);
+        let mut photos = Vec::new();
+        // Both cameras shoot the same few bursts, the second one with its clock behind.
+        for burst in &[0, 95, 230, 410, 600] {
+            for shot in 0..4 {
+                let taken = start + Duration::minutes(*burst) + Duration::seconds(shot * 7);
+                photos.push(photo("Canon EOS 5D", taken));
+                photos.push(photo(
+                    "Canon EOS 40D",
+                    taken - skew + Duration::seconds(shot),
+                ));
+            }
+        }
+        photos.push(photo("Canon EOS 5D", start + Duration::hours(30)));
+
+        let estimates =
+            estimate_offsets(&photos, None, Duration::seconds(30), Duration::hours(12))?;
+        assert_eq!(estimates.len(), 2);
+        let reference = estimates.iter().find(|e| e.reference).unwrap();
+        assert_eq!(reference.photos, 21);
+        assert_eq!(reference.rule, None);
+
+        let skewed = estimates.iter().find(|e| e.photos == 20).unwrap();
+        let offset = skewed.offset.unwrap();
+        assert!((offset - skew).num_seconds().abs() <= 5, "{}", offset);
+        let spec = skewed.rule.clone().unwrap();
+        assert_eq!(spec.from.as_deref(), Some("2021-06-03T07:47:00"));
+        let rule = ClockRule::new(spec)?;
+        let mut fixed = photo("Canon EOS 40D", start - skew);
+        assert!(apply(&[rule], &mut fixed).is_some());
+
+        assert!(estimate_offsets(&photos, Some("Nikon D70"), Duration::seconds(30), skew).is_err());
+        return Ok(());
+    }
+
+    #[test]
+    fn test_parse_offset() -> Result<(), PsError> {
+        assert_eq!(parse_offset("+01:13")?, Duration::minutes(73));
+        assert_eq!(parse_offset("-00:00:30")?, Duration::seconds(-30));
+        assert_eq!(format_offset(Duration::minutes(-73)), "-01:13:00");
+        assert!(parse_offset("1h13m").is_err());
+        return Ok(());
+    }
+
+    #[test]
+    fn test_apply() -> Result<(), PsError> {
+        let rules = vec![ClockRule::new(ClockRuleSpec {
+            model: Some("canon eos 40d".to_string()),
+            from: Some("2021-06-01".to_string()),
+            to: Some("2021-06-14".to_string()),
+            offset: "+01:13".to_string(),
+            ..Default::default()
+        })?];
+        let taken = NaiveDate::from_ymd(2021, 6, 14).and_hms(23, 0, 0);
+
+        let mut during_trip = photo("Canon EOS 40D", taken);
+        let correction = apply(&rules, &mut during_trip).unwrap();
+        assert_eq!(correction.original, taken);
+        assert_eq!(
+            during_trip.date(),
+            Some(NaiveDate::from_ymd(2021, 6, 15).and_hms(0, 13, 0))
+        );
+
+        let mut other_camera = photo("Canon EOS 5D", taken);
+        assert_eq!(apply(&rules, &mut other_camera), None);
+        assert_eq!(other_camera.date(), Some(taken));
+
+        let mut after_trip = photo("Canon EOS 40D", taken + Duration::days(1));
+        assert_eq!(apply(&rules, &mut after_trip), None);
+        return Ok(());
+    }
+}
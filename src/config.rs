@@ -1,23 +1,115 @@
 extern crate clap;
 
 pub mod configurator {
-    use super::clap::App;
+    use super::clap::{App, AppSettings, ArgMatches, SubCommand};
+    use crate::clock::{self, ClockRule};
+    use crate::discovery::discovery::default_min_date;
+    use crate::extract::Registry;
+    use crate::layout::{Category, Destinations};
+    use crate::naming;
+    use crate::pserror::error::{PsError, PsErrorKind};
+    use chrono::NaiveDate;
     use std::error::Error;
+    use std::ffi::OsStr;
+    use std::path::{Path, PathBuf};
 
     #[derive(PartialEq, Eq, Debug)]
     pub struct Config {
-        pub source: String,
-        pub destination: String,
+        pub source: PathBuf,
+        pub destination: PathBuf,
         pub logfile: Option<String>,
+        /// Where to write the JSON run report, if anywhere.
+        pub report: Option<String>,
         // TODO(sgzmd): these two need to be merged into a single enum
         pub dry_run: bool,
         pub copy: bool,
+        /// Continue an interrupted run instead of scanning the source again.
+        pub resume: bool,
+        /// Where files without a date go, relative to the destination.
+        pub undated_dir: String,
+        /// Where corrupt and unsupported files go, relative to the destination.
+        pub quarantine_dir: String,
+        /// Where photos, videos, RAW files, audio recordings and screenshots are sorted into, and
+        /// how their directories are laid out.
+        pub destinations: Destinations,
+        /// Dates before this one are suspicious and aren't used.
+        pub min_date: NaiveDate,
+        /// Corrections for cameras with a wrong clock.
+        pub clock_rules: Vec<ClockRule>,
+        /// Write corrected and fallback dates back into the sorted files or XMP sidecars.
+        pub write_dates: bool,
+        /// How to name files in the library, original names are kept if None.
+        pub name_template: Option<String>,
+        /// Where dates and metadata are read from, in order of preference.
+        pub extractors: Registry,
+        pub command: Command,
+    }
+
+    /// What photosort has been asked to do.
+    #[derive(PartialEq, Eq, Debug)]
+    pub enum Command {
+        /// Default mode: sort files from source into destination.
+        Sort,
+        /// Keep running, sorting files as they appear in the source directory.
+        Watch(WatchOptions),
+        /// Re-scan the destination library and re-create its index.
+        IndexRebuild,
+        /// Query the destination library index.
+        Query(QueryOptions),
+        /// Estimate clock skew between cameras which shot the same events in source.
+        ClockEstimate(EstimateOptions),
+    }
+
+    #[derive(PartialEq, Eq, Debug, Clone)]
+    pub struct WatchOptions {
+        /// How long a new file must stay unchanged before it is picked up.
+        pub settle_secs: u64,
+    }
+
+    #[derive(PartialEq, Eq, Debug, Clone)]
+    pub struct EstimateOptions {
+        /// Device other clocks are compared with, the one with most photos if not given.
+        pub reference: Option<String>,
+        /// How close in time shots of the same moment are.
+        pub window_secs: i64,
+        /// Largest skew to look for.
+        pub max_offset_hours: i64,
+    }
+
+    #[derive(PartialEq, Eq, Debug, Clone, Copy)]
+    pub enum OutputFormat {
+        Paths,
+        Csv,
+        Json,
+    }
+
+    #[derive(PartialEq, Eq, Debug, Clone)]
+    pub struct QueryOptions {
+        /// Substring matched against camera make or model, case-insensitive.
+        pub camera: Option<String>,
+        /// First day to include, inclusive.
+        pub from: Option<NaiveDate>,
+        /// Last day to include, inclusive.
+        pub to: Option<NaiveDate>,
+        pub format: OutputFormat,
+    }
+
+    fn dst_arg() -> clap::Arg<'static, 'static> {
+        clap::Arg::with_name("dst")
+            .long("dst")
+            .short("d")
+            .value_name("DESTINATION")
+            .required(true)
+            .empty_values(false)
+            .takes_value(true)
+            .help("Destination directory, e.g. -d /path/to/photos")
     }
 
     fn configure_matchers() -> App<'static, 'static> {
         clap::App::new("Photosort")
             .version("0.0.1")
             .author("Roman 'sgzmd' Kirillov <sigizmund@gmail.com>")
+            .setting(AppSettings::SubcommandsNegateReqs)
             .arg(
                 clap::Arg::with_name("src")
                     .long("src")
@@ -28,16 +120,7 @@ pub mod configurator {
                     .takes_value(true)
                     .help("Source directory with files, e.g. -s /path/to/photos"),
             )
-            .arg(
-                clap::Arg::with_name("dst")
-                    .long("dst")
-                    .short("d")
-                    .value_name("DESTINATION")
-                    .required(true)
-                    .empty_values(false)
-                    .takes_value(true)
-                    .help("Destination directory, e.g. -d /path/to/photos"),
-            )
+            .arg(dst_arg())
             .arg(
                 clap::Arg::with_name("mode")
                     .long("mode")
@@ -57,6 +140,14 @@ pub mod configurator {
                     .takes_value(true)
                     .help("Log file to use, e.g. -l output.log"),
             )
+            .arg(
+                clap::Arg::with_name("report")
+                    .long("report")
+                    .value_name("REPORT")
+                    .empty_values(false)
+                    .takes_value(true)
+                    .help("Write a JSON summary of the run, e.g. --report=report.json"),
+            )
             .arg(
                 clap::Arg::with_name("verbose")
                     .long("verbose")
@@ -71,6 +162,308 @@ pub mod configurator {
                     .takes_value(false)
                     .help("Dry-run, no changes are being written"),
             )
+            .arg(
+                clap::Arg::with_name("resume")
+                    .long("resume")
+                    .short("r")
+                    .takes_value(false)
+                    .help("Continue an interrupted run where it stopped"),
+            )
+            .arg(
+                clap::Arg::with_name("undated_dir")
+                    .long("undated-dir")
+                    .value_name("DIR")
+                    .default_value("_undated")
+                    .empty_values(false)
+                    .takes_value(true)
+                    .help("Where to put files without a date, relative to destination"),
+            )
+            .arg(
+                clap::Arg::with_name("quarantine_dir")
+                    .long("quarantine-dir")
+                    .value_name("DIR")
+                    .default_value("_quarantine")
+                    .empty_values(false)
+                    .takes_value(true)
+                    .help("Where to put corrupt and unsupported files, relative to destination"),
+            )
+            .arg(
+                clap::Arg::with_name("audio_dir")
+                    .long("audio-dir")
+                    .value_name("DIR")
+                    .empty_values(false)
+                    .takes_value(true)
+                    .help("Sort audio recordings into their own date directories under DIR, e.g. audio"),
+            )
+            .arg(
+                clap::Arg::with_name("dst_for")
+                    .long("dst-for")
+                    .value_name("KIND=DIR")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .help(
+                        "Sort photos, videos, raw, audio or screenshots into DIR, relative to \
+                         destination unless absolute, e.g. --dst-for=videos=/mnt/videos",
+                    ),
+            )
+            .arg(
+                clap::Arg::with_name("layout")
+                    .long("layout")
+                    .value_name("LAYOUT")
+                    .empty_values(false)
+                    .takes_value(true)
+                    .help("Directories to sort into with {year}, {month}, {day} and {camera}, see README"),
+            )
+            .arg(
+                clap::Arg::with_name("layout_for")
+                    .long("layout-for")
+                    .value_name("KIND=LAYOUT")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .help("Lay out one kind of file differently, e.g. --layout-for=raw={year}/{camera}"),
+            )
+            .arg(
+                clap::Arg::with_name("min_date")
+                    .long("min-date")
+                    .value_name("YYYY-MM-DD")
+                    .takes_value(true)
+                    .help("Ignore dates before this one as suspicious, default 1990-01-01"),
+            )
+            .arg(
+                clap::Arg::with_name("clock_offsets")
+                    .long("clock-offsets")
+                    .value_name("FILE")
+                    .empty_values(false)
+                    .takes_value(true)
+                    .help("JSON file with clock corrections for cameras, see README"),
+            )
+            .arg(
+                clap::Arg::with_name("write_dates")
+                    .long("write-dates")
+                    .takes_value(false)
+                    .help(
+                        "Write dates which didn't come from EXIF as it is into EXIF or an XMP \
+                         sidecar of the sorted file",
+                    ),
+            )
+            .arg(
+                clap::Arg::with_name("rename")
+                    .long("rename")
+                    .takes_value(false)
+                    .help("Name files by capture time, e.g. 20210306_164713_01.jpg"),
+            )
+            .arg(
+                clap::Arg::with_name("name_template")
+                    .long("name-template")
+                    .value_name("TEMPLATE")
+                    .empty_values(false)
+                    .takes_value(true)
+                    .help(
+                        "Name files following TEMPLATE with {date}, {time}, {subsec}, {camera}, \
+                         {name} and {seq}, see README",
+                    ),
+            )
+            .arg(
+                clap::Arg::with_name("enable_extractor")
+                    .long("enable-extractor")
+                    .value_name("NAME")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .help("Read dates and metadata with this extractor too, see README"),
+            )
+            .arg(
+                clap::Arg::with_name("disable_extractor")
+                    .long("disable-extractor")
+                    .value_name("NAME")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .help("Don't read dates and metadata with this extractor"),
+            )
+            .arg(
+                clap::Arg::with_name("extractor_order")
+                    .long("extractor-order")
+                    .value_name("NAME,...")
+                    .takes_value(true)
+                    .use_delimiter(true)
+                    .help("Try these extractors first, in this order, e.g. iptc,xmp,exif"),
+            )
+            .arg(
+                clap::Arg::with_name("watch")
+                    .long("watch")
+                    .short("w")
+                    .takes_value(false)
+                    .help("Keep running and sort new files as they appear in source directory"),
+            )
+            .arg(
+                clap::Arg::with_name("settle")
+                    .long("settle")
+                    .value_name("SECONDS")
+                    .default_value("5")
+                    .takes_value(true)
+                    .help("In watch mode, how long a file must be unchanged before it is sorted"),
+            )
+            .subcommand(
+                SubCommand::with_name("index")
+                    .about("Maintains the destination library index")
+                    .setting(AppSettings::SubcommandRequiredElseHelp)
+                    .subcommand(
+                        SubCommand::with_name("rebuild")
+                            .about("Scans an existing library and re-creates its index")
+                            .arg(dst_arg()),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("clock")
+                    .about("Works out camera clock corrections")
+                    .setting(AppSettings::SubcommandRequiredElseHelp)
+                    .subcommand(
+                        SubCommand::with_name("estimate")
+                            .about(
+                                "Estimates clock skew between cameras which shot the same events \
+                                 and prints rules for --clock-offsets",
+                            )
+                            .arg(
+                                clap::Arg::with_name("src")
+                                    .long("src")
+                                    .short("s")
+                                    .value_name("SOURCE")
+                                    .required(true)
+                                    .empty_values(false)
+                                    .takes_value(true)
+                                    .help("Directory with photos from all cameras"),
+                            )
+                            .arg(
+                                clap::Arg::with_name("reference")
+                                    .long("reference")
+                                    .value_name("CAMERA")
+                                    .takes_value(true)
+                                    .help("Camera with the right clock, e.g. --reference=D70"),
+                            )
+                            .arg(
+                                clap::Arg::with_name("window")
+                                    .long("window")
+                                    .value_name("SECONDS")
+                                    .default_value("30")
+                                    .takes_value(true)
+                                    .help("How close shots of the same moment are"),
+                            )
+                            .arg(
+                                clap::Arg::with_name("max_offset")
+                                    .long("max-offset")
+                                    .value_name("HOURS")
+                                    .default_value("24")
+                                    .takes_value(true)
+                                    .help("Largest clock skew to look for"),
+                            ),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("query")
+                    .about("Lists files in the library index matching all given filters")
+                    .arg(dst_arg())
+                    .arg(
+                        clap::Arg::with_name("camera")
+                            .long("camera")
+                            .value_name("CAMERA")
+                            .takes_value(true)
+                            .help("Camera make or model, e.g. --camera=D70"),
+                    )
+                    .arg(
+                        clap::Arg::with_name("from")
+                            .long("from")
+                            .value_name("YYYY-MM-DD")
+                            .takes_value(true)
+                            .help("Only files taken on or after this date"),
+                    )
+                    .arg(
+                        clap::Arg::with_name("to")
+                            .long("to")
+                            .value_name("YYYY-MM-DD")
+                            .takes_value(true)
+                            .help("Only files taken on or before this date"),
+                    )
+                    .arg(
+                        clap::Arg::with_name("format")
+                            .long("format")
+                            .value_name("FORMAT")
+                            .default_value("paths")
+                            .possible_values(&["paths", "csv", "json"])
+                            .takes_value(true)
+                            .help("Output format"),
+                    ),
+            )
+    }
+
+    fn parse_date(matches: &ArgMatches, name: &str) -> Result<Option<NaiveDate>, Box<dyn Error>> {
+        return match matches.value_of(name) {
+            None => Ok(None),
+            Some(val) => Ok(Some(NaiveDate::parse_from_str(val, "%Y-%m-%d")?)),
+        };
+    }
+
+    fn configured_extractors(matches: &ArgMatches) -> Result<Registry, Box<dyn Error>> {
+        let mut extractors = Registry::builtin();
+        if let Some(names) = matches.values_of("extractor_order") {
+            extractors.set_order(&names.collect::<Vec<_>>())?;
+        }
+        for name in matches.values_of("enable_extractor").into_iter().flatten() {
+            extractors.set_enabled(name, true)?;
+        }
+        for name in matches.values_of("disable_extractor").into_iter().flatten() {
+            extractors.set_enabled(name, false)?;
+        }
+        return Ok(extractors);
+    }
+
+    /// Splits a `KIND=VALUE` option.
+    fn kind_value(value: &str) -> Result<(Category, &str), PsError> {
+        return match value.find('=') {
+            Some(at) => Ok((Category::parse(&value[..at])?, &value[at + 1..])),
+            None => Err(PsError::new(
+                PsErrorKind::Unsupported,
+                format!("Expected KIND=VALUE, got {}", value),
+            )),
+        };
+    }
+
+    /// `--audio-dir=DIR` is the same as `--dst-for=audio=DIR`.
+    fn configured_destinations(
+        matches: &ArgMatches,
+        root: &OsStr,
+    ) -> Result<Destinations, Box<dyn Error>> {
+        let mut destinations = Destinations::new(root);
+        if let Some(dir) = matches.value_of("audio_dir") {
+            destinations.set_dir(Category::Audio, dir);
+        }
+        for value in matches.values_of("dst_for").into_iter().flatten() {
+            let (category, dir) = kind_value(value)?;
+            destinations.set_dir(category, dir);
+        }
+        if let Some(layout) = matches.value_of("layout") {
+            destinations.set_layout(None, layout)?;
+        }
+        for value in matches.values_of("layout_for").into_iter().flatten() {
+            let (category, layout) = kind_value(value)?;
+            destinations.set_layout(Some(category), layout)?;
+        }
+        return Ok(destinations);
+    }
+
+    fn parse_query(matches: &ArgMatches) -> Result<QueryOptions, Box<dyn Error>> {
+        return Ok(QueryOptions {
+            camera: matches.value_of("camera").map(|s| s.to_string()),
+            from: parse_date(matches, "from")?,
+            to: parse_date(matches, "to")?,
+            format: match matches.value_of("format") {
+                Some("csv") => OutputFormat::Csv,
+                Some("json") => OutputFormat::Json,
+                _ => OutputFormat::Paths,
+            },
+        });
     }
 
     pub fn print_help() {
@@ -87,11 +480,59 @@ pub mod configurator {
         };
 
         let matches = result?;
+
+        // Subcommands only care about the library or the source and how files are read, everything
+        // else stays at defaults.
+        let subcommand = match matches.subcommand() {
+            ("index", Some(index)) => match index.subcommand() {
+                ("rebuild", Some(rebuild)) => Some((Command::IndexRebuild, rebuild)),
+                _ => None,
+            },
+            ("query", Some(query)) => Some((Command::Query(parse_query(query)?), query)),
+            ("clock", Some(clock)) => match clock.subcommand() {
+                ("estimate", Some(estimate)) => Some((
+                    Command::ClockEstimate(EstimateOptions {
+                        reference: estimate.value_of("reference").map(|s| s.to_string()),
+                        // Unwraps are safe because both have default values
+                        window_secs: estimate.value_of("window").unwrap().parse()?,
+                        max_offset_hours: estimate.value_of("max_offset").unwrap().parse()?,
+                    }),
+                    estimate,
+                )),
+                _ => None,
+            },
+            _ => None,
+        };
+        if let Some((command, sub_matches)) = subcommand {
+            return Ok(Config {
+                source: sub_matches.value_of_os("src").unwrap_or_default().into(),
+                destination: sub_matches.value_of_os("dst").unwrap_or_default().into(),
+                logfile: matches.value_of("log").map(|s| s.to_string()),
+                report: None,
+                dry_run: false,
+                copy: false,
+                resume: false,
+                undated_dir: String::new(),
+                quarantine_dir: String::new(),
+                destinations: configured_destinations(
+                    &matches,
+                    sub_matches.value_of_os("dst").unwrap_or_default(),
+                )?,
+                min_date: parse_date(&matches, "min_date")?.unwrap_or_else(default_min_date),
+                clock_rules: Vec::new(),
+                write_dates: false,
+                name_template: None,
+                extractors: configured_extractors(&matches)?,
+                command,
+            });
+        }
+
         return Result::Ok(Config {
             // Unwrap is safe because next two are required parameters
-            source: matches.value_of("src").unwrap().to_string(),
-            destination: matches.value_of("dst").unwrap().to_string(),
+            source: matches.value_of_os("src").unwrap().into(),
+            destination: matches.value_of_os("dst").unwrap().into(),
             logfile: matches.value_of("log").map(|s| s.to_string()),
+            report: matches.value_of("report").map(|s| s.to_string()),
             dry_run: match matches.occurrences_of("dry_run") {
                 0 => false,
                 1 | _ => true,
@@ -103,6 +544,35 @@ pub mod configurator {
                     "move" | _ => false,
                 },
             },
+            resume: matches.occurrences_of("resume") > 0,
+            // Unwraps are safe because both have default values
+            undated_dir: matches.value_of("undated_dir").unwrap().to_string(),
+            quarantine_dir: matches.value_of("quarantine_dir").unwrap().to_string(),
+            // Unwrap is safe because destination is required.
+            destinations: configured_destinations(&matches, matches.value_of_os("dst").unwrap())?,
+            min_date: parse_date(&matches, "min_date")?.unwrap_or_else(default_min_date),
+            clock_rules: match matches.value_of("clock_offsets") {
+                Some(path) => clock::load_rules(Path::new(path))?,
+                None => Vec::new(),
+            },
+            write_dates: matches.occurrences_of("write_dates") > 0,
+            name_template: match matches.value_of("name_template") {
+                Some(template) => {
+                    naming::check_template(template)?;
+                    Some(template.to_string())
+                }
+                None if matches.occurrences_of("rename") > 0 => {
+                    Some(naming::DEFAULT_TEMPLATE.to_string())
+                }
+                None => None,
+            },
+            extractors: configured_extractors(&matches)?,
+            command: match matches.occurrences_of("watch") {
+                0 => Command::Sort,
+                _ => Command::Watch(WatchOptions {
+                    settle_secs: matches.value_of("settle").unwrap_or("5").parse()?,
+                }),
+            },
         });
     }
 }
@@ -110,6 +580,7 @@ pub mod configurator {
 #[cfg(test)]
 mod tests {
     use std::error::Error;
+    use std::path::{Path, PathBuf};
 
     #[test]
     fn test_parse_fail() {
@@ -118,6 +589,18 @@ mod tests {
         assert!(get_config(Option::from(&options)).is_err(), "Broken config");
     }
 
+    #[test]
+    fn test_parse_bad_name_template() {
+        use super::configurator::*;
+        let options = vec![
+            "CommandName",
+            "-sSOURCE",
+            "--dst=DEST",
+            "--name-template=../{date}",
+        ];
+        assert!(get_config(Option::from(&options)).is_err());
+    }
+
     #[test]
     fn test_parse_full_config() -> Result<(), Box<dyn Error>> {
         use super::configurator::*;
@@ -127,20 +610,123 @@ mod tests {
             "--dst=DEST",
             "--mode=move",
             "--log=some.file.log",
+            "--report=report.json",
+            "--quarantine-dir=broken",
+            "--audio-dir=audio",
+            "--dst-for=videos=/mnt/videos",
+            "--layout-for=raw={year}/{camera}",
+            "--min-date=1995-06-01",
+            "--rename",
+            "--disable-extractor=exif",
+            "--extractor-order=iptc,xmp",
             "-t",
         ];
         let config = get_config(Option::from(&options))?;
 
+        let mut extractors = crate::extract::Registry::builtin();
+        extractors.set_order(&["iptc", "xmp"])?;
+        extractors.set_enabled("exif", false)?;
+        let mut destinations = crate::layout::Destinations::new("DEST");
+        destinations.set_dir(crate::layout::Category::Audio, "audio");
+        destinations.set_dir(crate::layout::Category::Videos, "/mnt/videos");
+        destinations
+            .set_layout(Some(crate::layout::Category::Raw), "{year}/{camera}")
+            .unwrap();
         let expected_config = Config {
-            source: "SOURCE".to_string(),
-            destination: "DEST".to_string(),
+            source: PathBuf::from("SOURCE"),
+            destination: PathBuf::from("DEST"),
             logfile: Option::from("some.file.log".to_string()),
+            report: Option::from("report.json".to_string()),
             dry_run: true,
             copy: false,
+            resume: false,
+            undated_dir: "_undated".to_string(),
+            quarantine_dir: "broken".to_string(),
+            destinations,
+            min_date: chrono::NaiveDate::from_ymd(1995, 6, 1),
+            clock_rules: Vec::new(),
+            write_dates: false,
+            name_template: Some("{date}_{time}_{seq}".to_string()),
+            extractors,
+            command: Command::Sort,
         };
 
         assert_eq!(config, expected_config);
 
         return Ok(());
     }
+
+    #[test]
+    fn test_parse_query() -> Result<(), Box<dyn Error>> {
+        use super::configurator::*;
+        use chrono::NaiveDate;
+        let options = vec![
+            "CommandName",
+            "query",
+            "--dst=DEST",
+            "--camera=D70",
+            "--from=2019-06-01",
+            "--format=csv",
+        ];
+        let config = get_config(Option::from(&options))?;
+
+        assert_eq!(config.destination, Path::new("DEST"));
+        assert_eq!(
+            config.command,
+            Command::Query(QueryOptions {
+                camera: Some("D70".to_string()),
+                from: Some(NaiveDate::from_ymd(2019, 6, 1)),
+                to: None,
+                format: OutputFormat::Csv,
+            })
+        );
+
+        return Ok(());
+    }
+
+    #[test]
+    fn test_parse_index_rebuild() -> Result<(), Box<dyn Error>> {
+        use super::configurator::*;
+        let options = vec![
+            "CommandName",
+            "--dst-for=videos=/mnt/videos",
+            "index",
+            "rebuild",
+            "--dst=DEST",
+        ];
+        let config = get_config(Option::from(&options))?;
+
+        assert_eq!(config.command, Command::IndexRebuild);
+        assert_eq!(
+            config.destinations.roots(),
+            vec![PathBuf::from("DEST"), PathBuf::from("/mnt/videos")]
+        );
+
+        return Ok(());
+    }
+
+    #[test]
+    fn test_parse_clock_estimate() -> Result<(), Box<dyn Error>> {
+        use super::configurator::*;
+        let options = vec![
+            "CommandName",
+            "clock",
+            "estimate",
+            "--src=TRIP",
+            "--reference=D70",
+        ];
+        let config = get_config(Option::from(&options))?;
+
+        assert_eq!(config.source, Path::new("TRIP"));
+        assert_eq!(
+            config.command,
+            Command::ClockEstimate(EstimateOptions {
+                reference: Some("D70".to_string()),
+                window_secs: 30,
+                max_offset_hours: 24,
+            })
+        );
+
+        return Ok(());
+    }
 }
@@ -1,18 +1,24 @@
 pub mod discovery {
+    use crate::bmff;
+    use crate::integrity;
     use crate::pserror::error::*;
     use crate::Photo;
 
-    use chrono::{DateTime, NaiveDateTime};
+    use chrono::{Duration, Local, NaiveDate, NaiveDateTime};
     use indicatif::{ProgressBar, ProgressStyle};
     use log::{info, warn};
-    use std::path::Path;
+    use std::path::{Path, PathBuf};
     use walkdir::{DirEntry, WalkDir};
 
-    use crate::photo::PhotoBuilder;
-    use exif::{Error, Exif, In, Tag};
+    use crate::extract::{Extracted, Registry};
+    use crate::index::{exif_coordinate, exif_string, exif_uint, hash_file};
+    use crate::layout::RAW_EXTENSIONS;
+    use crate::photo::{Camera, Gps, MediaKind, Metadata, RejectedDate};
+    use exif::{Exif, In, Tag, Value};
+    #[cfg(feature = "ffmpeg")]
     use ffmpeg::format::context::Input;
     use std::fs::File;
-    use std::io::{BufReader, Read};
+    use std::io::BufReader;
 
     pub fn is_supported_file(file_name: &str) -> bool {
         let file_name = String::from(file_name).to_lowercase();
@@ -21,33 +27,134 @@ pub mod discovery {
             || file_name.ends_with("jpeg")
             || file_name.ends_with("png")
             || file_name.ends_with("gif")
+            || file_name.ends_with("webp")
+            || file_name.ends_with("avif")
+            || file_name.ends_with("jxl")
+            || file_name.ends_with("tif")
+            || file_name.ends_with("tiff")
             || file_name.ends_with("mp4")
             || file_name.ends_with("mov")
-            || file_name.ends_with("mp");
+            || file_name.ends_with("mp")
+            || file_name.ends_with("m4v")
+            || file_name.ends_with("3gp")
+            || file_name.ends_with("mts")
+            || file_name.ends_with("m2ts")
+            || file_name.ends_with("avi")
+            || file_name.ends_with("mkv")
+            || file_name.ends_with("webm")
+            || file_name.ends_with("mpg")
+            || file_name.ends_with("mpeg")
+            || file_name.ends_with("m4a")
+            || file_name.ends_with("wav")
+            || file_name.ends_with("mp3")
+            || RAW_EXTENSIONS.iter().any(|raw| file_name.ends_with(raw));
 
         info!("File {} is supported: {}", file_name, result);
         return result;
     }
 
+    /// Tells images from videos by the file name, if it has a known extension.
+    pub fn media_kind(file_name: &str) -> Option<MediaKind> {
+        let extension = Path::new(file_name)
+            .extension()?
+            .to_string_lossy()
+            .to_lowercase();
+        return match extension.as_str() {
+            "jpg" | "jpeg" | "png" | "gif" | "webp" | "avif" | "jxl" | "tif" | "tiff" => {
+                Some(MediaKind::Image)
+            }
+            "mp4" | "mov" | "mp" | "m4v" | "3gp" | "mts" | "m2ts" | "avi" | "mkv" | "webm"
+            | "mpg" | "mpeg" => Some(MediaKind::Video),
+            "m4a" | "wav" | "mp3" => Some(MediaKind::Audio),
+            raw if RAW_EXTENSIONS.contains(&raw) => Some(MediaKind::Image),
+            _ => None,
+        };
+    }
+
+    /// Extensions of files which belong to other files: sidecars, which travel with their photo or
+    /// video, and the index, playlists and clip information of an AVCHD card.
+    const COMPANION_EXTENSIONS: [&str; 9] = [
+        "xmp", "thm", "aae", "lrv", "bdm", "mpl", "cpi", "tid", "tdt",
+    ];
+
+    /// Whether the file is hidden, e.g. `.DS_Store`, or belongs to another file. Neither kind is
+    /// sorted on its own nor set aside.
+    fn is_companion_file(path: &Path) -> bool {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if name.starts_with('.') {
+            return true;
+        }
+        return match path.extension() {
+            Some(extension) => {
+                let extension = extension.to_string_lossy().to_lowercase();
+                COMPANION_EXTENSIONS.contains(&extension.as_str())
+            }
+            None => false,
+        };
+    }
+
     fn is_zip_file(file_name: &str) -> bool {
         return String::from(file_name).to_lowercase().ends_with(".zip");
     }
 
     /// Returns all physical files in the input_dir which are supported.
-    pub fn list_all_files(input_dir: &str) -> Vec<String> {
-        return WalkDir::new(input_dir)
-            .into_iter()
-            // Can we unwrap result?
-            .filter(|e| e.is_ok())
-            .map(|e| e.unwrap())
-            // Mapping path to string
-            .map(|e| e.into_path().into_os_string().into_string().unwrap())
-            // Filtering out unsupported files
-            .filter(|e| is_supported_file(e) | is_zip_file(e))
-            .collect();
+    ///
+    /// Fails only if input_dir itself can't be read; unreadable entries below it are logged and
+    /// skipped.
+    pub fn list_all_files<P: AsRef<Path>>(input_dir: P) -> Result<Vec<PathBuf>, PsError> {
+        // Filtering out unsupported files
+        return walk(input_dir, |name, entry| {
+            entry.file_type().is_file() && (is_supported_file(name) || is_zip_file(name))
+        });
     }
 
-    pub fn process_raw_files(files: &Vec<String>) -> Vec<Photo> {
+    /// Returns all regular files in the input_dir which list_all_files leaves out, except for
+    /// hidden files and files belonging to others.
+    pub fn list_unsupported_files<P: AsRef<Path>>(input_dir: P) -> Result<Vec<PathBuf>, PsError> {
+        return walk(input_dir, |name, entry| {
+            entry.file_type().is_file()
+                && !is_supported_file(name)
+                && !is_zip_file(name)
+                && !is_companion_file(entry.path())
+        });
+    }
+
+    fn walk<P, F>(input_dir: P, keep: F) -> Result<Vec<PathBuf>, PsError>
+    where
+        P: AsRef<Path>,
+        F: Fn(&str, &DirEntry) -> bool,
+    {
+        let input_dir = input_dir.as_ref();
+        let mut files = Vec::new();
+        for entry in WalkDir::new(input_dir) {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) if err.depth() == 0 => {
+                    return Err(PsError::from(std::io::Error::from(err))
+                        .with_path(input_dir)
+                        .at_stage(Stage::Walk));
+                }
+                Err(err) => {
+                    warn!("Skipping unreadable entry: {}", err);
+                    continue;
+                }
+            };
+            // Lossy conversion is good enough to look at the extension.
+            if keep(&entry.path().to_string_lossy(), &entry) {
+                files.push(entry.into_path());
+            }
+        }
+        return Ok(files);
+    }
+
+    /// Discovers dates of all files, returning photos and errors for the files which couldn't be
+    /// discovered. Files which aren't supported are never opened and fail with FileNotSupported,
+    /// damaged files fail the integrity check with Corrupt.
+    pub fn process_raw_files(
+        files: &Vec<PathBuf>,
+        extractors: &Registry,
+        min_date: NaiveDate,
+    ) -> (Vec<Photo>, Vec<PsError>) {
         let bar = ProgressBar::new(files.len() as u64);
         bar.set_message("Collecting information about files....");
         bar.set_style(
@@ -57,73 +164,180 @@ pub mod discovery {
         );
 
         let mut photos: Vec<Photo> = Vec::new();
-        for file in files {
-            let path = Path::new(file);
-            let result = discover_file(path);
+        let mut errors: Vec<PsError> = Vec::new();
+        for path in files {
+            let name = path.to_string_lossy();
+            let result = if is_supported_file(&name) {
+                integrity::check_file(path, &name).and_then(|_| {
+                    discover_file(path, extractors, min_date)
+                        .map_err(|e| e.at_stage(Stage::Discover))
+                })
+            } else {
+                Err(PsError::new(
+                    PsErrorKind::FileNotSupported,
+                    "File type is not supported".to_string(),
+                )
+                .at_stage(Stage::Discover))
+            };
             match result {
                 Ok(photo) => {
                     info!("Adding file to collection: {:?}", photo);
                     photos.push(photo);
                 }
                 Err(err) => {
-                    warn!("Couldn't discover file {:?} because of {:?}", path, err);
+                    let err = err.with_path(path);
+                    warn!("Couldn't discover file {:?} because of {}", path, err);
+                    errors.push(err);
                 }
             }
         }
 
         bar.finish();
-        return photos;
+        return (photos, errors);
     }
 
-    fn extract_picture_exif(file: &File, path: &Path) -> Result<Photo, PsError> {
-        let mut bufreader = std::io::BufReader::new(file);
-        let exifreader = exif::Reader::new();
-        let exif: Result<Exif, Error> = exifreader.read_from_container(&mut bufreader);
+    /// Reads the EXIF date and metadata of the file. Having EXIF without a date is fine, the date
+    /// may come from elsewhere.
+    ///
+    /// JPEG, TIFF, PNG, WebP and HEIF (including AVIF) files are read by kamadak-exif, JPEG XL
+    /// files by finding their `Exif` box.
+    pub fn read_exif(path: &Path) -> Result<Extracted, PsError> {
+        let reader = exif::Reader::new();
+        let exif = match bmff::read_jxl_exif(path)? {
+            Some(tiff) => reader.read_raw(tiff),
+            None => reader.read_from_container(&mut BufReader::new(File::open(path)?)),
+        };
+        let exif = exif.map_err(|e| {
+            PsError::new(
+                PsErrorKind::FormatError,
+                format!("Error reading EXIF: {:?} {:?}", path, e),
+            )
+        })?;
 
-        let photo_path = path.clone().to_str();
-        return match_exif(exif, photo_path);
+        let mut extracted = Extracted {
+            metadata: exif_metadata(&exif),
+            ..Default::default()
+        };
+        // TODO(sgzmd): we have to possibly use DateTimeDigitized for, e.g. scanned photos.
+        // Unclear how much value this will add, so leaving it for later.
+        if let Some(field) = exif.get_field(Tag::DateTimeOriginal, In::PRIMARY) {
+            let date = field.display_value().with_unit(&exif).to_string();
+            match NaiveDateTime::parse_from_str(&date, "%Y-%m-%d %H:%M:%S") {
+                Ok(ndt) => {
+                    extracted
+                        .dates
+                        .push(("EXIF DateTimeOriginal".to_string(), ndt));
+                    extracted.subsec = exif_string(&exif, Tag::SubSecTimeOriginal)
+                        .filter(|s| s.chars().all(|c| c.is_ascii_digit()));
+                }
+                Err(_err) => warn!("Couldn't parse date {} in {:?}", date, path),
+            }
+        }
+        return Ok(extracted);
     }
 
-    fn match_exif(exif: Result<Exif, Error>, photo_path: Option<&str>) -> Result<Photo, PsError> {
-        match exif {
-            Ok(exif) => {
-                // TODO(sgzmd): we have to possibly use DateTimeDigitized for, e.g. scanned photos.
-                // Unclear how much value this will add, so leaving it for later.
-                let field = exif.get_field(Tag::DateTimeOriginal, In::PRIMARY);
-                if field.is_some() {
-                    let date = field
-                        .unwrap() // This is safe because we checked that field.is_some()
-                        .display_value()
-                        .with_unit(&exif)
-                        .to_string();
-
-                    let parsed = NaiveDateTime::parse_from_str(&date, "%Y-%m-%d %H:%M:%S");
-                    match parsed {
-                        Ok(ndt) => {
-                            let photo = Photo::from(photo_path.unwrap().to_string(), ndt);
-                            return Ok(photo);
-                        }
-                        Err(_err) => Err(PsError::new(
-                            PsErrorKind::FormatError,
-                            format!("Couldn't parse date {}", date),
-                        )),
-                    }
-                } else {
-                    Err(PsError::new(
-                        PsErrorKind::NoExif,
-                        format!("No exif field for {:?}", photo_path),
-                    ))
-                }
+    /// Everything besides the date which EXIF tells about the photo.
+    fn exif_metadata(exif: &Exif) -> Metadata {
+        let latitude = exif_coordinate(exif, Tag::GPSLatitude, Tag::GPSLatitudeRef, "S");
+        let longitude = exif_coordinate(exif, Tag::GPSLongitude, Tag::GPSLongitudeRef, "W");
+        let gps = match (latitude, longitude) {
+            (Some(latitude), Some(longitude)) => Some(Gps {
+                latitude,
+                longitude,
+                altitude: exif_altitude(exif),
+            }),
+            _ => None,
+        };
+
+        return Metadata {
+            kind: Some(MediaKind::Image),
+            camera: Camera {
+                make: exif_string(exif, Tag::Make),
+                model: exif_string(exif, Tag::Model),
+                serial: exif_string(exif, Tag::BodySerialNumber),
+            },
+            lens: exif_string(exif, Tag::LensModel),
+            width: exif_uint(exif, Tag::PixelXDimension).or(exif_uint(exif, Tag::ImageWidth)),
+            height: exif_uint(exif, Tag::PixelYDimension).or(exif_uint(exif, Tag::ImageLength)),
+            orientation: exif_uint(exif, Tag::Orientation),
+            gps,
+            ..Default::default()
+        };
+    }
+
+    fn exif_altitude(exif: &Exif) -> Option<f64> {
+        let altitude = match &exif.get_field(Tag::GPSAltitude, In::PRIMARY)?.value {
+            Value::Rational(parts) if !parts.is_empty() => parts[0].to_f64(),
+            _ => return None,
+        };
+        // Reference 1 means below sea level.
+        return match exif_uint(exif, Tag::GPSAltitudeRef) {
+            Some(1) => Some(-altitude),
+            _ => Some(altitude),
+        };
+    }
+
+    /// Parses an ISO 6709 location as ffmpeg reports it for videos, e.g. `+37.7858-122.4064+010.000/`.
+    pub fn parse_iso6709(location: &str) -> Option<Gps> {
+        let location = location.trim().trim_end_matches('/');
+        let mut parts = Vec::new();
+        let mut start = 0;
+        for (i, c) in location.char_indices().skip(1) {
+            if c == '+' || c == '-' {
+                parts.push(&location[start..i]);
+                start = i;
             }
-            // Ignoring, will try ffmpeg later
-            Err(e) => Err(PsError::new(
-                PsErrorKind::FormatError,
-                format!("Error reading EXIF: {:?} {:?}", photo_path, e),
-            )),
         }
+        parts.push(&location[start..]);
+        if parts.len() < 2 {
+            return None;
+        }
+        return Some(Gps {
+            latitude: parts[0].parse().ok()?,
+            longitude: parts[1].parse().ok()?,
+            altitude: parts.get(2).and_then(|a| a.parse().ok()),
+        });
     }
 
-    pub fn discover_file(path: &Path) -> Result<Photo, PsError> {
+    /// Dates before this are suspicious unless configured otherwise.
+    pub fn default_min_date() -> NaiveDate {
+        return NaiveDate::from_ymd(1990, 1, 1);
+    }
+
+    /// Days which devices with a reset clock, or a zeroed timestamp, report: Unix, DOS and
+    /// QuickTime epochs and the usual camera default.
+    const SENTINEL_DATES: [(i32, u32, u32); 4] =
+        [(1970, 1, 1), (1980, 1, 1), (1904, 1, 1), (2000, 1, 1)];
+
+    /// Returns why `date` can't be trusted, if it can't.
+    pub fn suspicious_date(date: NaiveDateTime, min_date: NaiveDate) -> Option<String> {
+        let day = date.date();
+        if SENTINEL_DATES
+            .iter()
+            .any(|&(y, m, d)| day == NaiveDate::from_ymd(y, m, d))
+        {
+            return Some("looks like a reset clock".to_string());
+        }
+        if day < min_date {
+            return Some(format!("before {}", min_date));
+        }
+        // A day of slack for time zones.
+        if date > Local::now().naive_local() + Duration::days(1) {
+            return Some("in the future".to_string());
+        }
+        return None;
+    }
+
+    /// Discovers the date of the file, trying `extractors` in turn until one finds a date which
+    /// isn't suspicious; by default EXIF first and then the dates ffmpeg finds in the container and
+    /// its streams. Suspicious dates are skipped in favour of the next source and remembered in the
+    /// photo's `rejected_dates`. Metadata is collected from every extractor which has run, earlier
+    /// ones winning.
+    pub fn discover_file(
+        path: &Path,
+        extractors: &Registry,
+        min_date: NaiveDate,
+    ) -> Result<Photo, PsError> {
         let file_name = path.file_name();
         if file_name.is_none() {
             return Err(PsError::new(
@@ -132,78 +346,154 @@ pub mod discovery {
             ));
         }
 
-        let file = std::fs::File::open(path)?;
-        let exif = extract_picture_exif(&file, path);
-
-        if exif.is_ok() {
-            return Ok(exif.unwrap());
+        let mut rejected = Vec::new();
+        let mut metadata = Metadata::default();
+        for extractor in extractors.extractors_for(media_kind(&path.to_string_lossy())) {
+            match extractor.extract(path) {
+                Ok(extracted) => {
+                    metadata.fill_from(extracted.metadata);
+                    if let Some(mut photo) =
+                        pick_date(path, extracted.dates, min_date, &mut rejected)
+                    {
+                        photo.set_subsec(extracted.subsec);
+                        photo.set_date_inferred(extractor.infers_dates());
+                        return with_file_metadata(path, photo, metadata);
+                    }
+                }
+                Err(err) => info!("{} found nothing in {:?}: {}", extractor.name(), path, err),
+            }
         }
 
-        info!("No regular image exif in {:?}, trying ffmpeg...", path);
-
-        // okay let's try ffmpeg
-        let ffmpeg_date = get_ffmpeg_date(&path);
-        return if ffmpeg_date.is_ok() {
-            Ok(Photo::from(
-                path.to_str().unwrap().to_string(),
-                ffmpeg_date.unwrap(),
-            ))
-        } else {
-            info!("Couldn't get ffmpeg date from {:?}", path);
-            Err(PsError::new(
+        if rejected.is_empty() {
+            return Err(PsError::new(
                 PsErrorKind::NoDateField,
-                format!("No EXIF of any kind in {:}", path.to_str().unwrap()),
-            ))
-        };
+                format!("No EXIF of any kind in {}", path.display()),
+            ));
+        }
+        let rejected: Vec<String> = rejected.iter().map(|r| r.to_string()).collect();
+        return Err(PsError::new(
+            PsErrorKind::NoDateField,
+            format!("Date suspicious: {}", rejected.join("; ")),
+        ));
     }
 
-    fn extract_ndt(creation_time: &str) -> NaiveDateTime {
-        let dt = DateTime::parse_from_rfc3339(creation_time);
-        let dt = dt.unwrap();
-        let ndt: NaiveDateTime = NaiveDateTime::from_timestamp(dt.timestamp(), 0);
+    /// Completes `metadata` with what the file itself tells and attaches it to the photo.
+    fn with_file_metadata(
+        path: &Path,
+        mut photo: Photo,
+        mut metadata: Metadata,
+    ) -> Result<Photo, PsError> {
+        metadata.kind = media_kind(&path.to_string_lossy()).or(metadata.kind);
+        metadata.file_size = Some(std::fs::metadata(path)?.len());
+        metadata.hash = Some(hash_file(path)?);
+        photo.set_metadata(metadata);
+        return Ok(photo);
+    }
 
-        return ndt;
+    /// Returns the photo dated by the first of `candidates` which isn't suspicious, adding those
+    /// which are to `rejected`.
+    fn pick_date(
+        path: &Path,
+        candidates: Vec<(String, NaiveDateTime)>,
+        min_date: NaiveDate,
+        rejected: &mut Vec<RejectedDate>,
+    ) -> Option<Photo> {
+        for (source, date) in candidates {
+            match suspicious_date(date, min_date) {
+                None => {
+                    let mut photo = Photo::from(path.to_path_buf(), date);
+                    photo.set_rejected_dates(rejected.clone());
+                    return Some(photo);
+                }
+                Some(reason) => {
+                    warn!("Suspicious {} {} in {:?}: {}", source, date, path, reason);
+                    rejected.push(RejectedDate {
+                        source,
+                        date,
+                        reason,
+                    });
+                }
+            }
+        }
+        return None;
     }
 
-    fn get_ffmpeg_date(path: &Path) -> Result<NaiveDateTime, PsError> {
-        let inp: Input = ffmpeg::format::input(&path)?;
-        let meta = inp.metadata();
-        let file_creation_time = meta.get("creation_time");
-        let creation_time = if file_creation_time.is_some() {
-            let creation_time = file_creation_time.unwrap();
-            info!("Extracted datetime from container: {}", creation_time);
-            return Ok(extract_ndt(creation_time));
-        } else {
-            return stream_creation_time(inp);
+    #[cfg(feature = "ffmpeg")]
+    fn extract_ndt(creation_time: &str) -> Result<NaiveDateTime, PsError> {
+        return match chrono::DateTime::parse_from_rfc3339(creation_time) {
+            Ok(dt) => Ok(NaiveDateTime::from_timestamp(dt.timestamp(), 0)),
+            Err(err) => Err(PsError::new(
+                PsErrorKind::FormatError,
+                format!("Couldn't parse creation time {}: {}", creation_time, err),
+            )),
         };
     }
 
-    fn stream_creation_time(inp: Input) -> Result<NaiveDateTime, PsError> {
-        let mut stream_num = 0;
-        for stream in inp.streams() {
+    /// Returns creation times of the container and then of every stream which has one, in this
+    /// order of preference, together with what else the container tells about the video.
+    #[cfg(feature = "ffmpeg")]
+    pub fn read_ffmpeg(path: &Path) -> Result<Extracted, PsError> {
+        let inp: Input = ffmpeg::format::input(&path)?;
+        let container = inp.metadata();
+        let tag = |keys: &[&str]| {
+            keys.iter()
+                .filter_map(|key| container.get(key))
+                .map(|value| value.trim().to_string())
+                .find(|value| !value.is_empty())
+        };
+        let mut metadata = Metadata {
+            kind: Some(MediaKind::Video),
+            camera: Camera {
+                make: tag(&["com.apple.quicktime.make", "make"]),
+                model: tag(&["com.apple.quicktime.model", "model"]),
+                serial: None,
+            },
+            gps: tag(&["location", "com.apple.quicktime.location.ISO6709"])
+                .and_then(|location| parse_iso6709(&location)),
+            ..Default::default()
+        };
+
+        // One unparsable tag doesn't spoil the dates of the other streams.
+        let mut dates = Vec::new();
+        let mut push_date = |source: String, creation_time: &str| match extract_ndt(creation_time) {
+            Ok(date) => dates.push((source, date)),
+            Err(err) => warn!("Ignoring {} of {:?}: {}", source, path, err),
+        };
+        if let Some(creation_time) = inp.metadata().get("creation_time") {
+            info!("Extracted datetime from container: {}", creation_time);
+            push_date("container creation_time".to_string(), creation_time);
+        }
+
+        for (stream_num, stream) in inp.streams().enumerate() {
             info!("Trying stream {}", stream_num);
-            let meta = stream.metadata();
-            let stream_creation_time = meta.get("creation_time");
-            if stream_creation_time.is_some() {
-                let stream_creation_time = stream_creation_time.unwrap();
+            let codec = stream.codec();
+            if metadata.width.is_none() && codec.medium() == ffmpeg::media::Type::Video {
+                if let Ok(video) = codec.decoder().video() {
+                    metadata.width = Some(video.width());
+                    metadata.height = Some(video.height());
+                }
+            }
+            if let Some(creation_time) = stream.metadata().get("creation_time") {
                 info!(
                     "Extracted data from stream {}: {}",
-                    stream_num, stream_creation_time
+                    stream_num, creation_time
+                );
+                push_date(
+                    format!("stream {} creation_time", stream_num),
+                    creation_time,
                 );
-                return Ok(extract_ndt(stream_creation_time));
             }
-
-            stream_num += 1;
         }
 
-        return Err(PsError::new(
-            PsErrorKind::NoDateField,
-            "Cannot extract date/time from file".to_string(),
-        ));
+        return Ok(Extracted {
+            dates,
+            subsec: None,
+            metadata,
+        });
     }
 
     #[cfg(test)]
-    mod tests {
+    pub mod tests {
         use super::*;
         use chrono::NaiveDate;
         use log::LevelFilter;
@@ -212,6 +502,18 @@ pub mod discovery {
             simple_logging::log_to_stderr(LevelFilter::Info);
         }
 
+        #[test]
+        fn test_list_all_files_skips_directories() -> Result<(), PsError> {
+            let temp_dir = tempfile::tempdir()?;
+            std::fs::create_dir_all(temp_dir.path().join("2021.mov/jpg"))?;
+            std::fs::write(temp_dir.path().join("2021.mov/IMG_0001.JPG"), "photo")?;
+
+            let files = list_all_files(temp_dir.path())?;
+            assert_eq!(files, vec![temp_dir.path().join("2021.mov/IMG_0001.JPG")]);
+            return Ok(());
+        }
+
+        #[test]
         fn test_is_supported_file() {
             setup();
 
@@ -219,9 +521,19 @@ pub mod discovery {
             assert!(is_supported_file("filename.png"));
             assert!(is_supported_file("filename.mp4"));
             assert!(is_supported_file("filename.mov"));
+            assert!(is_supported_file("00001.MTS"));
+            assert!(is_supported_file("MVI_0001.AVI"));
+            assert!(is_supported_file("DSC_0001.NEF"));
+            assert!(is_supported_file("IMG_0001.DNG"));
+            // RAWs which aren't built on TIFF have no EXIF to read and are set aside.
+            assert!(!is_supported_file("IMG_0001.CR3"));
             assert!(!is_supported_file("filename.doc"));
+            assert_eq!(media_kind("clip.webm"), Some(MediaKind::Video));
+            assert_eq!(media_kind("clip.mpeg"), Some(MediaKind::Video));
+            assert_eq!(media_kind("memo.M4A"), Some(MediaKind::Audio));
         }
 
+        #[cfg(feature = "ffmpeg")]
         #[test]
         fn test_extract_ndt() {
             setup();
@@ -229,29 +541,186 @@ pub mod discovery {
             let dt = "2011-11-05T02:51:16.000000Z";
 
             assert_eq!(
-                extract_ndt(dt),
+                extract_ndt(dt).unwrap(),
                 NaiveDate::from_ymd(2011, 11, 5).and_hms(2, 51, 16)
             );
+            assert!(extract_ndt("not a date").is_err());
         }
 
         #[test]
+        #[cfg(feature = "ffmpeg")]
         fn test_get_ffmpeg_date() {
             setup();
 
             let path = Path::new("./test-assets/mpeg/05112011034.mp4");
-            let dt = get_ffmpeg_date(&path);
+            let dt = read_ffmpeg(&path);
 
             assert_eq!(
-                dt.unwrap(),
+                dt.unwrap().dates[0].1,
                 NaiveDate::from_ymd(2011, 11, 5).and_hms(2, 51, 16)
             );
         }
 
+        #[test]
+        fn test_suspicious_date() {
+            let min_date = default_min_date();
+            let date = |y, m, d| NaiveDate::from_ymd(y, m, d).and_hms(10, 30, 0);
+
+            assert_eq!(suspicious_date(date(2011, 11, 5), min_date), None);
+            assert!(suspicious_date(date(2000, 1, 1), min_date).is_some());
+            assert!(suspicious_date(date(1970, 1, 1), min_date).is_some());
+            assert!(suspicious_date(date(1904, 1, 1), min_date).is_some());
+            assert!(suspicious_date(date(1987, 6, 1), min_date).is_some());
+            assert!(suspicious_date(date(2999, 1, 1), min_date).is_some());
+            // The floor is configurable.
+            assert_eq!(
+                suspicious_date(date(1987, 6, 1), NaiveDate::from_ymd(1980, 1, 2)),
+                None
+            );
+        }
+
+        #[test]
+        fn test_discover_file_metadata() {
+            let path = Path::new("./test-assets/jpg/Canon_40D.jpg");
+            let metadata = discover_file(path, &Registry::builtin(), default_min_date())
+                .unwrap()
+                .metadata()
+                .clone();
+            assert_eq!(metadata.kind, Some(MediaKind::Image));
+            assert_eq!(metadata.camera.make.as_deref(), Some("Canon"));
+            assert_eq!(metadata.camera.model.as_deref(), Some("Canon EOS 40D"));
+            assert_eq!((metadata.width, metadata.height), (Some(100), Some(68)));
+            assert_eq!(metadata.orientation, Some(1));
+            assert_eq!(metadata.gps, None);
+            assert_eq!(metadata.file_size, Some(7958));
+            assert_eq!(metadata.hash, Some(hash_file(path).unwrap()));
+
+            let path = Path::new("./test-assets/jpg/Nikon_D70.jpg");
+            let photo = discover_file(path, &Registry::builtin(), default_min_date()).unwrap();
+            assert_eq!(photo.camera().model.as_deref(), Some("NIKON D70"));
+            assert_eq!(
+                (photo.metadata().width, photo.metadata().height),
+                (Some(100), Some(66))
+            );
+
+            let path = Path::new("./test-assets/jpg/gps/DSCN0010.jpg");
+            let gps = discover_file(path, &Registry::builtin(), default_min_date())
+                .unwrap()
+                .metadata()
+                .gps
+                .unwrap();
+            assert!((gps.latitude - 43.467448).abs() < 1e-6);
+            assert!((gps.longitude - 11.885127).abs() < 1e-6);
+
+            let json = serde_json::to_value(photo.metadata()).unwrap();
+            assert_eq!(json["kind"], "image");
+            assert_eq!(json["camera"]["make"], "NIKON CORPORATION");
+        }
+
+        /// The Canon 40D sample's EXIF wrapped into each container which can hold it.
+        pub fn exif_samples() -> Vec<(&'static str, Vec<u8>)> {
+            use crate::bmff::tests::mp4_box;
+
+            let jpeg = std::fs::read("./test-assets/jpg/Canon_40D.jpg").unwrap();
+            let at = jpeg.windows(6).position(|w| w == b"Exif\0\0").unwrap();
+            let length = (jpeg[at - 2] as usize) << 8 | jpeg[at - 1] as usize;
+            let tiff = jpeg[at + 6..at - 2 + length].to_vec();
+
+            let mut webp = b"VP8X\x0a\0\0\0\x08\0\0\0\0\0\0\0\0\0".to_vec();
+            webp.extend_from_slice(b"EXIF");
+            webp.extend_from_slice(&(tiff.len() as u32).to_le_bytes());
+            webp.extend_from_slice(&tiff);
+            if tiff.len() & 1 == 1 {
+                webp.push(0);
+            }
+            let mut riff = b"RIFF".to_vec();
+            riff.extend_from_slice(&(webp.len() as u32 + 4).to_le_bytes());
+            riff.extend_from_slice(b"WEBP");
+            riff.extend(webp);
+
+            // HEIF keeps EXIF in an item, here stored in the `idat` box right after the offset of
+            // the TIFF header.
+            let mut exif_item = vec![0, 0, 0, 0];
+            exif_item.extend_from_slice(&tiff);
+            let infe = mp4_box(b"infe", b"\x02\0\0\0\0\x01\0\0Exif\0");
+            let mut iinf = vec![0, 0, 0, 0, 0, 1];
+            iinf.extend(infe);
+            let mut iloc = vec![
+                1, 0, 0, 0, 0x44, 0x00, 0, 1, 0, 1, 0, 1, 0, 0, 0, 1, 0, 0, 0, 0,
+            ];
+            iloc.extend_from_slice(&(exif_item.len() as u32).to_be_bytes());
+            let mut meta = vec![0, 0, 0, 0];
+            meta.extend(mp4_box(b"iinf", &iinf));
+            meta.extend(mp4_box(b"iloc", &iloc));
+            meta.extend(mp4_box(b"idat", &exif_item));
+            let mut avif = mp4_box(b"ftyp", b"avif\0\0\0\0mif1avif");
+            avif.extend(mp4_box(b"meta", &meta));
+
+            let mut jxl = bmff::JXL_SIGNATURE.to_vec();
+            jxl.extend(mp4_box(b"ftyp", b"jxl \0\0\0\0jxl "));
+            jxl.extend(mp4_box(b"Exif", &exif_item));
+            jxl.extend(mp4_box(b"jxlc", b"\xff\x0a"));
+
+            return vec![
+                ("Canon_40D.tif", tiff),
+                ("Canon_40D.webp", riff),
+                ("Canon_40D.avif", avif),
+                ("Canon_40D.jxl", jxl),
+            ];
+        }
+
+        #[test]
+        fn test_read_exif_containers() {
+            let temp_dir = tempfile::tempdir().unwrap();
+            for (name, data) in exif_samples() {
+                let path = temp_dir.path().join(name);
+                std::fs::write(&path, data).unwrap();
+                assert!(is_supported_file(name), "{}", name);
+                let photo = discover_file(&path, &Registry::builtin(), default_min_date())
+                    .unwrap_or_else(|e| panic!("{}: {}", name, e));
+                assert_eq!(
+                    photo.date(),
+                    Some(NaiveDate::from_ymd(2008, 5, 30).and_hms(15, 56, 1)),
+                    "{}",
+                    name
+                );
+                assert_eq!(photo.metadata().kind, Some(MediaKind::Image));
+                assert_eq!(
+                    photo.camera().model.as_deref(),
+                    Some("Canon EOS 40D"),
+                    "{}",
+                    name
+                );
+            }
+        }
+
+        #[test]
+        fn test_parse_iso6709() {
+            let gps = parse_iso6709("+37.7858-122.4064+010.000/").unwrap();
+            assert_eq!(
+                (gps.latitude, gps.longitude, gps.altitude),
+                (37.7858, -122.4064, Some(10.0))
+            );
+            assert_eq!(parse_iso6709("-33.8688+151.2093/").unwrap().altitude, None);
+            assert!(parse_iso6709("nowhere").is_none());
+        }
+
+        #[test]
+        fn test_discover_file_rejects_suspicious_date() {
+            let path = Path::new("./test-assets/jpg/Canon_40D.jpg");
+            // Canon_40D.jpg was taken on 2008-05-30, which a later floor makes suspicious.
+            let err = discover_file(path, &Registry::builtin(), NaiveDate::from_ymd(2010, 1, 1))
+                .err()
+                .unwrap();
+            assert_eq!(err.kind(), &PsErrorKind::NoDateField);
+            assert!(err.message().contains("2008-05-30 15:56:01"));
+        }
+
         #[test]
         fn test_list_all_files() {
             setup();
 
-            let all_files = list_all_files("./test-assets");
+            let all_files = list_all_files("./test-assets").unwrap();
             assert_eq!(all_files.len(), 92);
         }
 
@@ -259,13 +728,88 @@ pub mod discovery {
         fn test_process_raw_files() {
             setup();
 
-            let supported_files: Vec<String> = list_all_files("./test-assets")
+            let supported_files: Vec<PathBuf> = list_all_files("./test-assets")
+                .unwrap()
                 .into_iter()
-                .filter(|e| is_supported_file(e))
+                .filter(|e| is_supported_file(&e.to_string_lossy()))
                 .collect();
 
-            let photos = process_raw_files(&supported_files);
-            assert_eq!(photos.len(), 55);
+            let (photos, errors) =
+                process_raw_files(&supported_files, &Registry::builtin(), default_min_date());
+            // corrupted.jpg has a date, but doesn't pass the integrity check.
+            // WWL_(Polaroid)_ION230.jpg is dated 2026-11-24, which is suspicious until then.
+            let future = errors
+                .iter()
+                .filter(|e| e.message().contains("in the future"))
+                .count();
+            assert_eq!(photos.len() + future, 54);
+            assert_eq!(photos.len() + errors.len(), supported_files.len());
+        }
+
+        #[test]
+        fn test_non_utf8_file_name() {
+            use std::ffi::OsStr;
+            use std::os::unix::ffi::OsStrExt;
+
+            let temp_dir = tempfile::tempdir().unwrap();
+            // Latin-1 name, as found on old Windows shares.
+            let path = temp_dir.path().join(OsStr::from_bytes(b"caf\xe9.jpg"));
+            std::fs::copy("./test-assets/jpg/Canon_40D.jpg", &path).unwrap();
+
+            let files = list_all_files(temp_dir.path().to_str().unwrap()).unwrap();
+            assert_eq!(files, vec![path.clone()]);
+
+            let photo = discover_file(&path, &Registry::builtin(), default_min_date()).unwrap();
+            assert_eq!(photo.path().as_ref(), Some(&path));
+        }
+
+        /// Deterministic xorshift, good enough to mutate files reproducibly.
+        fn next_random(state: &mut u64) -> u64 {
+            *state ^= *state << 13;
+            *state ^= *state >> 7;
+            *state ^= *state << 17;
+            return *state;
+        }
+
+        #[test]
+        fn test_discover_file_never_panics() {
+            let temp_dir = tempfile::tempdir().unwrap();
+            let mut inputs: Vec<Vec<u8>> = Vec::new();
+
+            let mut broken: Vec<PathBuf> = list_all_files("./test-assets/jpg/invalid").unwrap();
+            broken.push(PathBuf::from("./test-assets/jpg/corrupted.jpg"));
+            for file in &broken {
+                inputs.push(std::fs::read(file).unwrap());
+            }
+
+            let mut state = 0x2545_f491_4f6c_dd1d;
+            for file in &[
+                "./test-assets/jpg/Canon_40D.jpg",
+                "./test-assets/jpg/Nikon_D70.jpg",
+                "./test-assets/jpg/gps/DSCN0010.jpg",
+            ] {
+                let original = std::fs::read(file).unwrap();
+                for _ in 0..40 {
+                    let mut mutated = original.clone();
+                    // Flip bytes in the first few kilobytes, where EXIF lives.
+                    let window = mutated.len().min(4096) as u64;
+                    for _ in 0..(next_random(&mut state) % 16 + 1) {
+                        let at = (next_random(&mut state) % window) as usize;
+                        mutated[at] = next_random(&mut state) as u8;
+                    }
+                    inputs.push(mutated);
+
+                    let cut = (next_random(&mut state) % original.len() as u64) as usize;
+                    inputs.push(original[..cut].to_vec());
+                }
+            }
+
+            for (i, input) in inputs.iter().enumerate() {
+                let path = temp_dir.path().join(format!("fuzz-{}.jpg", i));
+                std::fs::write(&path, input).unwrap();
+                // Any result is fine, as long as it is a result.
+                let _ = discover_file(&path, &Registry::builtin(), default_min_date());
+            }
         }
     }
 }
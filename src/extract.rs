@@ -0,0 +1,504 @@
+use crate::audio;
+use crate::avchd;
+use crate::avi;
+use crate::bmff;
+use crate::discovery::discovery;
+use crate::fallback;
+use crate::imagetext;
+use crate::iptc;
+use crate::matroska;
+use crate::photo::{MediaKind, Metadata};
+use crate::pserror::error::{PsError, PsErrorKind};
+use crate::xmp;
+use chrono::NaiveDateTime;
+use std::path::Path;
+
+/// What an extractor found in a file. Anything may be missing.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Extracted {
+    /// Capture dates together with where in the file they came from, best first.
+    pub dates: Vec<(String, NaiveDateTime)>,
+    /// Fraction of a second of the first date, as digits.
+    pub subsec: Option<String>,
+    pub metadata: Metadata,
+}
+
+/// Source of dates and metadata for some kinds of files.
+pub trait MetadataExtractor: Send + Sync {
+    /// Short name used to enable or disable the extractor, e.g. "exif".
+    fn name(&self) -> &'static str;
+
+    /// Extractors with lower priority run first, and their dates are preferred.
+    fn priority(&self) -> u32;
+
+    fn enabled_by_default(&self) -> bool {
+        return true;
+    }
+
+    /// Whether its dates are guessed from outside the file's metadata, e.g. from its name, so that
+    /// they're worth writing back into it.
+    fn infers_dates(&self) -> bool {
+        return false;
+    }
+
+    /// Whether it's worth running on files of `kind`, which is None if the kind isn't known.
+    fn supports(&self, kind: Option<MediaKind>) -> bool;
+
+    fn extract(&self, path: &Path) -> Result<Extracted, PsError>;
+}
+
+/// EXIF of JPEG, TIFF, PNG and the like.
+pub struct ExifExtractor;
+
+impl MetadataExtractor for ExifExtractor {
+    fn name(&self) -> &'static str {
+        return "exif";
+    }
+
+    fn priority(&self) -> u32 {
+        return 10;
+    }
+
+    fn supports(&self, kind: Option<MediaKind>) -> bool {
+        return kind.is_none() || kind == Some(MediaKind::Image);
+    }
+
+    fn extract(&self, path: &Path) -> Result<Extracted, PsError> {
+        return discovery::read_exif(path);
+    }
+}
+
+/// XMP sidecars and XMP embedded in images and MP4 or MOV files.
+pub struct XmpExtractor;
+
+impl MetadataExtractor for XmpExtractor {
+    fn name(&self) -> &'static str {
+        return "xmp";
+    }
+
+    fn priority(&self) -> u32 {
+        return 20;
+    }
+
+    fn supports(&self, _kind: Option<MediaKind>) -> bool {
+        return true;
+    }
+
+    fn extract(&self, path: &Path) -> Result<Extracted, PsError> {
+        return xmp::read_xmp(path);
+    }
+}
+
+/// IPTC-IIM of JPEG files, as scanners and press agencies write it.
+pub struct IptcExtractor;
+
+impl MetadataExtractor for IptcExtractor {
+    fn name(&self) -> &'static str {
+        return "iptc";
+    }
+
+    fn priority(&self) -> u32 {
+        return 30;
+    }
+
+    fn supports(&self, kind: Option<MediaKind>) -> bool {
+        return kind.is_none() || kind == Some(MediaKind::Image);
+    }
+
+    fn extract(&self, path: &Path) -> Result<Extracted, PsError> {
+        return iptc::read_iptc(path);
+    }
+}
+
+/// Creation time in PNG text chunks and dates in GIF comments.
+pub struct TextExtractor;
+
+impl MetadataExtractor for TextExtractor {
+    fn name(&self) -> &'static str {
+        return "text";
+    }
+
+    fn priority(&self) -> u32 {
+        return 40;
+    }
+
+    fn supports(&self, kind: Option<MediaKind>) -> bool {
+        return kind.is_none() || kind == Some(MediaKind::Image);
+    }
+
+    fn extract(&self, path: &Path) -> Result<Extracted, PsError> {
+        return imagetext::read_image_text(path);
+    }
+}
+
+/// `IDIT` and `INFO` dates of AVI files, as cameras of the 2000s recorded them.
+pub struct AviExtractor;
+
+impl MetadataExtractor for AviExtractor {
+    fn name(&self) -> &'static str {
+        return "avi";
+    }
+
+    fn priority(&self) -> u32 {
+        return 45;
+    }
+
+    fn supports(&self, kind: Option<MediaKind>) -> bool {
+        return kind.is_none() || kind == Some(MediaKind::Video);
+    }
+
+    fn extract(&self, path: &Path) -> Result<Extracted, PsError> {
+        return avi::read_avi(path);
+    }
+}
+
+/// Recording times of AVCHD camcorder clips, from the stream or the playlists next to it.
+pub struct AvchdExtractor;
+
+impl MetadataExtractor for AvchdExtractor {
+    fn name(&self) -> &'static str {
+        return "avchd";
+    }
+
+    fn priority(&self) -> u32 {
+        return 45;
+    }
+
+    fn supports(&self, kind: Option<MediaKind>) -> bool {
+        return kind.is_none() || kind == Some(MediaKind::Video);
+    }
+
+    fn extract(&self, path: &Path) -> Result<Extracted, PsError> {
+        return avchd::read_avchd(path);
+    }
+}
+
+/// Recording dates of WAV and MP3 files, as field recorders and phones write them.
+pub struct AudioExtractor;
+
+impl MetadataExtractor for AudioExtractor {
+    fn name(&self) -> &'static str {
+        return "audio";
+    }
+
+    fn priority(&self) -> u32 {
+        return 45;
+    }
+
+    fn supports(&self, kind: Option<MediaKind>) -> bool {
+        return kind.is_none() || kind == Some(MediaKind::Audio);
+    }
+
+    fn extract(&self, path: &Path) -> Result<Extracted, PsError> {
+        return audio::read_audio(path);
+    }
+}
+
+/// Dates in file names, e.g. `IMG_20210306_164713.jpg`, for files with none inside.
+pub struct FileNameExtractor;
+
+impl MetadataExtractor for FileNameExtractor {
+    fn name(&self) -> &'static str {
+        return "filename";
+    }
+
+    fn priority(&self) -> u32 {
+        return 80;
+    }
+
+    fn infers_dates(&self) -> bool {
+        return true;
+    }
+
+    fn supports(&self, _kind: Option<MediaKind>) -> bool {
+        return true;
+    }
+
+    fn extract(&self, path: &Path) -> Result<Extracted, PsError> {
+        return fallback::read_file_name(path);
+    }
+}
+
+/// Modification time of PNG and GIF files, the last resort for screenshots and memes.
+pub struct MtimeExtractor;
+
+impl MetadataExtractor for MtimeExtractor {
+    fn name(&self) -> &'static str {
+        return "mtime";
+    }
+
+    fn priority(&self) -> u32 {
+        return 90;
+    }
+
+    fn infers_dates(&self) -> bool {
+        return true;
+    }
+
+    fn supports(&self, kind: Option<MediaKind>) -> bool {
+        return kind.is_none() || kind == Some(MediaKind::Image);
+    }
+
+    fn extract(&self, path: &Path) -> Result<Extracted, PsError> {
+        return fallback::read_mtime(path);
+    }
+}
+
+/// Container and stream metadata of anything ffmpeg can open.
+#[cfg(feature = "ffmpeg")]
+pub struct FfmpegExtractor;
+
+#[cfg(feature = "ffmpeg")]
+impl MetadataExtractor for FfmpegExtractor {
+    fn name(&self) -> &'static str {
+        return "ffmpeg";
+    }
+
+    fn priority(&self) -> u32 {
+        return 50;
+    }
+
+    fn supports(&self, _kind: Option<MediaKind>) -> bool {
+        return true;
+    }
+
+    fn extract(&self, path: &Path) -> Result<Extracted, PsError> {
+        return discovery::read_ffmpeg(path);
+    }
+}
+
+/// MP4, MOV and M4A metadata read without ffmpeg, which takes over when ffmpeg isn't built in.
+pub struct BmffExtractor;
+
+impl MetadataExtractor for BmffExtractor {
+    fn name(&self) -> &'static str {
+        return "bmff";
+    }
+
+    fn priority(&self) -> u32 {
+        return 50;
+    }
+
+    fn enabled_by_default(&self) -> bool {
+        return !cfg!(feature = "ffmpeg");
+    }
+
+    fn supports(&self, kind: Option<MediaKind>) -> bool {
+        return kind != Some(MediaKind::Image);
+    }
+
+    fn extract(&self, path: &Path) -> Result<Extracted, PsError> {
+        return bmff::read_bmff(path);
+    }
+}
+
+/// Matroska and WebM segment info read without ffmpeg, which takes over when ffmpeg isn't built in.
+pub struct MatroskaExtractor;
+
+impl MetadataExtractor for MatroskaExtractor {
+    fn name(&self) -> &'static str {
+        return "matroska";
+    }
+
+    fn priority(&self) -> u32 {
+        return 50;
+    }
+
+    fn enabled_by_default(&self) -> bool {
+        return !cfg!(feature = "ffmpeg");
+    }
+
+    fn supports(&self, kind: Option<MediaKind>) -> bool {
+        return kind.is_none() || kind == Some(MediaKind::Video);
+    }
+
+    fn extract(&self, path: &Path) -> Result<Extracted, PsError> {
+        return matroska::read_matroska(path);
+    }
+}
+
+struct Entry {
+    extractor: Box<dyn MetadataExtractor>,
+    enabled: bool,
+}
+
+/// Extractors in the order they run in, each of which can be turned on and off.
+pub struct Registry {
+    entries: Vec<Entry>,
+}
+
+impl Registry {
+    pub fn empty() -> Registry {
+        return Registry {
+            entries: Vec::new(),
+        };
+    }
+
+    /// Every extractor photosort has, enabled or not by default.
+    pub fn builtin() -> Registry {
+        let mut registry = Registry::empty();
+        registry.register(Box::new(ExifExtractor));
+        registry.register(Box::new(XmpExtractor));
+        registry.register(Box::new(IptcExtractor));
+        #[cfg(feature = "ffmpeg")]
+        registry.register(Box::new(FfmpegExtractor));
+        registry.register(Box::new(BmffExtractor));
+        registry.register(Box::new(MatroskaExtractor));
+        registry.register(Box::new(TextExtractor));
+        registry.register(Box::new(AviExtractor));
+        registry.register(Box::new(AvchdExtractor));
+        registry.register(Box::new(AudioExtractor));
+        registry.register(Box::new(FileNameExtractor));
+        registry.register(Box::new(MtimeExtractor));
+        return registry;
+    }
+
+    /// Adds the extractor after those with the same or lower priority.
+    pub fn register(&mut self, extractor: Box<dyn MetadataExtractor>) {
+        let at = self
+            .entries
+            .iter()
+            .position(|e| e.extractor.priority() > extractor.priority())
+            .unwrap_or(self.entries.len());
+        let enabled = extractor.enabled_by_default();
+        self.entries.insert(at, Entry { extractor, enabled });
+    }
+
+    fn position(&self, name: &str) -> Result<usize, PsError> {
+        return match self.entries.iter().position(|e| e.extractor.name() == name) {
+            Some(at) => Ok(at),
+            None => Err(PsError::new(
+                PsErrorKind::Unsupported,
+                format!(
+                    "Unknown extractor {}, expected one of: {}",
+                    name,
+                    self.entries
+                        .iter()
+                        .map(|e| e.extractor.name())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            )),
+        };
+    }
+
+    pub fn set_enabled(&mut self, name: &str, enabled: bool) -> Result<(), PsError> {
+        let at = self.position(name)?;
+        self.entries[at].enabled = enabled;
+        return Ok(());
+    }
+
+    /// Runs the named extractors first, in the given order, and the rest after them as before.
+    pub fn set_order(&mut self, names: &[&str]) -> Result<(), PsError> {
+        for (to, name) in names.iter().enumerate() {
+            let at = self.position(name)?;
+            if at < to {
+                return Err(PsError::new(
+                    PsErrorKind::Unsupported,
+                    format!("Extractor {} is given twice", name),
+                ));
+            }
+            let entry = self.entries.remove(at);
+            self.entries.insert(to, entry);
+        }
+        return Ok(());
+    }
+
+    /// Enabled extractors which support `kind`, in the order to run them in.
+    pub fn extractors_for(&self, kind: Option<MediaKind>) -> Vec<&dyn MetadataExtractor> {
+        return self
+            .entries
+            .iter()
+            .filter(|e| e.enabled && e.extractor.supports(kind))
+            .map(|e| e.extractor.as_ref())
+            .collect();
+    }
+
+    /// Names of enabled extractors, in order.
+    pub fn enabled(&self) -> Vec<&'static str> {
+        return self
+            .entries
+            .iter()
+            .filter(|e| e.enabled)
+            .map(|e| e.extractor.name())
+            .collect();
+    }
+}
+
+// Two registries are the same if the same extractors are enabled, which is what matters for
+// comparing configurations.
+impl PartialEq for Registry {
+    fn eq(&self, other: &Registry) -> bool {
+        return self.enabled() == other.enabled();
+    }
+}
+
+impl Eq for Registry {}
+
+impl std::fmt::Debug for Registry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.enabled()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Fixed(&'static str, u32);
+
+    impl MetadataExtractor for Fixed {
+        fn name(&self) -> &'static str {
+            return self.0;
+        }
+
+        fn priority(&self) -> u32 {
+            return self.1;
+        }
+
+        fn enabled_by_default(&self) -> bool {
+            return self.0 != "off";
+        }
+
+        fn supports(&self, kind: Option<MediaKind>) -> bool {
+            return kind == Some(MediaKind::Image);
+        }
+
+        fn extract(&self, _path: &Path) -> Result<Extracted, PsError> {
+            return Ok(Extracted::default());
+        }
+    }
+
+    #[test]
+    fn test_registry_order_and_selection() -> Result<(), PsError> {
+        let mut registry = Registry::empty();
+        registry.register(Box::new(ExifExtractor));
+        registry.register(Box::new(BmffExtractor));
+        registry.register(Box::new(Fixed("sidecar", 5)));
+        registry.register(Box::new(Fixed("off", 20)));
+        registry.set_enabled("bmff", true)?;
+        assert_eq!(registry.enabled(), vec!["sidecar", "exif", "bmff"]);
+
+        let names = |registry: &Registry, kind| {
+            registry
+                .extractors_for(kind)
+                .iter()
+                .map(|e| e.name())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(&registry, Some(MediaKind::Video)), vec!["bmff"]);
+
+        registry.set_enabled("off", true)?;
+        registry.set_enabled("exif", false)?;
+        assert_eq!(
+            names(&registry, Some(MediaKind::Image)),
+            vec!["sidecar", "off"]
+        );
+        assert!(registry.set_enabled("heic", true).is_err());
+
+        registry.set_order(&["off", "bmff"])?;
+        assert_eq!(registry.enabled(), vec!["off", "bmff", "sidecar"]);
+        assert!(registry.set_order(&["bmff", "bmff"]).is_err());
+        return Ok(());
+    }
+}
@@ -0,0 +1,161 @@
+use crate::extract::Extracted;
+use crate::pserror::error::{PsError, PsErrorKind};
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, NaiveTime};
+use std::path::Path;
+
+/// Extensions of files whose modification time is worth trusting: screenshots, downloads and
+/// memes are often PNG or GIF without any date inside, and haven't been edited since. Photos and
+/// videos have their date inside, so their modification time is more likely when they were copied.
+const MTIME_EXTENSIONS: [&str; 2] = ["png", "gif"];
+
+fn no_date(msg: String) -> PsError {
+    return PsError::new(PsErrorKind::NoDateField, msg);
+}
+
+/// Runs of digits in `name`, e.g. `IMG_20210306_164713` has two.
+fn digit_runs(name: &str) -> Vec<&str> {
+    let mut runs = Vec::new();
+    let mut start = None;
+    for (i, c) in name.char_indices() {
+        match (c.is_ascii_digit(), start) {
+            (true, None) => start = Some(i),
+            (false, Some(from)) => {
+                runs.push(&name[from..i]);
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(from) = start {
+        runs.push(&name[from..]);
+    }
+    return runs;
+}
+
+fn time_of(hours: &str, minutes: &str, seconds: &str) -> Option<NaiveTime> {
+    return NaiveTime::from_hms_opt(
+        hours.parse().ok()?,
+        minutes.parse().ok()?,
+        seconds.parse().ok()?,
+    );
+}
+
+/// Finds the date in a file name as phones, cameras and screenshot tools write it, e.g.
+/// `IMG_20210306_164713.jpg`, `PXL_20210306_164713123.jpg`, `Screenshot 2021-03-06 at 16.47.13.png`
+/// or `IMG-20210306-WA0001.jpg`. A name with only a day is dated at midnight.
+pub fn date_from_name(name: &str) -> Option<NaiveDateTime> {
+    let runs = digit_runs(name);
+    let len = |i: usize| runs.get(i).map_or(0, |run| run.len());
+    for i in 0..runs.len() {
+        let run = runs[i];
+        // YYYYMMDD, then HHMMSS in the same run or the next one.
+        if run.len() >= 8 {
+            let day = match NaiveDate::parse_from_str(&run[..8], "%Y%m%d") {
+                Ok(day) => day,
+                Err(_) => continue,
+            };
+            let time = if run.len() >= 14 {
+                time_of(&run[8..10], &run[10..12], &run[12..14])
+            } else if run.len() == 8 && len(i + 1) >= 6 {
+                let next = runs[i + 1];
+                time_of(&next[..2], &next[2..4], &next[4..6])
+            } else {
+                None
+            };
+            return Some(day.and_time(time.unwrap_or_else(|| NaiveTime::from_hms(0, 0, 0))));
+        }
+        // YYYY MM DD, then HH MM SS, with anything between them.
+        if run.len() == 4 && len(i + 1) == 2 && len(i + 2) == 2 {
+            let day = match NaiveDate::from_ymd_opt(
+                run.parse().ok()?,
+                runs[i + 1].parse().ok()?,
+                runs[i + 2].parse().ok()?,
+            ) {
+                Some(day) => day,
+                None => continue,
+            };
+            let time = if (3..6).all(|j| len(i + j) == 2) {
+                time_of(runs[i + 3], runs[i + 4], runs[i + 5])
+            } else {
+                None
+            };
+            return Some(day.and_time(time.unwrap_or_else(|| NaiveTime::from_hms(0, 0, 0))));
+        }
+    }
+    return None;
+}
+
+/// Dates the file by its name, for files which carry no date inside.
+pub fn read_file_name(path: &Path) -> Result<Extracted, PsError> {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    return match date_from_name(&stem) {
+        Some(date) => Ok(Extracted {
+            dates: vec![("file name".to_string(), date)],
+            ..Default::default()
+        }),
+        None => Err(no_date(format!(
+            "No date in the name of {}",
+            path.display()
+        ))),
+    };
+}
+
+/// Dates PNG and GIF files by when they were last modified. Other files fail, see
+/// `MTIME_EXTENSIONS`.
+pub fn read_mtime(path: &Path) -> Result<Extracted, PsError> {
+    let extension = path
+        .extension()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_lowercase();
+    if !MTIME_EXTENSIONS.contains(&extension.as_str()) {
+        return Err(no_date(format!(
+            "Not dating {} by its modification time",
+            path.display()
+        )));
+    }
+    let modified = DateTime::<Local>::from(std::fs::metadata(path)?.modified()?);
+    return Ok(Extracted {
+        dates: vec![("modification time".to_string(), modified.naive_local())],
+        ..Default::default()
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_date_from_name() {
+        let date = NaiveDate::from_ymd(2021, 3, 6).and_hms(16, 47, 13);
+        for name in &[
+            "IMG_20210306_164713",
+            "PXL_20210306_164713123.MP",
+            "Screenshot_20210306-164713_Chrome",
+            "Screenshot 2021-03-06 at 16.47.13",
+            "2021-03-06 16-47-13",
+            "VID20210306164713",
+        ] {
+            assert_eq!(date_from_name(name), Some(date), "{}", name);
+        }
+        assert_eq!(
+            date_from_name("IMG-20210306-WA0001"),
+            Some(date.date().and_hms(0, 0, 0))
+        );
+        assert_eq!(date_from_name("DSC_0001"), None);
+        assert_eq!(date_from_name("IMG_99999999"), None);
+    }
+
+    #[test]
+    fn test_read_mtime() -> Result<(), PsError> {
+        let temp_dir = tempfile::tempdir()?;
+        let png = temp_dir.path().join("meme.png");
+        let jpg = temp_dir.path().join("photo.jpg");
+        std::fs::write(&png, b"")?;
+        std::fs::write(&jpg, b"")?;
+        let date = read_mtime(&png)?.dates[0].1;
+        assert!((Local::now().naive_local() - date).num_minutes().abs() < 5);
+        assert!(read_mtime(&jpg).is_err());
+        return Ok(());
+    }
+}
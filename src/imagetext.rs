@@ -0,0 +1,242 @@
+use crate::extract::Extracted;
+use crate::pserror::error::{PsError, PsErrorKind};
+use crate::xmp::parse_xmp_date;
+use chrono::{DateTime, NaiveDateTime};
+use log::info;
+use std::path::Path;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Keyword of PNG text chunks holding the time the image was created.
+const CREATION_TIME: &str = "Creation Time";
+
+fn format_error(msg: String) -> PsError {
+    return PsError::new(PsErrorKind::FormatError, msg);
+}
+
+/// Parses a date as found in free text: RFC 1123 as the PNG specification suggests, EXIF style
+/// or ISO 8601. A time zone is dropped, keeping the local time.
+pub fn parse_text_date(text: &str) -> Option<NaiveDateTime> {
+    let text = text.trim();
+    if let Ok(date) = DateTime::parse_from_rfc2822(text) {
+        return Some(date.naive_local());
+    }
+    for format in &["%Y:%m:%d %H:%M:%S", "%Y-%m-%d %H:%M:%S"] {
+        if let Ok(date) = NaiveDateTime::parse_from_str(text, format) {
+            return Some(date);
+        }
+    }
+    return parse_xmp_date(text)
+        .filter(|(_, has_time, _)| *has_time)
+        .map(|(date, _, _)| date);
+}
+
+/// Keyword and text of the uncompressed text chunks of a PNG: `tEXt`, which is Latin-1, and
+/// `iTXt`, which is UTF-8 after a compression flag, method, language tag and translated keyword.
+fn png_text(data: &[u8]) -> Vec<(String, String)> {
+    let mut texts = Vec::new();
+    let mut pos = PNG_SIGNATURE.len();
+    while pos + 8 <= data.len() {
+        let length =
+            u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
+        let chunk_type = &data[pos + 4..pos + 8];
+        let chunk = match data.get(pos + 8..pos + 8 + length) {
+            Some(chunk) => chunk,
+            None => break,
+        };
+        let mut fields = chunk.splitn(2, |&b| b == 0);
+        let keyword = String::from_utf8_lossy(fields.next().unwrap_or(&[])).to_string();
+        let rest = fields.next().unwrap_or(&[]);
+        match chunk_type {
+            b"tEXt" => texts.push((keyword, rest.iter().map(|&b| b as char).collect())),
+            b"iTXt" if rest.first() == Some(&0) => {
+                // Skip the flags, then the language tag and the translated keyword.
+                let mut fields = rest.get(2..).unwrap_or(&[]).splitn(3, |&b| b == 0);
+                if let Some(text) = fields.nth(2) {
+                    texts.push((keyword, String::from_utf8_lossy(text).to_string()));
+                }
+            }
+            b"IEND" => break,
+            _ => {}
+        }
+        pos += 8 + length + 4;
+    }
+    return texts;
+}
+
+/// Sub-blocks of a GIF starting at `pos`, joined, and where they end.
+fn gif_sub_blocks(data: &[u8], mut pos: usize) -> Option<(Vec<u8>, usize)> {
+    let mut joined = Vec::new();
+    loop {
+        let size = *data.get(pos)? as usize;
+        pos += 1;
+        if size == 0 {
+            return Some((joined, pos));
+        }
+        joined.extend_from_slice(data.get(pos..pos + size)?);
+        pos += size;
+    }
+}
+
+/// Text of the comment extensions of a GIF.
+fn gif_comments(data: &[u8]) -> Vec<String> {
+    let mut comments = Vec::new();
+    // Header and logical screen descriptor, then the global colour table if there is one.
+    let mut pos = 13;
+    match data.get(10) {
+        Some(flags) if flags & 0x80 != 0 => pos += 3 << ((flags & 0x07) + 1),
+        Some(_) => {}
+        None => return comments,
+    }
+    loop {
+        match data.get(pos) {
+            Some(0x21) => {
+                let label = data.get(pos + 1).cloned();
+                let (blocks, end) = match gif_sub_blocks(data, pos + 2) {
+                    Some(blocks) => blocks,
+                    None => break,
+                };
+                if label == Some(0xFE) {
+                    comments.push(blocks.iter().map(|&b| b as char).collect());
+                }
+                pos = end;
+            }
+            Some(0x2C) => {
+                // Image descriptor, local colour table, LZW code size and image data.
+                let flags = match data.get(pos + 9) {
+                    Some(flags) => *flags,
+                    None => break,
+                };
+                pos += 10;
+                if flags & 0x80 != 0 {
+                    pos += 3 << ((flags & 0x07) + 1);
+                }
+                pos = match gif_sub_blocks(data, pos + 1) {
+                    Some((_, end)) => end,
+                    None => break,
+                };
+            }
+            _ => break,
+        }
+    }
+    return comments;
+}
+
+/// Finds a date in a comment, which is either just the date or has it on a line of its own,
+/// possibly after a label such as `Created: `.
+fn comment_date(comment: &str) -> Option<NaiveDateTime> {
+    return parse_text_date(comment).or_else(|| {
+        comment.lines().find_map(|line| {
+            parse_text_date(line).or_else(|| {
+                let at = line.find(": ")?;
+                parse_text_date(&line[at + 2..])
+            })
+        })
+    });
+}
+
+/// Reads the creation time of a PNG from its text chunks and dates in the comments of a GIF,
+/// which is what screenshot tools and image editors leave when they don't write EXIF. XMP in
+/// either is read by the XMP extractor, and EXIF in a PNG `eXIf` chunk by the EXIF one.
+pub fn read_image_text(path: &Path) -> Result<Extracted, PsError> {
+    let data = std::fs::read(path)?;
+    let mut extracted = Extracted::default();
+    if data.starts_with(PNG_SIGNATURE) {
+        for (keyword, text) in png_text(&data) {
+            if keyword == CREATION_TIME {
+                match parse_text_date(&text) {
+                    Some(date) => extracted
+                        .dates
+                        .push(("PNG Creation Time".to_string(), date)),
+                    None => info!("Couldn't parse PNG Creation Time {} in {:?}", text, path),
+                }
+            }
+        }
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        for comment in gif_comments(&data) {
+            if let Some(date) = comment_date(&comment) {
+                extracted.dates.push(("GIF comment".to_string(), date));
+            }
+        }
+    } else {
+        return Err(format_error(format!(
+            "{} is neither PNG nor GIF",
+            path.display()
+        )));
+    }
+    return Ok(extracted);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn png_chunk(chunk_type: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(chunk_type);
+        chunk.extend_from_slice(data);
+        chunk.extend_from_slice(&crc32fast::hash(&chunk[4..]).to_be_bytes());
+        return chunk;
+    }
+
+    #[test]
+    fn test_parse_text_date() {
+        let date = NaiveDate::from_ymd(2021, 3, 6).and_hms(16, 47, 13);
+        assert_eq!(parse_text_date("Sat, 06 Mar 2021 16:47:13 GMT"), Some(date));
+        assert_eq!(parse_text_date("6 Mar 2021 16:47:13 +0100"), Some(date));
+        assert_eq!(parse_text_date("2021:03:06 16:47:13"), Some(date));
+        assert_eq!(parse_text_date("2021-03-06T16:47:13+01:00"), Some(date));
+        assert_eq!(parse_text_date("2021-03-06"), None);
+        assert_eq!(parse_text_date("made with GIMP"), None);
+    }
+
+    #[test]
+    fn test_read_png_text() -> Result<(), PsError> {
+        let mut png = PNG_SIGNATURE.to_vec();
+        png.extend(png_chunk(b"IHDR", &[0u8; 13]));
+        png.extend(png_chunk(b"tEXt", b"Software\0Screenshot tool"));
+        png.extend(png_chunk(
+            b"iTXt",
+            b"Creation Time\0\0\0en\0\0Sat, 06 Mar 2021 16:47:13 GMT",
+        ));
+        png.extend(png_chunk(b"IEND", b""));
+
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("screenshot.png");
+        std::fs::write(&path, png)?;
+        assert_eq!(
+            read_image_text(&path)?.dates,
+            vec![(
+                "PNG Creation Time".to_string(),
+                NaiveDate::from_ymd(2021, 3, 6).and_hms(16, 47, 13)
+            )]
+        );
+        return Ok(());
+    }
+
+    #[test]
+    fn test_read_gif_comment() -> Result<(), PsError> {
+        // 1x1 with a two-colour global table, a comment split over two sub-blocks and one pixel.
+        let mut gif = b"GIF89a\x01\x00\x01\x00\x80\x00\x00".to_vec();
+        gif.extend_from_slice(&[0, 0, 0, 255, 255, 255]);
+        gif.extend_from_slice(b"\x21\xfe\x0bCreated: 20\x1121:03:06 16:47:13\x00");
+        gif.extend_from_slice(b"\x2c\0\0\0\0\x01\0\x01\0\0\x02\x02\x44\x01\x00");
+        gif.extend_from_slice(b"\x3b");
+
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("meme.gif");
+        std::fs::write(&path, gif)?;
+        assert_eq!(
+            read_image_text(&path)?.dates,
+            vec![(
+                "GIF comment".to_string(),
+                NaiveDate::from_ymd(2021, 3, 6).and_hms(16, 47, 13)
+            )]
+        );
+
+        std::fs::write(&path, b"\xff\xd8\xff\xd9")?;
+        assert!(read_image_text(&path).is_err());
+        return Ok(());
+    }
+}
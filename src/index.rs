@@ -0,0 +1,514 @@
+use crate::config::configurator::{OutputFormat, QueryOptions};
+use crate::discovery::discovery;
+use crate::extract::Registry;
+use crate::layout::Destinations;
+use crate::pserror::error::{PsError, PsErrorKind};
+use crate::Photo;
+use chrono::{Duration, NaiveDateTime};
+use exif::{In, Tag, Value};
+use log::{info, warn};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+/// Name of the index database, kept in the root of the destination library.
+pub const INDEX_FILE_NAME: &str = ".photosort.db";
+
+const DATE_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// Single file of the library as recorded in the index.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct IndexEntry {
+    /// Path relative to the library root.
+    pub path: String,
+    pub date: Option<NaiveDateTime>,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// SHA-256 of the file contents, hex-encoded.
+    pub hash: String,
+    /// SHA-256 of the file as it was imported, which differs from `hash` once `--write-dates` has
+    /// changed the file. None if not known, e.g. for files found by a rebuild.
+    pub original_hash: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    /// Where the file was imported from, if known.
+    pub source: Option<String>,
+}
+
+pub struct Index {
+    root: PathBuf,
+    conn: Connection,
+}
+
+impl Index {
+    /// Opens (creating if necessary) the index of the library rooted at `root`.
+    pub fn open<P: AsRef<Path>>(root: P) -> Result<Index, PsError> {
+        let root = root.as_ref().to_path_buf();
+        std::fs::create_dir_all(&root)?;
+        let conn = Connection::open(root.join(INDEX_FILE_NAME))?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS photos (
+                path TEXT PRIMARY KEY,
+                date TEXT,
+                camera_make TEXT,
+                camera_model TEXT,
+                width INTEGER,
+                height INTEGER,
+                hash TEXT NOT NULL,
+                latitude REAL,
+                longitude REAL,
+                source TEXT,
+                original_hash TEXT
+            )",
+            params![],
+        )?;
+        // Indexes created before original hashes were recorded.
+        let has_original_hash = conn
+            .prepare("SELECT original_hash FROM photos LIMIT 0")
+            .is_ok();
+        if !has_original_hash {
+            conn.execute(
+                "ALTER TABLE photos ADD COLUMN original_hash TEXT",
+                params![],
+            )?;
+        }
+        return Ok(Index { root, conn });
+    }
+
+    /// Records a photo which has just been placed at its `new_path`. `original_hash` is the hash
+    /// of the file before `--write-dates` changed it, if it did.
+    pub fn record_photo(
+        &self,
+        photo: &Photo,
+        source: &str,
+        original_hash: Option<String>,
+    ) -> Result<(), PsError> {
+        let new_path = match photo.new_path() {
+            Some(new_path) => Path::new(new_path),
+            None => {
+                return Err(PsError::new(
+                    PsErrorKind::IndexError,
+                    format!("Photo {:?} has not been placed yet", photo.path()),
+                ));
+            }
+        };
+        let mut entry = self.photo_entry(new_path, photo, Some(source.to_string()))?;
+        entry.original_hash = Some(original_hash.unwrap_or_else(|| entry.hash.clone()));
+        return self.insert(&entry);
+    }
+
+    /// Whether the file at `path` was imported from a file with the contents of `source` and has
+    /// been changed since by `--write-dates` only.
+    pub fn is_imported_from(&self, path: &Path, source: &Path) -> Result<bool, PsError> {
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+        let hashes: Option<(String, Option<String>)> = self
+            .conn
+            .query_row(
+                "SELECT hash, original_hash FROM photos WHERE path = ?1",
+                params![relative.to_string_lossy()],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        return match hashes {
+            Some((hash, Some(original_hash))) => {
+                Ok(original_hash == hash_file(source)? && hash == hash_file(path)?)
+            }
+            _ => Ok(false),
+        };
+    }
+
+    pub fn insert(&self, entry: &IndexEntry) -> Result<(), PsError> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO photos
+                (path, date, camera_make, camera_model, width, height, hash, latitude, longitude, source,
+                 original_hash)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            params![
+                entry.path,
+                entry.date.map(|d| d.format(DATE_FORMAT).to_string()),
+                entry.camera_make,
+                entry.camera_model,
+                entry.width,
+                entry.height,
+                entry.hash,
+                entry.latitude,
+                entry.longitude,
+                entry.source,
+                entry.original_hash,
+            ],
+        )?;
+        return Ok(());
+    }
+
+    /// Drops the index and re-creates it from files found under the library root and the
+    /// directories of kinds of files kept elsewhere, reading them with the given extractors.
+    /// Returns the number of files indexed.
+    pub fn rebuild(
+        &self,
+        destinations: &Destinations,
+        extractors: &Registry,
+    ) -> Result<usize, PsError> {
+        // Original hashes can't be found out again, so they are kept for files which haven't
+        // changed.
+        let mut original_hashes: HashMap<String, (String, String)> = HashMap::new();
+        {
+            let mut statement = self.conn.prepare(
+                "SELECT path, hash, original_hash FROM photos WHERE original_hash IS NOT NULL",
+            )?;
+            let rows = statement.query_map(params![], |row| {
+                Ok((row.get(0)?, (row.get(1)?, row.get(2)?)))
+            })?;
+            for row in rows {
+                let (path, hashes) = row?;
+                original_hashes.insert(path, hashes);
+            }
+        }
+        self.conn.execute("DELETE FROM photos", params![])?;
+
+        let mut roots: Vec<PathBuf> = vec![self.root.clone()];
+        for root in destinations.roots() {
+            // Directories inside one already walked, and those nothing was sorted into yet.
+            if root.is_dir() && !roots.iter().any(|walked| root.starts_with(walked)) {
+                roots.push(root);
+            }
+        }
+        let mut files: Vec<PathBuf> = Vec::new();
+        for root in &roots {
+            files.extend(
+                discovery::list_all_files(root)?
+                    .into_iter()
+                    .filter(|f| discovery::is_supported_file(&f.to_string_lossy())),
+            );
+        }
+
+        let mut indexed = 0;
+        for file in files {
+            let path = file.as_path();
+            let entry =
+                match discovery::discover_file(path, extractors, discovery::default_min_date()) {
+                    Ok(photo) => self.photo_entry(path, &photo, None),
+                    Err(_) => self.read_entry(path),
+                };
+            match entry {
+                Ok(mut entry) => {
+                    if let Some((hash, original_hash)) = original_hashes.remove(&entry.path) {
+                        if hash == entry.hash {
+                            entry.original_hash = Some(original_hash);
+                        }
+                    }
+                    self.insert(&entry)?;
+                    indexed += 1;
+                }
+                Err(err) => warn!("Couldn't index {:?}: {}", path, err),
+            }
+        }
+
+        info!("Indexed {} files in {:?}", indexed, self.root);
+        return Ok(indexed);
+    }
+
+    /// Returns all entries matching every filter in `query`, ordered by date.
+    pub fn query(&self, query: &QueryOptions) -> Result<Vec<IndexEntry>, PsError> {
+        let mut sql = String::from("SELECT * FROM photos WHERE 1 = 1");
+        let mut args: Vec<String> = Vec::new();
+
+        if let Some(camera) = &query.camera {
+            args.push(format!("%{}%", camera.to_lowercase()));
+            sql.push_str(&format!(
+                " AND (lower(camera_make) LIKE ?{0} OR lower(camera_model) LIKE ?{0})",
+                args.len()
+            ));
+        }
+        if let Some(from) = query.from {
+            args.push(from.and_hms(0, 0, 0).format(DATE_FORMAT).to_string());
+            sql.push_str(&format!(" AND date >= ?{}", args.len()));
+        }
+        if let Some(to) = query.to {
+            let next_day = to.and_hms(0, 0, 0) + Duration::days(1);
+            args.push(next_day.format(DATE_FORMAT).to_string());
+            sql.push_str(&format!(" AND date < ?{}", args.len()));
+        }
+        sql.push_str(" ORDER BY date, path");
+
+        let mut statement = self.conn.prepare(&sql)?;
+        let rows = statement.query_map(args, entry_from_row)?;
+
+        let mut entries = Vec::new();
+        for row in rows {
+            entries.push(row?);
+        }
+        return Ok(entries);
+    }
+
+    /// Entry for the file at `path` from what discovery found out about `photo`. Only the hash is
+    /// taken from the file itself, which may have changed since, e.g. by `--write-dates`.
+    fn photo_entry(
+        &self,
+        path: &Path,
+        photo: &Photo,
+        source: Option<String>,
+    ) -> Result<IndexEntry, PsError> {
+        let metadata = photo.metadata();
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+        return Ok(IndexEntry {
+            path: relative.to_string_lossy().to_string(),
+            date: photo.date(),
+            camera_make: metadata.camera.make.clone(),
+            camera_model: metadata.camera.model.clone(),
+            width: metadata.width,
+            height: metadata.height,
+            hash: hash_file(path)?,
+            original_hash: None,
+            latitude: metadata.gps.map(|gps| gps.latitude),
+            longitude: metadata.gps.map(|gps| gps.longitude),
+            source,
+        });
+    }
+
+    /// Entry for a file which couldn't be discovered, from whatever EXIF it has.
+    fn read_entry(&self, path: &Path) -> Result<IndexEntry, PsError> {
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+        let mut entry = IndexEntry {
+            path: relative.to_string_lossy().to_string(),
+            hash: hash_file(path)?,
+            ..Default::default()
+        };
+
+        // Not every supported file has EXIF, e.g. videos, which is fine.
+        let file = File::open(path)?;
+        if let Ok(exif) = exif::Reader::new().read_from_container(&mut BufReader::new(file)) {
+            entry.camera_make = exif_string(&exif, Tag::Make);
+            entry.camera_model = exif_string(&exif, Tag::Model);
+            entry.width = exif_uint(&exif, Tag::PixelXDimension);
+            entry.height = exif_uint(&exif, Tag::PixelYDimension);
+            entry.latitude = exif_coordinate(&exif, Tag::GPSLatitude, Tag::GPSLatitudeRef, "S");
+            entry.longitude = exif_coordinate(&exif, Tag::GPSLongitude, Tag::GPSLongitudeRef, "W");
+        }
+
+        return Ok(entry);
+    }
+
+    /// Full path of the indexed file.
+    pub fn full_path(&self, entry: &IndexEntry) -> PathBuf {
+        return self.root.join(&entry.path);
+    }
+}
+
+fn entry_from_row(row: &Row) -> rusqlite::Result<IndexEntry> {
+    let date: Option<String> = row.get("date")?;
+    return Ok(IndexEntry {
+        path: row.get("path")?,
+        date: date.and_then(|d| NaiveDateTime::parse_from_str(&d, DATE_FORMAT).ok()),
+        camera_make: row.get("camera_make")?,
+        camera_model: row.get("camera_model")?,
+        width: row.get("width")?,
+        height: row.get("height")?,
+        hash: row.get("hash")?,
+        latitude: row.get("latitude")?,
+        longitude: row.get("longitude")?,
+        source: row.get("source")?,
+        original_hash: row.get("original_hash")?,
+    });
+}
+
+pub fn hash_file(path: &Path) -> Result<String, PsError> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    return Ok(hex::encode(hasher.finalize()));
+}
+
+pub fn exif_string(exif: &exif::Exif, tag: Tag) -> Option<String> {
+    return match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Ascii(values) if !values.is_empty() => {
+            let value = String::from_utf8_lossy(&values[0]).trim().to_string();
+            if value.is_empty() {
+                None
+            } else {
+                Some(value)
+            }
+        }
+        _ => None,
+    };
+}
+
+pub fn exif_uint(exif: &exif::Exif, tag: Tag) -> Option<u32> {
+    return exif.get_field(tag, In::PRIMARY)?.value.get_uint(0);
+}
+
+/// Converts EXIF degrees/minutes/seconds into signed decimal degrees.
+pub fn exif_coordinate(
+    exif: &exif::Exif,
+    tag: Tag,
+    ref_tag: Tag,
+    negative_ref: &str,
+) -> Option<f64> {
+    let degrees = match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Rational(parts) if parts.len() == 3 => {
+            parts[0].to_f64() + parts[1].to_f64() / 60.0 + parts[2].to_f64() / 3600.0
+        }
+        _ => return None,
+    };
+    return match exif_string(exif, ref_tag) {
+        Some(r) if r == negative_ref => Some(-degrees),
+        _ => Some(degrees),
+    };
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains(',') || value.contains('"') || value.contains('\n') {
+        return format!("\"{}\"", value.replace('"', "\"\""));
+    }
+    return value.to_string();
+}
+
+/// Renders query results in the requested format.
+pub fn format_entries(index: &Index, entries: &[IndexEntry], format: OutputFormat) -> String {
+    let opt = |v: Option<String>| v.unwrap_or_default();
+    return match format {
+        OutputFormat::Paths => entries
+            .iter()
+            .map(|e| format!("{}\n", index.full_path(e).display()))
+            .collect(),
+        OutputFormat::Csv => {
+            let mut out = String::from(
+                "path,date,camera_make,camera_model,width,height,hash,latitude,longitude,source\n",
+            );
+            for e in entries {
+                let fields = vec![
+                    index.full_path(e).display().to_string(),
+                    opt(e.date.map(|d| d.format(DATE_FORMAT).to_string())),
+                    opt(e.camera_make.clone()),
+                    opt(e.camera_model.clone()),
+                    opt(e.width.map(|w| w.to_string())),
+                    opt(e.height.map(|h| h.to_string())),
+                    e.hash.clone(),
+                    opt(e.latitude.map(|l| l.to_string())),
+                    opt(e.longitude.map(|l| l.to_string())),
+                    opt(e.source.clone()),
+                ];
+                let fields: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+                out.push_str(&fields.join(","));
+                out.push('\n');
+            }
+            out
+        }
+        OutputFormat::Json => {
+            let values: Vec<serde_json::Value> = entries
+                .iter()
+                .map(|e| {
+                    serde_json::json!({
+                        "path": index.full_path(e).display().to_string(),
+                        "date": e.date.map(|d| d.format(DATE_FORMAT).to_string()),
+                        "camera_make": e.camera_make,
+                        "camera_model": e.camera_model,
+                        "width": e.width,
+                        "height": e.height,
+                        "hash": e.hash,
+                        "latitude": e.latitude,
+                        "longitude": e.longitude,
+                        "source": e.source,
+                    })
+                })
+                .collect();
+            let mut out = serde_json::to_string_pretty(&values).unwrap_or_default();
+            out.push('\n');
+            out
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::photo::{Camera, Metadata, PhotoBuilder};
+    use chrono::NaiveDate;
+
+    #[test]
+    fn test_record_and_query() -> Result<(), PsError> {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().to_str().unwrap().to_string();
+        let new_path = format!("{}/2004/08/27/Nikon_D70.jpg", root);
+        std::fs::create_dir_all(format!("{}/2004/08/27", root))?;
+        std::fs::copy("./test-assets/jpg/Nikon_D70.jpg", &new_path)?;
+
+        let index = Index::open(&root)?;
+        let photo = PhotoBuilder::new()
+            .with_path("./test-assets/jpg/Nikon_D70.jpg".to_string())
+            .with_date(NaiveDate::from_ymd(2004, 8, 27).and_hms(13, 52, 55))
+            .with_new_path(new_path)
+            .with_metadata(Metadata {
+                camera: Camera {
+                    make: Some("NIKON CORPORATION".to_string()),
+                    model: Some("NIKON D70".to_string()),
+                    serial: None,
+                },
+                ..Default::default()
+            })
+            .build();
+        index.record_photo(&photo, "card", None)?;
+
+        let mut query = QueryOptions {
+            camera: Some("d70".to_string()),
+            from: Some(NaiveDate::from_ymd(2004, 8, 1)),
+            to: Some(NaiveDate::from_ymd(2004, 8, 27)),
+            format: OutputFormat::Paths,
+        };
+        let entries = index.query(&query)?;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].path, "2004/08/27/Nikon_D70.jpg");
+        assert_eq!(entries[0].camera_model.as_deref(), Some("NIKON D70"));
+        assert_eq!(entries[0].source.as_deref(), Some("card"));
+
+        query.to = Some(NaiveDate::from_ymd(2004, 8, 26));
+        assert!(index.query(&query)?.is_empty());
+
+        return Ok(());
+    }
+
+    #[test]
+    fn test_rebuild() -> Result<(), PsError> {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().to_str().unwrap().to_string();
+        std::fs::copy(
+            "./test-assets/jpg/gps/DSCN0010.jpg",
+            format!("{}/a.jpg", root),
+        )?;
+        std::fs::copy("./test-assets/jpg/Canon_40D.jpg", format!("{}/b.jpg", root))?;
+        // Videos kept on another volume.
+        let videos_dir = tempfile::tempdir().unwrap();
+        let video = videos_dir.path().join("2021/03/06/clip.mp4");
+        std::fs::create_dir_all(video.parent().unwrap())?;
+        std::fs::write(&video, b"not really a video")?;
+        let mut destinations = Destinations::new(&root);
+        destinations.set_dir(
+            crate::layout::Category::Videos,
+            videos_dir.path().to_str().unwrap(),
+        );
+
+        let index = Index::open(&root)?;
+        assert_eq!(index.rebuild(&destinations, &Registry::builtin())?, 3);
+
+        let query = QueryOptions {
+            camera: None,
+            from: None,
+            to: None,
+            format: OutputFormat::Csv,
+        };
+        let entries = index.query(&query)?;
+        assert_eq!(entries.len(), 3);
+        assert!(entries
+            .iter()
+            .any(|e| Path::new(&e.path) == video.as_path()));
+        let gps = entries.iter().find(|e| e.path == "a.jpg").unwrap();
+        assert!(gps.latitude.unwrap() > 43.0 && gps.latitude.unwrap() < 44.0);
+        assert!(format_entries(&index, &entries, OutputFormat::Csv).starts_with("path,date"));
+
+        return Ok(());
+    }
+}
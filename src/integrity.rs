@@ -0,0 +1,238 @@
+use crate::bmff;
+use crate::discovery::discovery;
+use crate::photo::MediaKind;
+use crate::pserror::error::{PsError, PsErrorKind, Stage};
+use log::info;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+fn corrupt(msg: String) -> PsError {
+    return PsError::new(PsErrorKind::Corrupt, msg).at_stage(Stage::Verify);
+}
+
+/// Checks that the file at `path` isn't damaged or truncated, so that it can be quarantined
+/// before it enters the library. `name` is the original file name, which is what tells what the
+/// file should be when it has been extracted into a temporary file.
+///
+/// JPEG and PNG files are checked by walking their structure, MP4 and MOV by walking their boxes.
+/// Those and other videos are then, if built with ffmpeg, read packet by packet; other files are
+/// accepted as they are.
+pub fn check_file(path: &Path, name: &str) -> Result<(), PsError> {
+    let name = name.to_lowercase();
+    let mut header = [0u8; 12];
+    let header_len = File::open(path)?.read(&mut header)?;
+    let header = &header[..header_len];
+
+    if header.starts_with(b"\xff\xd8") {
+        return check_jpeg(&std::fs::read(path)?);
+    }
+    if header.starts_with(PNG_SIGNATURE) {
+        return check_png(&std::fs::read(path)?);
+    }
+    if name.ends_with(".jpg") || name.ends_with(".jpeg") {
+        return Err(corrupt("Not a JPEG file".to_string()));
+    }
+    if name.ends_with(".png") {
+        return Err(corrupt("Not a PNG file".to_string()));
+    }
+    if [".mp4", ".mov", ".mp", ".m4v", ".3gp", ".m4a"]
+        .iter()
+        .any(|extension| name.ends_with(extension))
+    {
+        return check_video(path);
+    }
+    if discovery::media_kind(&name) == Some(MediaKind::Video) {
+        return check_packets(path);
+    }
+    return Ok(());
+}
+
+fn check_jpeg(data: &[u8]) -> Result<(), PsError> {
+    let truncated = || corrupt(format!("JPEG is truncated, {} bytes long", data.len()));
+    let mut pos = 2;
+    loop {
+        if pos >= data.len() {
+            return Err(truncated());
+        }
+        if data[pos] != 0xFF {
+            return Err(corrupt(format!("No JPEG marker at offset {}", pos)));
+        }
+        // Any number of 0xFF may pad a marker.
+        while pos < data.len() && data[pos] == 0xFF {
+            pos += 1;
+        }
+        if pos >= data.len() {
+            return Err(truncated());
+        }
+        let marker = data[pos];
+        pos += 1;
+
+        match marker {
+            // EOI, anything after it (e.g. maker trailers) isn't part of the image.
+            0xD9 => return Ok(()),
+            // Markers without a segment.
+            0x01 | 0xD0..=0xD7 => continue,
+            _ => {}
+        }
+
+        if pos + 2 > data.len() {
+            return Err(truncated());
+        }
+        let length = (data[pos] as usize) << 8 | data[pos + 1] as usize;
+        if length < 2 || pos + length > data.len() {
+            return Err(truncated());
+        }
+        pos += length;
+
+        if marker == 0xDA {
+            // SOS is followed by entropy-coded data, which runs until the next marker other than
+            // a stuffed 0xFF00 or a restart marker.
+            loop {
+                if pos + 1 >= data.len() {
+                    return Err(truncated());
+                }
+                if data[pos] == 0xFF
+                    && data[pos + 1] != 0
+                    && !(0xD0..=0xD7).contains(&data[pos + 1])
+                {
+                    break;
+                }
+                pos += 1;
+            }
+        }
+    }
+}
+
+fn check_png(data: &[u8]) -> Result<(), PsError> {
+    let mut pos = PNG_SIGNATURE.len();
+    loop {
+        if pos + 8 > data.len() {
+            return Err(corrupt(format!(
+                "PNG is truncated, {} bytes long",
+                data.len()
+            )));
+        }
+        let length =
+            u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
+        let chunk_type = &data[pos + 4..pos + 8];
+        // Type, data and CRC.
+        let end = pos + 8 + length + 4;
+        if end > data.len() {
+            return Err(corrupt(format!(
+                "PNG is truncated, {} bytes long",
+                data.len()
+            )));
+        }
+
+        let crc = u32::from_be_bytes([data[end - 4], data[end - 3], data[end - 2], data[end - 1]]);
+        if crc32fast::hash(&data[pos + 4..end - 4]) != crc {
+            return Err(corrupt(format!(
+                "Bad CRC of PNG chunk {} at offset {}",
+                String::from_utf8_lossy(chunk_type),
+                pos
+            )));
+        }
+
+        if chunk_type == b"IEND" {
+            return Ok(());
+        }
+        pos = end;
+    }
+}
+
+fn check_video(path: &Path) -> Result<(), PsError> {
+    bmff::check_structure(path).map_err(|e| corrupt(e.message().to_string()))?;
+    info!("Box structure of {} is intact", path.display());
+    return check_packets(path);
+}
+
+#[cfg(feature = "ffmpeg")]
+fn check_packets(path: &Path) -> Result<(), PsError> {
+    let mut input = ffmpeg::format::input(&path)
+        .map_err(|e| corrupt(format!("ffmpeg can't open the file: {}", e)))?;
+    if input.streams().count() == 0 {
+        return Err(corrupt("No streams in the container".to_string()));
+    }
+
+    // Reading packets without decoding them is enough to notice missing data.
+    let mut packets = 0;
+    let mut packet = ffmpeg::Packet::empty();
+    loop {
+        match packet.read(&mut input) {
+            Ok(_) => packets += 1,
+            Err(ffmpeg::Error::Eof) => break,
+            Err(e) => {
+                return Err(corrupt(format!(
+                    "Couldn't read packet {} of the video: {}",
+                    packets, e
+                )))
+            }
+        }
+    }
+
+    info!("Read {} packets from {}", packets, path.display());
+    return Ok(());
+}
+
+/// Without ffmpeg only the box structure is checked.
+#[cfg(not(feature = "ffmpeg"))]
+fn check_packets(_path: &Path) -> Result<(), PsError> {
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png_chunk(chunk_type: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(chunk_type);
+        chunk.extend_from_slice(data);
+        chunk.extend_from_slice(&crc32fast::hash(&chunk[4..]).to_be_bytes());
+        return chunk;
+    }
+
+    #[test]
+    fn test_check_jpeg() {
+        let good = std::fs::read("./test-assets/jpg/Canon_40D.jpg").unwrap();
+        assert!(check_jpeg(&good).is_ok());
+        let err = check_jpeg(&good[..good.len() / 2]).err().unwrap();
+        assert_eq!(err.kind(), &PsErrorKind::Corrupt);
+
+        let corrupted = std::fs::read("./test-assets/jpg/corrupted.jpg").unwrap();
+        assert!(check_jpeg(&corrupted).is_err());
+    }
+
+    #[test]
+    fn test_check_png() {
+        let mut png = PNG_SIGNATURE.to_vec();
+        png.extend(png_chunk(b"IHDR", &[0, 0, 0, 1, 0, 0, 0, 1, 8, 0, 0, 0, 0]));
+        png.extend(png_chunk(
+            b"IDAT",
+            &[0x78, 0x9c, 0x63, 0x60, 0, 0, 0, 2, 0, 1],
+        ));
+        png.extend(png_chunk(b"IEND", &[]));
+        assert!(check_png(&png).is_ok());
+
+        assert!(check_png(&png[..png.len() - 12]).is_err());
+        let mut damaged = png.clone();
+        damaged[40] ^= 0xFF;
+        assert!(check_png(&damaged).is_err());
+    }
+
+    #[test]
+    fn test_check_file_by_name() -> Result<(), PsError> {
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("upload.tmp");
+        std::fs::write(&path, "not a picture")?;
+
+        // What the content should be is told by the original name.
+        assert!(check_file(&path, "IMG_0001.JPG").is_err());
+        assert!(check_file(&path, "notes.txt").is_ok());
+        assert!(check_file(Path::new("./test-assets/jpg/Canon_40D.jpg"), "a.jpg").is_ok());
+        return Ok(());
+    }
+}
@@ -0,0 +1,232 @@
+use crate::extract::Extracted;
+use crate::pserror::error::{PsError, PsErrorKind};
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+use log::{info, warn};
+use std::path::Path;
+
+/// Photoshop image resource holding IPTC-IIM.
+const IPTC_RESOURCE: u16 = 0x0404;
+
+/// Datasets of the application record (2) which are read, and the coded character set (1:90).
+const CHARSET: (u8, u8) = (1, 90);
+const KEYWORDS: (u8, u8) = (2, 25);
+const DATE_CREATED: (u8, u8) = (2, 55);
+const TIME_CREATED: (u8, u8) = (2, 60);
+const DIGITAL_CREATION_DATE: (u8, u8) = (2, 62);
+const DIGITAL_CREATION_TIME: (u8, u8) = (2, 63);
+const BYLINE: (u8, u8) = (2, 80);
+const CAPTION: (u8, u8) = (2, 120);
+
+fn format_error(msg: String) -> PsError {
+    return PsError::new(PsErrorKind::FormatError, msg);
+}
+
+/// Payload of the first APP13 segment of a JPEG which holds Photoshop image resources.
+fn find_photoshop_resources(data: &[u8]) -> Option<&[u8]> {
+    if !data.starts_with(b"\xff\xd8") {
+        return None;
+    }
+    let mut pos = 2;
+    // Segments up to the start of scan.
+    while pos + 4 <= data.len() && data[pos] == 0xFF {
+        let marker = data[pos + 1];
+        let length = (data[pos + 2] as usize) << 8 | data[pos + 3] as usize;
+        if marker == 0xDA {
+            return None;
+        }
+        let segment = data.get(pos + 4..pos + 2 + length)?;
+        if marker == 0xED && segment.starts_with(b"Photoshop 3.0\0") {
+            return Some(&segment[14..]);
+        }
+        pos += 2 + length;
+    }
+    return None;
+}
+
+/// Data of the image resource `id`. Each resource is `8BIM`, its id, a Pascal string name and the
+/// size of its data, with name and data padded to an even length.
+fn find_resource(resources: &[u8], id: u16) -> Option<&[u8]> {
+    let mut pos = 0;
+    while resources.get(pos..pos + 4)? == b"8BIM" {
+        let b = resources.get(pos + 4..pos + 6)?;
+        let resource_id = u16::from_be_bytes([b[0], b[1]]);
+        let name_len = *resources.get(pos + 6)? as usize;
+        pos += 6 + (name_len + 2) / 2 * 2;
+        let b = resources.get(pos..pos + 4)?;
+        let size = u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize;
+        let data = resources.get(pos + 4..pos + 4 + size)?;
+        if resource_id == id {
+            return Some(data);
+        }
+        pos += 4 + size + size % 2;
+    }
+    return None;
+}
+
+/// IIM datasets as record, dataset number and value. Each is tagged 0x1C and has a two-byte length;
+/// extended lengths are only used for binary data, so reading stops there.
+fn datasets(iim: &[u8]) -> Vec<((u8, u8), &[u8])> {
+    let mut result = Vec::new();
+    let mut pos = 0;
+    while pos + 5 <= iim.len() && iim[pos] == 0x1C {
+        let len = (iim[pos + 3] as usize) << 8 | iim[pos + 4] as usize;
+        if len & 0x8000 != 0 {
+            break;
+        }
+        let value = match iim.get(pos + 5..pos + 5 + len) {
+            Some(value) => value,
+            None => break,
+        };
+        result.push(((iim[pos + 1], iim[pos + 2]), value));
+        pos += 5 + len;
+    }
+    return result;
+}
+
+/// Text of a dataset, UTF-8 if 1:90 says so or it happens to be valid, otherwise Latin-1 as
+/// older software writes it.
+fn text(value: &[u8], utf8: bool) -> String {
+    let text = match std::str::from_utf8(value) {
+        Ok(text) => text.to_string(),
+        Err(_) if utf8 => String::from_utf8_lossy(value).to_string(),
+        Err(_) => value.iter().map(|&b| b as char).collect(),
+    };
+    return text
+        .trim_matches(|c: char| c.is_whitespace() || c == '\0')
+        .to_string();
+}
+
+/// Parses an IIM date, `CCYYMMDD`, and time, `HHMMSS` with a time zone which is dropped. A missing
+/// time is midnight.
+pub fn parse_iim_date(date: &str, time: Option<&str>) -> Option<NaiveDateTime> {
+    let date = NaiveDate::parse_from_str(date, "%Y%m%d").ok()?;
+    let time = match time {
+        Some(time) => NaiveTime::parse_from_str(time.get(..6)?, "%H%M%S").ok()?,
+        None => NaiveTime::from_hms(0, 0, 0),
+    };
+    return Some(date.and_time(time));
+}
+
+/// Reads the IPTC-IIM date, caption, keywords and byline of a JPEG, as scanning and newsroom
+/// software writes them into an APP13 segment. The date the photo was taken comes before the date
+/// it was digitised.
+pub fn read_iptc(path: &Path) -> Result<Extracted, PsError> {
+    let data = std::fs::read(path)?;
+    let iim = find_photoshop_resources(&data)
+        .and_then(|resources| find_resource(resources, IPTC_RESOURCE))
+        .ok_or_else(|| format_error(format!("No IPTC in {}", path.display())))?;
+    let datasets = datasets(iim);
+    // ESC % G selects UTF-8.
+    let utf8 = datasets
+        .iter()
+        .any(|(tag, value)| *tag == CHARSET && value == b"\x1b%G");
+    let get = |tag| {
+        datasets
+            .iter()
+            .find(|(t, _)| *t == tag)
+            .map(|(_, value)| text(value, utf8))
+            .filter(|value| !value.is_empty())
+    };
+
+    let mut extracted = Extracted::default();
+    extracted.metadata.caption = get(CAPTION);
+    extracted.metadata.byline = get(BYLINE);
+    extracted.metadata.keywords = datasets
+        .iter()
+        .filter(|(tag, _)| *tag == KEYWORDS)
+        .map(|(_, value)| text(value, utf8))
+        .filter(|keyword| !keyword.is_empty())
+        .collect();
+
+    for (source, date, time) in &[
+        ("IPTC DateCreated", DATE_CREATED, TIME_CREATED),
+        (
+            "IPTC DigitalCreationDate",
+            DIGITAL_CREATION_DATE,
+            DIGITAL_CREATION_TIME,
+        ),
+    ] {
+        if let Some(day) = get(*date) {
+            let time = get(*time);
+            match parse_iim_date(&day, time.as_deref()) {
+                Some(date) => extracted.dates.push((source.to_string(), date)),
+                None => warn!("Couldn't parse {} {} {:?} in {:?}", source, day, time, path),
+            }
+        }
+    }
+    info!("IPTC of {:?}: {:?}", path, extracted);
+    return Ok(extracted);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dataset(record: u8, number: u8, value: &[u8]) -> Vec<u8> {
+        let mut data = vec![0x1C, record, number];
+        data.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        data.extend_from_slice(value);
+        return data;
+    }
+
+    /// A JPEG with nothing but an APP13 segment holding `iim`, after an unrelated resource.
+    fn jpeg_with_iptc(iim: &[u8]) -> Vec<u8> {
+        let mut resources = b"Photoshop 3.0\0".to_vec();
+        // Resolution info, with an empty name padded to two bytes and odd-sized data.
+        resources.extend_from_slice(b"8BIM\x03\xed\0\0\0\0\0\x03abc\0");
+        resources.extend_from_slice(b"8BIM\x04\x04\0\0");
+        resources.extend_from_slice(&(iim.len() as u32).to_be_bytes());
+        resources.extend_from_slice(iim);
+
+        let mut data = b"\xff\xd8\xff\xed".to_vec();
+        data.extend_from_slice(&((resources.len() + 2) as u16).to_be_bytes());
+        data.extend(resources);
+        data.extend_from_slice(b"\xff\xd9");
+        return data;
+    }
+
+    #[test]
+    fn test_read_iptc() -> Result<(), PsError> {
+        let mut iim = dataset(1, 90, b"\x1b%G");
+        iim.extend(dataset(2, 55, b"19870214"));
+        iim.extend(dataset(2, 60, b"143005+0100"));
+        iim.extend(dataset(2, 62, b"20050301"));
+        iim.extend(dataset(2, 25, b"archive"));
+        iim.extend(dataset(2, 25, "Zürich".as_bytes()));
+        iim.extend(dataset(2, 80, b"J. Smith"));
+        iim.extend(dataset(2, 120, b"Opening of the new station "));
+
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("scan.jpg");
+        std::fs::write(&path, jpeg_with_iptc(&iim))?;
+        let extracted = read_iptc(&path)?;
+        assert_eq!(
+            extracted.dates,
+            vec![
+                (
+                    "IPTC DateCreated".to_string(),
+                    NaiveDate::from_ymd(1987, 2, 14).and_hms(14, 30, 5)
+                ),
+                (
+                    "IPTC DigitalCreationDate".to_string(),
+                    NaiveDate::from_ymd(2005, 3, 1).and_hms(0, 0, 0)
+                ),
+            ]
+        );
+        let metadata = extracted.metadata;
+        assert_eq!(metadata.keywords, vec!["archive", "Zürich"]);
+        assert_eq!(metadata.byline.as_deref(), Some("J. Smith"));
+        assert_eq!(
+            metadata.caption.as_deref(),
+            Some("Opening of the new station")
+        );
+
+        // Latin-1, as written without a character set.
+        std::fs::write(&path, jpeg_with_iptc(&dataset(2, 80, b"Jos\xe9")))?;
+        assert_eq!(read_iptc(&path)?.metadata.byline.as_deref(), Some("José"));
+
+        std::fs::write(&path, b"\xff\xd8\xff\xd9")?;
+        assert!(read_iptc(&path).is_err());
+        return Ok(());
+    }
+}
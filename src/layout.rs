@@ -0,0 +1,280 @@
+use crate::naming::camera_code;
+use crate::photo::{MediaKind, Photo};
+use crate::pserror::error::{PsError, PsErrorKind};
+use chrono::Datelike;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+/// Library convention, e.g. `2021/03/06`.
+pub const DEFAULT_LAYOUT: &str = "{year}/{month}/{day}";
+
+const PLACEHOLDERS: [&str; 4] = ["{year}", "{month}", "{day}", "{camera}"];
+
+/// Extensions of camera RAW files, which are sorted like other photos but may go elsewhere. Only
+/// RAWs built on TIFF, whose EXIF can be read, are sorted.
+pub const RAW_EXTENSIONS: [&str; 6] = ["cr2", "nef", "arw", "dng", "pef", "srw"];
+
+/// Kinds of files which can have a destination and layout of their own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Category {
+    Photos,
+    Videos,
+    Raw,
+    Audio,
+    Screenshots,
+}
+
+impl Category {
+    pub const ALL: [Category; 5] = [
+        Category::Photos,
+        Category::Videos,
+        Category::Raw,
+        Category::Audio,
+        Category::Screenshots,
+    ];
+
+    /// Name on the command line and in the report.
+    pub fn name(&self) -> &'static str {
+        return match self {
+            Category::Photos => "photos",
+            Category::Videos => "videos",
+            Category::Raw => "raw",
+            Category::Audio => "audio",
+            Category::Screenshots => "screenshots",
+        };
+    }
+
+    pub fn parse(name: &str) -> Result<Category, PsError> {
+        return match Category::ALL.iter().find(|c| c.name() == name) {
+            Some(category) => Ok(*category),
+            None => Err(PsError::new(
+                PsErrorKind::Unsupported,
+                format!(
+                    "Unknown kind of file {}, expected one of: {}",
+                    name,
+                    Category::ALL
+                        .iter()
+                        .map(|c| c.name())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            )),
+        };
+    }
+
+    /// Category of a file of `kind` originally called `name`. Screenshots are told by the names
+    /// phones and desktops give them, e.g. `Screenshot_20210306-164713.png` or
+    /// `Screen Shot 2019-07-14 at 18.30.05.png`.
+    pub fn of(kind: Option<MediaKind>, name: &OsStr) -> Category {
+        let name = name.to_string_lossy().to_lowercase();
+        let extension = Path::new(&name)
+            .extension()
+            .map(|e| e.to_string_lossy().to_string())
+            .unwrap_or_default();
+        return match kind {
+            Some(MediaKind::Video) => Category::Videos,
+            Some(MediaKind::Audio) => Category::Audio,
+            _ if RAW_EXTENSIONS.contains(&extension.as_str()) => Category::Raw,
+            _ if ["screenshot", "screen shot", "screen_shot"]
+                .iter()
+                .any(|s| name.contains(s)) =>
+            {
+                Category::Screenshots
+            }
+            _ => Category::Photos,
+        };
+    }
+}
+
+/// Where each category of files goes and how the directories under it are laid out. Layouts are
+/// templates with these placeholders, each `/` starting a directory:
+///
+/// * `{year}`, `{month}`, `{day}`: capture date, `2021`, `03` and `06`
+/// * `{camera}`: camera model (or make) as in file names, if known
+///
+/// Directories which would be empty, e.g. `{camera}` of a file without one, are left out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Destinations {
+    root: PathBuf,
+    /// Destinations of categories which don't go into `root`, relative to it unless absolute.
+    dirs: BTreeMap<Category, String>,
+    layout: String,
+    layouts: BTreeMap<Category, String>,
+}
+
+impl Destinations {
+    /// Everything in `root`, laid out by day.
+    pub fn new<P: Into<PathBuf>>(root: P) -> Destinations {
+        return Destinations {
+            root: root.into(),
+            dirs: BTreeMap::new(),
+            layout: DEFAULT_LAYOUT.to_string(),
+            layouts: BTreeMap::new(),
+        };
+    }
+
+    pub fn set_dir(&mut self, category: Category, dir: &str) {
+        self.dirs.insert(category, dir.to_string());
+    }
+
+    /// Sets the layout of `category`, or of all categories without one of their own if None.
+    /// Fails for unknown placeholders and for `..`, which would lead out of the destination.
+    pub fn set_layout(&mut self, category: Option<Category>, layout: &str) -> Result<(), PsError> {
+        check_layout(layout)?;
+        match category {
+            Some(category) => {
+                self.layouts.insert(category, layout.to_string());
+            }
+            None => self.layout = layout.to_string(),
+        }
+        return Ok(());
+    }
+
+    /// The library root and the directories of all categories, each once.
+    pub fn roots(&self) -> Vec<PathBuf> {
+        let mut roots = vec![self.root.clone()];
+        for category in Category::ALL.iter() {
+            let root = self.root(*category);
+            if !roots.contains(&root) {
+                roots.push(root);
+            }
+        }
+        return roots;
+    }
+
+    /// Directory files of `category` are sorted into.
+    pub fn root(&self, category: Category) -> PathBuf {
+        return match self.dirs.get(&category) {
+            // An absolute directory replaces the root.
+            Some(dir) => self.root.join(dir),
+            None => self.root.clone(),
+        };
+    }
+
+    /// Directory `photo` goes into, which must have a date by now.
+    pub fn dir_for(&self, category: Category, photo: &Photo) -> PathBuf {
+        let layout = self.layouts.get(&category).unwrap_or(&self.layout);
+        let date = photo.date().unwrap();
+        let rendered = layout
+            .replace("{year}", &date.year().to_string())
+            .replace("{month}", &format!("{:02}", date.month()))
+            .replace("{day}", &format!("{:02}", date.day()))
+            .replace("{camera}", &camera_code(photo.camera()));
+        let mut dir = self.root(category);
+        for part in rendered.split('/').filter(|part| !part.is_empty()) {
+            dir.push(part);
+        }
+        return dir;
+    }
+}
+
+fn check_layout(layout: &str) -> Result<(), PsError> {
+    let invalid = |reason: String| {
+        PsError::new(
+            PsErrorKind::Unsupported,
+            format!("Invalid layout {}: {}", layout, reason),
+        )
+    };
+    let mut rest = layout;
+    while let Some(start) = rest.find('{') {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end + 1,
+            None => rest.len(),
+        };
+        let placeholder = &rest[start..end];
+        if !PLACEHOLDERS.contains(&placeholder) {
+            return Err(invalid(format!(
+                "unknown placeholder {}, expected one of: {}",
+                placeholder,
+                PLACEHOLDERS.join(", ")
+            )));
+        }
+        rest = &rest[end..];
+    }
+    if layout.split(&['/', '\\'][..]).any(|part| part == "..") {
+        return Err(invalid("must stay inside the destination".to_string()));
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::photo::PhotoBuilder;
+    use chrono::NaiveDate;
+
+    #[test]
+    fn test_category_of() {
+        let image = Some(MediaKind::Image);
+        assert_eq!(
+            Category::of(image, OsStr::new("IMG_0001.JPG")),
+            Category::Photos
+        );
+        assert_eq!(
+            Category::of(image, OsStr::new("DSC_0001.NEF")),
+            Category::Raw
+        );
+        assert_eq!(
+            Category::of(image, OsStr::new("Screen Shot 2019-07-14 at 18.30.05.png")),
+            Category::Screenshots
+        );
+        assert_eq!(
+            Category::of(Some(MediaKind::Video), OsStr::new("Screenshot.mp4")),
+            Category::Videos
+        );
+        assert_eq!(
+            Category::of(Some(MediaKind::Audio), OsStr::new("memo.m4a")),
+            Category::Audio
+        );
+        assert!(Category::parse("pictures").is_err());
+    }
+
+    #[test]
+    fn test_dir_for() -> Result<(), PsError> {
+        let photo = PhotoBuilder::new()
+            .with_date(NaiveDate::from_ymd(2021, 3, 6).and_hms(16, 47, 13))
+            .with_path("IMG_0001.JPG".to_string())
+            .build();
+        let mut destinations = Destinations::new("LIB");
+        destinations.set_dir(Category::parse("videos")?, "/mnt/videos");
+        destinations.set_dir(Category::Screenshots, "screenshots");
+        assert_eq!(
+            destinations.roots(),
+            vec![
+                PathBuf::from("LIB"),
+                PathBuf::from("/mnt/videos"),
+                PathBuf::from("LIB/screenshots"),
+            ]
+        );
+        destinations.set_layout(None, "{year}/{year}-{month}")?;
+        destinations.set_layout(Some(Category::Raw), "{year}/{camera}/{month}{day}")?;
+
+        assert_eq!(
+            destinations.dir_for(Category::Photos, &photo),
+            Path::new("LIB/2021/2021-03")
+        );
+        assert_eq!(
+            destinations.dir_for(Category::Videos, &photo),
+            Path::new("/mnt/videos/2021/2021-03")
+        );
+        assert_eq!(
+            destinations.dir_for(Category::Screenshots, &photo),
+            Path::new("LIB/screenshots/2021/2021-03")
+        );
+        // No camera, so no directory for it.
+        assert_eq!(
+            destinations.dir_for(Category::Raw, &photo),
+            Path::new("LIB/2021/0306")
+        );
+
+        for layout in &["{year}/{week}", "{year}/{month", "{year}/../{month}"] {
+            assert!(destinations.set_layout(None, layout).is_err(), "{}", layout);
+        }
+        assert_eq!(
+            destinations.dir_for(Category::Photos, &photo),
+            Path::new("LIB/2021/2021-03")
+        );
+        return Ok(());
+    }
+}
@@ -0,0 +1,189 @@
+use crate::layout::Destinations;
+use crate::pserror::error::{PsError, PsErrorKind};
+use chrono::Local;
+use fs2::FileExt;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Name of the lock file, kept in the root of the destination library.
+pub const LOCK_FILE_NAME: &str = ".photosort.lock";
+
+/// Who is sorting into the library, written into the lock file for other runs to report.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct LockOwner {
+    pub host: String,
+    pub pid: u32,
+    pub started: String,
+}
+
+impl LockOwner {
+    fn current() -> LockOwner {
+        return LockOwner {
+            host: hostname::get()
+                .map(|h| h.to_string_lossy().to_string())
+                .unwrap_or_else(|_| "unknown".to_string()),
+            pid: std::process::id(),
+            started: Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+        };
+    }
+}
+
+impl std::fmt::Display for LockOwner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "host {}, pid {}, started {}",
+            self.host, self.pid, self.started
+        )
+    }
+}
+
+/// Exclusive advisory lock on a destination library, held until dropped.
+///
+/// The lock itself is an OS file lock, so it goes away together with the process holding it, even
+/// if that process is killed. The lock file is never deleted (which would let two runs lock two
+/// different files); it's truncated on release instead, so a non-empty file which nobody holds
+/// is left over from a run which died, and is safe to take over.
+pub struct LibraryLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl LibraryLock {
+    pub fn acquire<P: AsRef<Path>>(destination: P) -> Result<LibraryLock, PsError> {
+        let destination = destination.as_ref();
+        std::fs::create_dir_all(destination)?;
+        let path = destination.join(LOCK_FILE_NAME);
+        // Not truncated: the previous owner is still needed to report who holds the lock.
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        if let Err(err) = file.try_lock_exclusive() {
+            if err.kind() != fs2::lock_contended_error().kind() {
+                return Err(err.into());
+            }
+            let holder = match read_owner(&mut file) {
+                Some(owner) => owner.to_string(),
+                None => "unknown owner".to_string(),
+            };
+            return Err(PsError::new(
+                PsErrorKind::Locked,
+                format!(
+                    "{} is being sorted by another run ({})",
+                    destination.display(),
+                    holder
+                ),
+            ));
+        }
+
+        if let Some(stale) = read_owner(&mut file) {
+            warn!(
+                "Taking over stale lock on {} ({})",
+                destination.display(),
+                stale
+            );
+        }
+
+        let owner = LockOwner::current();
+        let json = serde_json::to_string(&owner)
+            .map_err(|e| PsError::new(PsErrorKind::FormatError, e.to_string()))?;
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(json.as_bytes())?;
+        file.sync_all()?;
+
+        info!("Locked {:?} ({})", path, owner);
+        return Ok(LibraryLock { file, path });
+    }
+
+    /// Locks the library and every directory a kind of file is sorted into, so that a run sharing
+    /// any of them, e.g. videos on another volume, is refused as well.
+    pub fn acquire_all(destinations: &Destinations) -> Result<Vec<LibraryLock>, PsError> {
+        return destinations
+            .roots()
+            .iter()
+            .map(LibraryLock::acquire)
+            .collect();
+    }
+}
+
+impl Drop for LibraryLock {
+    fn drop(&mut self) {
+        if let Err(err) = self.file.set_len(0) {
+            warn!("Couldn't clear lock file {:?}: {}", self.path, err);
+        }
+        let _ = self.file.unlock();
+    }
+}
+
+fn read_owner(file: &mut File) -> Option<LockOwner> {
+    let mut contents = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut contents).ok()?;
+    return serde_json::from_str(&contents).ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_second_lock_is_refused() -> Result<(), PsError> {
+        let temp_dir = tempfile::tempdir()?;
+        let destination = temp_dir.path().to_str().unwrap();
+
+        let lock = LibraryLock::acquire(destination)?;
+        let err = LibraryLock::acquire(destination).err().unwrap();
+        assert!(err
+            .to_string()
+            .contains(&format!("pid {}", std::process::id())));
+
+        drop(lock);
+        assert!(LibraryLock::acquire(destination).is_ok());
+        return Ok(());
+    }
+
+    #[test]
+    fn test_every_destination_is_locked() -> Result<(), PsError> {
+        let temp_dir = tempfile::tempdir()?;
+        let videos = temp_dir.path().join("videos");
+        let mut destinations = Destinations::new(temp_dir.path().join("library"));
+        destinations.set_dir(crate::layout::Category::Videos, videos.to_str().unwrap());
+
+        let locks = LibraryLock::acquire_all(&destinations)?;
+        assert_eq!(locks.len(), 2);
+        assert!(LibraryLock::acquire(&videos).is_err());
+        drop(locks);
+
+        let other = LibraryLock::acquire(&videos)?;
+        assert!(LibraryLock::acquire_all(&destinations).is_err());
+        drop(other);
+        return Ok(());
+    }
+
+    #[test]
+    fn test_stale_lock_is_taken_over() -> Result<(), PsError> {
+        let temp_dir = tempfile::tempdir()?;
+        let destination = temp_dir.path().to_str().unwrap();
+        let stale = LockOwner {
+            host: "nas".to_string(),
+            pid: 1,
+            started: "2021-03-06 16:47:13".to_string(),
+        };
+        std::fs::write(
+            temp_dir.path().join(LOCK_FILE_NAME),
+            serde_json::to_string(&stale).unwrap(),
+        )?;
+
+        let mut lock = LibraryLock::acquire(destination)?;
+        assert_eq!(read_owner(&mut lock.file).unwrap().pid, std::process::id());
+        return Ok(());
+    }
+}
@@ -1,21 +1,46 @@
+#[cfg(feature = "ffmpeg")]
 extern crate ffmpeg_next as ffmpeg;
 
-use std::path::Path;
-
-use chrono::Datelike;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use indicatif::{ProgressBar, ProgressStyle};
 use log::LevelFilter;
 use log::{info, warn};
 
 use crate::pserror::error::*;
-use config::configurator::{get_config, Config};
+use config::configurator::{get_config, Command, Config, EstimateOptions};
+use index::Index;
+use layout::{Category, Destinations};
+use naming::Namer;
 use photo::Photo;
+use report::{Outcome, RunReport};
 
+mod audio;
+mod avchd;
+mod avi;
+mod bmff;
+mod clock;
 mod config;
 mod discovery;
+mod extract;
+mod fallback;
+mod imagetext;
+mod index;
+mod integrity;
+mod iptc;
+mod layout;
+mod lock;
+mod matroska;
+mod naming;
 mod photo;
 mod pserror;
+mod report;
+mod resume;
+mod watch;
+mod writeback;
+mod xmp;
 mod zipfiles;
 
 mod error_messages {
@@ -25,7 +50,7 @@ mod error_messages {
 #[derive(PartialEq, Eq, Debug)]
 enum Action {
     HELP,
-    CONVERT(Config),
+    CONVERT(Box<Config>),
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -36,111 +61,530 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
 
     let config = config.unwrap();
+    enable_logging(&config)?;
     info!("Starting conversion for config {:?}", config);
 
-    ffmpeg::init().unwrap();
-    convert_files(&config);
+    #[cfg(feature = "ffmpeg")]
+    ffmpeg::init()?;
+
+    // Queries only read the index and estimates only read the source, everything else writes
+    // into the library, unless it's a dry run. Sorting also writes into the directories of kinds
+    // of files kept elsewhere.
+    let locks = match &config.command {
+        Command::Query(_) | Command::ClockEstimate(_) => Vec::new(),
+        Command::Sort | Command::Watch(_) if config.dry_run => Vec::new(),
+        Command::Sort | Command::Watch(_) => lock::LibraryLock::acquire_all(&config.destinations)?,
+        _ => vec![lock::LibraryLock::acquire(&config.destination)?],
+    };
+
+    let report = match &config.command {
+        Command::Sort => convert_files(&config)?,
+        Command::Watch(options) => watch::watch(&config, options, open_index(&config))?,
+        Command::ClockEstimate(options) => {
+            run_clock_estimate(&config, options)?;
+            return Ok(());
+        }
+        _ => {
+            run_index_command(&config)?;
+            return Ok(());
+        }
+    };
+
+    print!("{}", report.summary());
+    if let Some(path) = &config.report {
+        if let Err(err) = report.write_json(path) {
+            eprintln!("Couldn't write report to {}: {}", path, err);
+        }
+    }
+
+    let exit_code = report.exit_code();
+    if exit_code != 0 {
+        // Exiting skips destructors, so the library has to be unlocked by hand.
+        drop(locks);
+        std::process::exit(exit_code);
+    }
+    return Ok(());
+}
 
+fn run_index_command(config: &Config) -> Result<(), PsError> {
+    let index = Index::open(&config.destination)?;
+    match &config.command {
+        Command::IndexRebuild => {
+            let indexed = index.rebuild(&config.destinations, &config.extractors)?;
+            println!(
+                "Indexed {} files in {}",
+                indexed,
+                config.destination.display()
+            );
+        }
+        Command::Query(query) => {
+            let entries = index.query(query)?;
+            print!("{}", index::format_entries(&index, &entries, query.format));
+        }
+        Command::Sort | Command::Watch(_) | Command::ClockEstimate(_) => {}
+    }
     return Ok(());
 }
 
-fn convert_files(config: &Config) {
-    if config.logfile.is_some() {
-        let logfile = config.logfile.as_ref().unwrap();
-        match simple_logging::log_to_file(logfile, LevelFilter::Info) {
-            Ok(_) => {}
-            Err(e) => {
-                eprintln!("Couldn't enable logging: {:?}", e);
-                std::process::exit(1);
+/// Prints proposed clock rules as JSON, ready for --clock-offsets, and how they were found.
+fn run_clock_estimate(config: &Config, options: &EstimateOptions) -> Result<(), PsError> {
+    let files = discovery::discovery::list_all_files(&config.source)?;
+    let (photos, _) =
+        discovery::discovery::process_raw_files(&files, &config.extractors, config.min_date);
+    let estimates = clock::estimate_offsets(
+        &photos,
+        options.reference.as_deref(),
+        chrono::Duration::seconds(options.window_secs),
+        chrono::Duration::hours(options.max_offset_hours),
+    )?;
+
+    for estimate in &estimates {
+        let name = clock::camera_name(&estimate.camera);
+        match estimate.offset {
+            _ if estimate.reference => {
+                eprintln!("{}: reference, {} photos", name, estimate.photos)
             }
+            Some(offset) => eprintln!(
+                "{}: {} photos, offset {} from {} matching shots",
+                name,
+                estimate.photos,
+                clock::format_offset(offset),
+                estimate.matches
+            ),
+            None => eprintln!(
+                "{}: {} photos, no shots in common with the reference",
+                name, estimate.photos
+            ),
         }
     }
 
-    if config.source.ends_with(".zip") {
-        zipfiles::process_zip_file(&config.source, &config);
+    let rules: Vec<&clock::ClockRuleSpec> =
+        estimates.iter().filter_map(|e| e.rule.as_ref()).collect();
+    let json = serde_json::to_string_pretty(&rules)
+        .map_err(|e| PsError::new(PsErrorKind::FormatError, e.to_string()))?;
+    println!("{}", json);
+    return Ok(());
+}
+
+fn enable_logging(config: &Config) -> Result<(), PsError> {
+    if let Some(logfile) = &config.logfile {
+        if let Err(e) = simple_logging::log_to_file(logfile, LevelFilter::Info) {
+            eprintln!("Couldn't enable logging: {:?}", e);
+            return Err(e.into());
+        }
+    }
+    return Ok(());
+}
+
+fn open_index(config: &Config) -> Option<Index> {
+    // Nothing is written in dry-run mode, so there is nothing to index either.
+    if config.dry_run {
+        return None;
+    }
+    return match Index::open(&config.destination) {
+        Ok(index) => Some(index),
+        Err(err) => {
+            warn!(
+                "Couldn't open library index, continuing without it: {}",
+                err
+            );
+            None
+        }
+    };
+}
+
+/// Sorts the source into the library. Fails only if an interrupted run can't be resumed, other
+/// errors are counted in the report.
+fn convert_files(config: &Config) -> Result<RunReport, PsError> {
+    let mut report = RunReport::new();
+    let index = open_index(config);
+    let shutdown = match resume::register_shutdown() {
+        Ok(shutdown) => shutdown,
+        Err(err) => {
+            warn!("Couldn't install signal handlers: {}", err);
+            std::sync::Arc::new(AtomicBool::new(false))
+        }
+    };
+
+    let journal = resume::Journal::new(&config.destination, &config.source);
+    let leftovers = if config.resume {
+        match journal.load_remaining() {
+            Ok(leftovers) => leftovers,
+            Err(err) => {
+                eprintln!("Cannot resume: {}", err);
+                return Err(err);
+            }
+        }
+    } else {
+        None
+    };
+
+    let remaining = if config.source.extension() == Some(OsStr::new("zip")) {
+        // Zip entry names are always UTF-8.
+        let only: Option<Vec<String>> = leftovers.map(|leftovers| {
+            leftovers
+                .iter()
+                .map(|p| p.to_string_lossy().to_string())
+                .collect()
+        });
+        match zipfiles::process_zip_file(
+            &config.source,
+            config,
+            index.as_ref(),
+            only.as_ref(),
+            &shutdown,
+            &mut report,
+        ) {
+            Ok((_, remaining)) => remaining.into_iter().map(PathBuf::from).collect(),
+            Err(err) => {
+                warn!(
+                    "Couldn't process zip file {}: {}",
+                    config.source.display(),
+                    err
+                );
+                report.record_failure(&config.source, &err);
+                Vec::new()
+            }
+        }
+    } else {
+        // Unsupported files are listed too, so that they are quarantined rather than left behind.
+        let file_list = match leftovers {
+            Some(leftovers) => Ok(leftovers),
+            None => discovery::discovery::list_all_files(&config.source).and_then(|mut files| {
+                files.extend(discovery::discovery::list_unsupported_files(
+                    &config.source,
+                )?);
+                Ok(files)
+            }),
+        };
+        match file_list {
+            Ok(file_list) => sort_files(config, &file_list, index.as_ref(), &shutdown, &mut report),
+            Err(err) => {
+                warn!(
+                    "Couldn't list files in {}: {}",
+                    config.source.display(),
+                    err
+                );
+                report.record_failure(&config.source, &err);
+                Vec::new()
+            }
+        }
+    };
+
+    report.remaining = remaining.len();
+    if config.dry_run {
+        return Ok(report);
+    }
+
+    let saved = if remaining.is_empty() {
+        journal.clear()
     } else {
-        let file_list = discovery::discovery::list_all_files(&config.source);
-        let mut photo_list = discovery::discovery::process_raw_files(&file_list);
-        info!("Produced a list of {} files", photo_list.len());
-        update_new_path(&config.destination, &mut photo_list);
-        info!("Updated a list of {} files", file_list.len());
-        let bar = ProgressBar::new(file_list.len() as u64);
-
-        bar.set_message("Moving/copying files ... ");
-        bar.set_style(
-            ProgressStyle::default_bar()
-                .template("[{elapsed_precise}] {bar:80.green/red} {pos:>7}/{len:7} {msg}")
-                .progress_chars("█░"),
+        eprintln!(
+            "Interrupted, {} files left. Run again with --resume to continue.",
+            remaining.len()
         );
-        for photo in photo_list {
-            bar.inc(1);
-            match move_photo(&photo, !config.copy, config.dry_run) {
-                Ok(_) => {
-                    info!(
-                        "Moved photo {} -> {}",
-                        photo.path().as_ref().unwrap(),
-                        photo.new_path().as_ref().unwrap()
+        journal.save_remaining(remaining)
+    };
+    if let Err(err) = saved {
+        warn!("Couldn't update resume state: {}", err);
+    }
+    return Ok(report);
+}
+
+/// Discovers dates of all files in the list and moves/copies them into the destination. Files
+/// which can't be sorted are set aside into the undated or quarantine directory.
+///
+/// Stops early once `shutdown` is set, returning the files which haven't been processed.
+fn sort_files(
+    config: &Config,
+    file_list: &Vec<PathBuf>,
+    index: Option<&Index>,
+    shutdown: &AtomicBool,
+    report: &mut RunReport,
+) -> Vec<PathBuf> {
+    let (mut photo_list, errors) =
+        discovery::discovery::process_raw_files(file_list, &config.extractors, config.min_date);
+    info!("Produced a list of {} files", photo_list.len());
+    if shutdown.load(Ordering::Relaxed) {
+        return file_list.clone();
+    }
+
+    for photo in photo_list.iter_mut() {
+        apply_clock_rules(config, photo, None, report);
+    }
+
+    // Files are named in this order, which mustn't depend on how the source was listed.
+    photo_list
+        .sort_by(|a, b| (a.date(), a.subsec(), a.path()).cmp(&(b.date(), b.subsec(), b.path())));
+    let mut namer = Namer::new(config.name_template.clone()).with_index(index);
+    update_new_path(&config.destinations, &mut photo_list, &mut namer);
+    info!("Updated a list of {} files", file_list.len());
+    let bar = ProgressBar::new(file_list.len() as u64);
+
+    bar.set_message("Moving/copying files ... ");
+    bar.set_style(
+        ProgressStyle::default_bar()
+            .template("[{elapsed_precise}] {bar:80.green/red} {pos:>7}/{len:7} {msg}")
+            .progress_chars("█░"),
+    );
+    for (i, photo) in photo_list.iter().enumerate() {
+        if shutdown.load(Ordering::Relaxed) {
+            bar.abandon_with_message("Interrupted");
+            return photo_list[i..]
+                .iter()
+                .filter_map(|p| p.path().clone())
+                .chain(
+                    errors
+                        .iter()
+                        .filter_map(|e| e.path().map(Path::to_path_buf)),
+                )
+                .collect();
+        }
+
+        bar.inc(1);
+        if let Some(path) = photo.path() {
+            report.add_rejected_dates(path, photo);
+        }
+        match move_photo(photo, index, !config.copy, config.dry_run) {
+            Ok(outcome) => {
+                let original_path = photo.path().as_ref().unwrap();
+                info!(
+                    "Moved photo {} -> {}: {:?}",
+                    original_path.display(),
+                    photo.new_path().as_ref().unwrap().display(),
+                    outcome
+                );
+                if outcome == Outcome::Moved || outcome == Outcome::Copied {
+                    let original_hash = write_back_date(config, photo, report);
+                    record_in_index(
+                        index,
+                        photo,
+                        &original_path.to_string_lossy(),
+                        original_hash,
                     );
                 }
-                Err(err) => {
-                    warn!("Failed to move photo {:?}: {}", photo.path(), err);
-                }
+                record_destination(config, photo, None, outcome, report);
+                report.record(outcome);
+            }
+            Err(err) => {
+                let original_path = photo.path().as_ref().unwrap();
+                let err = err.with_path(original_path).at_stage(Stage::Transfer);
+                warn!("Failed to move photo {}: {}", original_path.display(), err);
+                report.record_failure(original_path, &err);
             }
         }
-        bar.finish();
+    }
+    bar.finish();
+
+    for (i, err) in errors.iter().enumerate() {
+        if shutdown.load(Ordering::Relaxed) {
+            return errors[i..]
+                .iter()
+                .filter_map(|e| e.path().map(Path::to_path_buf))
+                .collect();
+        }
+        // Discovery errors always carry the path.
+        let path = err.path().unwrap();
+        let relative = match path.strip_prefix(&config.source) {
+            Ok(relative) if relative.file_name().is_some() => relative,
+            _ => Path::new(path.file_name().unwrap_or(path.as_os_str())),
+        };
+        match set_aside(config, path, relative, !config.copy, err, report) {
+            Ok(Some(outcome)) => report.record(outcome),
+            Ok(None) => report.record_failure(path, err),
+            Err(err) => {
+                let err = err.with_path(path).at_stage(Stage::Transfer);
+                warn!("Failed to set aside {}: {}", path.display(), err);
+                report.record_failure(path, &err);
+            }
+        }
+    }
+    return Vec::new();
+}
+
+/// Moves a file which couldn't be sorted because of `reason` into the undated or quarantine
+/// directory, keeping its path `relative` to the source so that files from different folders
+/// don't clash. Returns None, leaving the file where it was, if `reason` isn't about the file
+/// itself, e.g. it couldn't be read.
+fn set_aside(
+    config: &Config,
+    path: &Path,
+    relative: &Path,
+    move_file: bool,
+    reason: &PsError,
+    report: &mut RunReport,
+) -> Result<Option<Outcome>, PsError> {
+    let (dir, outcome) = match reason.kind() {
+        PsErrorKind::NoExif | PsErrorKind::NoDateField | PsErrorKind::FormatError => {
+            (&config.undated_dir, Outcome::Undated)
+        }
+        PsErrorKind::Corrupt | PsErrorKind::FileNotSupported | PsErrorKind::Unsupported => {
+            (&config.quarantine_dir, Outcome::Quarantined)
+        }
+        _ => return Ok(None),
+    };
+
+    let destination = config.destination.join(dir).join(relative);
+    let photo = photo::PhotoBuilder::new()
+        .with_os_path(path)
+        .with_new_path(destination.clone())
+        .build();
+    let result = move_photo(&photo, None, move_file, config.dry_run)?;
+    if result == Outcome::Duplicate {
+        return Ok(Some(result));
+    }
+
+    info!("Set aside {} -> {}", path.display(), destination.display());
+    report.add_set_aside(reason.path().unwrap_or(path), &destination, reason);
+    return Ok(Some(match result {
+        Outcome::Skipped => Outcome::Skipped,
+        _ => outcome,
+    }));
+}
+
+/// Corrects the photo's date if a clock rule matches it. `source` is what to call the photo in
+/// the report, if not its path.
+fn apply_clock_rules(
+    config: &Config,
+    photo: &mut Photo,
+    source: Option<&Path>,
+    report: &mut RunReport,
+) {
+    if let Some(correction) = clock::apply(&config.clock_rules, photo) {
+        let path = source
+            .or_else(|| photo.path().as_deref())
+            .unwrap_or(Path::new(""));
+        info!(
+            "Corrected date of {}: {} -> {} ({})",
+            path.display(),
+            correction.original,
+            correction.corrected,
+            correction.rule
+        );
+        report.add_clock_correction(path, &correction);
     }
 }
 
-fn update_new_path(dest_dir: &String, photos: &mut Vec<Photo>) {
+/// With `--write-dates`, writes the date of a sorted photo into the file (or its sidecar) when
+/// the file's own metadata doesn't already say so. Done before indexing, which hashes the file.
+///
+/// Returns the hash the file had before, if it has been changed, for the index to tell it's still
+/// the file which has been imported.
+fn write_back_date(config: &Config, photo: &Photo, report: &mut RunReport) -> Option<String> {
+    if !config.write_dates || !photo.date_is_fallback() {
+        return None;
+    }
+    let (new_path, date) = match (photo.new_path(), photo.date()) {
+        (Some(new_path), Some(date)) => (new_path, date),
+        _ => return None,
+    };
+    let original_hash = index::hash_file(new_path).ok();
+    return match writeback::write_date(new_path, date) {
+        Ok(written) => {
+            report.dates_written += 1;
+            match written {
+                writeback::Written::Exif => original_hash,
+                writeback::Written::Sidecar(_) => None,
+            }
+        }
+        Err(err) => {
+            warn!("Failed to write date into {}: {}", new_path.display(), err);
+            None
+        }
+    };
+}
+
+/// Counts a sorted file, or one which would be sorted in dry-run mode, towards the destination of
+/// its kind. Duplicates already in the library aren't counted.
+fn record_destination(
+    config: &Config,
+    photo: &Photo,
+    original_name: Option<&OsStr>,
+    outcome: Outcome,
+    report: &mut RunReport,
+) {
+    match outcome {
+        Outcome::Moved | Outcome::Copied => {}
+        Outcome::Skipped if config.dry_run => {}
+        _ => return,
+    }
+    let category = category_of(photo, original_name);
+    report.add_destination(category.name(), &config.destinations.root(category));
+}
+
+fn record_in_index(
+    index: Option<&Index>,
+    photo: &Photo,
+    source: &str,
+    original_hash: Option<String>,
+) {
+    if let Some(index) = index {
+        if let Err(err) = index.record_photo(photo, source, original_hash) {
+            warn!("Failed to index photo {:?}: {}", photo.new_path(), err);
+        }
+    }
+}
+
+fn update_new_path(destinations: &Destinations, photos: &mut Vec<Photo>, namer: &mut Namer) {
     for photo in photos {
-        update_photo_new_path(dest_dir, photo, Option::None)
+        update_photo_new_path(destinations, photo, Option::None, namer)
     }
 }
 
-fn update_photo_new_path(dest_dir: &String, photo: &mut Photo, original_name: Option<&str>) {
-    let existing_path = Path::new(photo.path().as_ref().unwrap());
+/// Kind of destination the photo, originally called `original_name` if not by its path, goes to.
+fn category_of(photo: &Photo, original_name: Option<&OsStr>) -> Category {
+    let name = original_name
+        .or_else(|| photo.path().as_ref().and_then(|path| path.file_name()))
+        .unwrap_or_default();
+    return Category::of(photo.metadata().kind, name);
+}
+
+/// Sets where the photo goes: the directory its kind of file and date are laid out into.
+fn update_photo_new_path(
+    destinations: &Destinations,
+    photo: &mut Photo,
+    original_name: Option<&OsStr>,
+    namer: &mut Namer,
+) {
+    let existing_path = photo.path().as_ref().unwrap();
     match existing_path.file_name() {
         None => {
             info!(
                 "Path doesn't appear to have a valid file name: {}",
-                photo
-                    .path()
-                    .as_ref()
-                    .unwrap_or(&"BAD_FILE_NAME".to_string())
+                existing_path.display()
             )
         }
         Some(file_name) => {
-            let new_name = match original_name {
-                None => file_name.to_str().unwrap(),
-                Some(original_name) => original_name,
-            };
+            let original_name = original_name.unwrap_or(file_name).to_os_string();
 
             // photo must have valid date at this point.
-            let date = photo.date().unwrap();
-            let path = format!(
-                "{}/{}/{:02}/{:02}/{}",
-                dest_dir,
-                date.year(),
-                date.month(),
-                date.day(),
-                new_name // should be safe (why?)
-            );
+            let category = category_of(photo, Some(&original_name));
+            let dir = destinations.dir_for(category, photo);
+            let path = namer.name(&dir, photo, &original_name);
 
             photo.set_new_path(path);
         }
     }
 }
 
-fn move_photo(photo: &Photo, move_file: bool, dry_run: bool) -> Result<(), PsError> {
+/// Moves or copies the photo to its `new_path`, reporting what has been done. A file already at
+/// `new_path` is a duplicate if it's the photo, as it is or as `index` says it has been imported.
+fn move_photo(
+    photo: &Photo,
+    index: Option<&Index>,
+    move_file: bool,
+    dry_run: bool,
+) -> Result<Outcome, PsError> {
     let new_path = photo.new_path().as_ref().unwrap();
 
-    let full_path = Path::new(new_path);
+    let full_path = new_path.as_path();
     let dir = match full_path.parent() {
         None => {
             return Err(PsError::new(
                 PsErrorKind::IoError,
-                format!("No parent directory for {}", new_path),
-            ));
+                format!("No parent directory for {}", new_path.display()),
+            )
+            .at_stage(Stage::Plan));
         }
         Some(dir) => dir,
     };
@@ -156,28 +600,120 @@ fn move_photo(photo: &Photo, move_file: bool, dry_run: bool) -> Result<(), PsErr
 
     if dry_run {
         info!("Dry-run, not really copying/moving {:?}", photo.path());
-        return Ok(());
+        return Ok(Outcome::Skipped);
     }
 
     // If photo doesn't have path() at this point, it's a fatal mistake.
     let original_path = photo.path().as_ref().unwrap();
 
-    if move_file {
-        match std::fs::rename(original_path, &new_path) {
-            Ok(_) => {}
-            Err(err) => {
-                info!("Failed to move file: {}", err);
-            }
+    if full_path.exists() {
+        if is_already_sorted(original_path, new_path, index)? {
+            info!(
+                "{} is already in the library as {}",
+                original_path.display(),
+                new_path.display()
+            );
+            return Ok(Outcome::Duplicate);
         }
+        return Err(PsError::new(
+            PsErrorKind::Conflict,
+            format!("A different file already exists at {}", new_path.display()),
+        ));
+    }
+
+    if let Err(err) = transfer_file(original_path, new_path, move_file) {
+        info!(
+            "Failed to {} {} -> {}: {}",
+            if move_file { "move" } else { "copy" },
+            original_path.display(),
+            new_path.display(),
+            err
+        );
+        return Err(err.into());
+    }
+    transfer_sidecars(original_path, new_path, move_file);
+    return Ok(if move_file {
+        Outcome::Moved
     } else {
-        match std::fs::copy(original_path, &new_path) {
-            Ok(_) => {}
-            Err(err) => {
-                info!("Failed to copy {} -> {}: {}", original_path, &new_path, err);
-            }
+        Outcome::Copied
+    });
+}
+
+/// Moves or copies a file to `new_path`. A move to another file system, where the file can't
+/// just be renamed, copies it and then removes the original.
+fn transfer_file(original_path: &Path, new_path: &Path, move_file: bool) -> std::io::Result<()> {
+    if !move_file {
+        return copy_file(original_path, new_path);
+    }
+    return match std::fs::rename(original_path, new_path) {
+        Err(err) if err.kind() == std::io::ErrorKind::CrossesDevices => {
+            move_across_devices(original_path, new_path)
+        }
+        result => result,
+    };
+}
+
+fn move_across_devices(original_path: &Path, new_path: &Path) -> std::io::Result<()> {
+    copy_file(original_path, new_path)?;
+    return std::fs::remove_file(original_path);
+}
+
+/// Copies aside and renames, so that an interrupted copy never leaves a truncated file behind.
+fn copy_file(original_path: &Path, new_path: &Path) -> std::io::Result<()> {
+    let mut partial_path = new_path.as_os_str().to_os_string();
+    partial_path.push(".partial");
+    let result = std::fs::copy(original_path, &partial_path)
+        .and_then(|_| std::fs::rename(&partial_path, new_path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&partial_path);
+    }
+    return result;
+}
+
+/// Moves or copies the XMP sidecars of a sorted file along with it, renamed after its new name.
+/// A sidecar which can't be transferred is left in the source; the file itself stays sorted.
+fn transfer_sidecars(original_path: &Path, new_path: &Path, move_file: bool) {
+    let sidecars = xmp::sidecar_paths(original_path)
+        .into_iter()
+        .zip(xmp::sidecar_paths(new_path));
+    for (sidecar, new_sidecar) in sidecars {
+        if !sidecar.is_file() {
+            continue;
+        }
+        if new_sidecar.exists() {
+            warn!(
+                "Not overwriting {} with sidecar {}",
+                new_sidecar.display(),
+                sidecar.display()
+            );
+            continue;
         }
+        match transfer_file(&sidecar, &new_sidecar, move_file) {
+            Ok(_) => info!("{} -> {}", sidecar.display(), new_sidecar.display()),
+            Err(err) => warn!("Failed to transfer sidecar {}: {}", sidecar.display(), err),
+        }
+    }
+}
+
+/// Whether the file at `path` is `source` sorted by an earlier run: the same content, or imported
+/// from the same content according to `index` and changed by `--write-dates` since.
+fn is_already_sorted(source: &Path, path: &Path, index: Option<&Index>) -> Result<bool, PsError> {
+    if is_same_file_content(source, path)? {
+        return Ok(true);
+    }
+    return match index {
+        Some(index) => index.is_imported_from(path, source),
+        None => Ok(false),
+    };
+}
+
+fn is_same_file_content(first: &Path, second: &Path) -> Result<bool, PsError> {
+    let mut first = std::fs::File::open(first)?;
+    let mut second = std::fs::File::open(second)?;
+    if first.metadata()?.len() != second.metadata()?.len() {
+        return Ok(false);
     }
-    Ok(())
+    return Ok(file_diff::diff_files(&mut first, &mut second));
 }
 
 #[cfg(test)]
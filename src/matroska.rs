@@ -0,0 +1,219 @@
+use crate::extract::Extracted;
+use crate::photo::MediaKind;
+use crate::pserror::error::{PsError, PsErrorKind};
+use chrono::{Duration, NaiveDate, NaiveDateTime};
+use log::info;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+const EBML: u32 = 0x1A45_DFA3;
+const SEGMENT: u32 = 0x1853_8067;
+const INFO: u32 = 0x1549_A966;
+const CLUSTER: u32 = 0x1F43_B675;
+const DATE_UTC: u32 = 0x4461;
+const TITLE: u32 = 0x7BA9;
+
+/// Largest `Info` element which is read, it only holds a few short values.
+const MAX_INFO_LEN: u64 = 1 << 16;
+
+fn format_error(msg: String) -> PsError {
+    return PsError::new(PsErrorKind::FormatError, msg);
+}
+
+/// Reads an EBML variable-length integer: the number of leading zero bits of the first byte tells
+/// how many more bytes follow. IDs keep the length marker, sizes don't. None is the unknown size.
+fn read_vint(file: &mut File, keep_marker: bool) -> Result<Option<u64>, PsError> {
+    let mut first = [0u8; 1];
+    file.read_exact(&mut first)?;
+    let len = first[0].leading_zeros() as usize + 1;
+    if len > 8 {
+        return Err(format_error("Invalid EBML integer".to_string()));
+    }
+    let mut rest = [0u8; 7];
+    file.read_exact(&mut rest[..len - 1])?;
+    let marker = 0x80u64 >> (len - 1);
+    let mut value = if keep_marker {
+        first[0] as u64
+    } else {
+        first[0] as u64 & (marker - 1)
+    };
+    let mut all_ones = value == marker - 1;
+    for &b in &rest[..len - 1] {
+        value = value << 8 | b as u64;
+        all_ones &= b == 0xFF;
+    }
+    return Ok(if all_ones && !keep_marker {
+        None
+    } else {
+        Some(value)
+    });
+}
+
+/// Reads the ID and size of the element at the current position.
+fn read_element(file: &mut File) -> Result<(u32, Option<u64>), PsError> {
+    let id = read_vint(file, true)?.unwrap_or_default() as u32;
+    return Ok((id, read_vint(file, false)?));
+}
+
+/// Children of an element held in memory, as ID and data.
+fn children(mut data: &[u8]) -> Vec<(u32, &[u8])> {
+    let mut result = Vec::new();
+    // Same as read_vint, over a slice.
+    let vint = |data: &[u8], keep_marker: bool| -> Option<(u64, usize)> {
+        let len = data.first()?.leading_zeros() as usize + 1;
+        if len > 8 {
+            return None;
+        }
+        let bytes = data.get(..len)?;
+        let mut value = if keep_marker {
+            bytes[0] as u64
+        } else {
+            bytes[0] as u64 & (0xFF >> len)
+        };
+        for &b in &bytes[1..] {
+            value = value << 8 | b as u64;
+        }
+        return Some((value, len));
+    };
+    while let Some((id, id_len)) = vint(data, true) {
+        let (size, size_len) = match vint(&data[id_len..], false) {
+            Some(size) => size,
+            None => break,
+        };
+        let start = id_len + size_len;
+        let element = match start
+            .checked_add(size as usize)
+            .and_then(|end| data.get(start..end))
+        {
+            Some(element) => element,
+            None => break,
+        };
+        result.push((id as u32, element));
+        data = &data[start + element.len()..];
+    }
+    return result;
+}
+
+/// Data of the `Info` element of the first segment, which comes before the media data in
+/// `Cluster`s. Elements in between are skipped over without reading them.
+fn read_info(file: &mut File) -> Result<Vec<u8>, PsError> {
+    let (id, size) = read_element(file)?;
+    if id != EBML {
+        return Err(format_error("Not a Matroska or WebM file".to_string()));
+    }
+    file.seek(SeekFrom::Current(size.unwrap_or_default() as i64))?;
+    if read_element(file)?.0 != SEGMENT {
+        return Err(format_error("No segment".to_string()));
+    }
+    loop {
+        let (id, size) = read_element(file)?;
+        match (id, size) {
+            (INFO, Some(size)) if size <= MAX_INFO_LEN => {
+                let mut info = vec![0u8; size as usize];
+                file.read_exact(&mut info)?;
+                return Ok(info);
+            }
+            (CLUSTER, _) | (_, None) => break,
+            (_, Some(size)) => {
+                file.seek(SeekFrom::Current(size as i64))?;
+            }
+        }
+    }
+    return Err(format_error("No segment info".to_string()));
+}
+
+/// Reads the date and title of a Matroska or WebM file from its segment info. `DateUTC` is in
+/// nanoseconds since 2001-01-01 and, as its name says, in UTC.
+pub fn read_matroska(path: &Path) -> Result<Extracted, PsError> {
+    let info = read_info(&mut File::open(path)?)?;
+    let mut extracted = Extracted::default();
+    extracted.metadata.kind = Some(MediaKind::Video);
+    for (id, data) in children(&info) {
+        match id {
+            DATE_UTC if data.len() == 8 => {
+                let mut bytes = [0u8; 8];
+                bytes.copy_from_slice(data);
+                let nanos = i64::from_be_bytes(bytes);
+                extracted.dates.push((
+                    "Matroska DateUTC".to_string(),
+                    matroska_epoch() + Duration::nanoseconds(nanos),
+                ));
+            }
+            TITLE => {
+                let title = String::from_utf8_lossy(data).trim().to_string();
+                if !title.is_empty() {
+                    extracted.metadata.title = Some(title);
+                }
+            }
+            _ => {}
+        }
+    }
+    info!("Segment info of {:?}: {:?}", path, extracted);
+    return Ok(extracted);
+}
+
+fn matroska_epoch() -> NaiveDateTime {
+    return NaiveDate::from_ymd(2001, 1, 1).and_hms(0, 0, 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn element(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut element = id.to_vec();
+        // Eight-byte sizes, as muxers write them when they fill them in afterwards.
+        element.push(0x01);
+        element.extend_from_slice(&(data.len() as u64).to_be_bytes()[1..]);
+        element.extend_from_slice(data);
+        return element;
+    }
+
+    #[test]
+    fn test_read_matroska() -> Result<(), PsError> {
+        let date = NaiveDate::from_ymd(2021, 3, 6).and_hms(16, 47, 13);
+        let nanos = (date - matroska_epoch()).num_nanoseconds().unwrap();
+
+        let mut file = element(b"\x1a\x45\xdf\xa3", &element(b"\x42\x82", b"webm"));
+        let mut segment = element(b"\x11\x4d\x9b\x74", &[0u8; 20]);
+        let mut info = element(b"\x2a\xd7\xb1", &1_000_000u32.to_be_bytes());
+        info.extend(element(b"\x44\x61", &nanos.to_be_bytes()));
+        info.extend(element(b"\x7b\xa9", b"Birthday"));
+        segment.extend(element(b"\x15\x49\xa9\x66", &info));
+        segment.extend(element(b"\x1f\x43\xb6\x75", &[0u8; 100]));
+        // A segment of unknown size, as written while streaming.
+        file.extend_from_slice(b"\x18\x53\x80\x67\x01\xff\xff\xff\xff\xff\xff\xff");
+        file.extend(segment);
+
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("clip.webm");
+        std::fs::write(&path, file)?;
+        let extracted = read_matroska(&path)?;
+        assert_eq!(
+            extracted.dates,
+            vec![("Matroska DateUTC".to_string(), date)]
+        );
+        assert_eq!(extracted.metadata.title.as_deref(), Some("Birthday"));
+
+        std::fs::write(&path, b"RIFF\0\0\0\0AVI ")?;
+        assert!(read_matroska(&path).is_err());
+        return Ok(());
+    }
+
+    #[test]
+    fn test_children_malformed() {
+        let title = element(b"\x7b\xa9", b"Birthday");
+        // A zero byte starts no valid integer, and a size past the end is cut short.
+        for tail in &[
+            &b"\x00\x00"[..],
+            b"\x44\x61\x01\xff\xff\xff\xff\xff\xff\xfe",
+        ] {
+            let mut data = title.clone();
+            data.extend_from_slice(tail);
+            let found = children(&data);
+            assert_eq!(found.len(), 1);
+            assert_eq!(found[0], (0x7ba9, &b"Birthday"[..]));
+        }
+    }
+}
@@ -0,0 +1,265 @@
+use crate::index::Index;
+use crate::is_already_sorted;
+use crate::photo::{Camera, Photo};
+use crate::pserror::error::{PsError, PsErrorKind};
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+
+/// Library convention, e.g. `20210306_164713_01.jpg`.
+pub const DEFAULT_TEMPLATE: &str = "{date}_{time}_{seq}";
+
+const PLACEHOLDERS: [&str; 6] = [
+    "{date}", "{time}", "{subsec}", "{camera}", "{name}", "{seq}",
+];
+
+/// Placeholders which are never empty, one of which a template needs to give every file a name.
+const NEVER_EMPTY: [&str; 3] = ["{date}", "{name}", "{seq}"];
+
+/// Chooses file names in the library, either keeping the original names or following a template
+/// with these placeholders:
+///
+/// * `{date}`, `{time}`: capture date and time, `20210306` and `164713`
+/// * `{subsec}`: fraction of a second from EXIF `SubSecTimeOriginal`, if any
+/// * `{camera}`: camera model (or make) without spaces and punctuation, if known
+/// * `{name}`: original file name without its extension
+/// * `{seq}`: counter telling apart files which would get the same name, `01`, `02`...
+///
+/// Placeholders which are empty for a photo don't leave doubled separators behind. The extension is
+/// the original one in lower case. Files are numbered in the order they are named in, so that the
+/// same input named in the same order gets the same names again; a name already taken in the
+/// library by an identical file, or by the same file changed by `--write-dates` according to the
+/// index, is reused, so that it's reported as a duplicate.
+pub struct Namer<'a> {
+    template: Option<String>,
+    index: Option<&'a Index>,
+    /// Paths given out during this run.
+    taken: HashSet<PathBuf>,
+}
+
+impl<'a> Namer<'a> {
+    /// Keeps original names if `template` is None.
+    pub fn new(template: Option<String>) -> Namer<'a> {
+        return Namer {
+            template,
+            index: None,
+            taken: HashSet::new(),
+        };
+    }
+
+    /// Looks up files already in the library in `index`.
+    pub fn with_index(mut self, index: Option<&'a Index>) -> Namer<'a> {
+        self.index = index;
+        return self;
+    }
+
+    /// Returns the path in `dir` under which `photo`, originally called `original_name`, goes.
+    pub fn name(&mut self, dir: &Path, photo: &Photo, original_name: &OsStr) -> PathBuf {
+        let template = match &self.template {
+            None => return dir.join(original_name),
+            Some(template) => template,
+        };
+
+        let original = Path::new(original_name);
+        let extension = original
+            .extension()
+            .map(|e| format!(".{}", e.to_string_lossy().to_lowercase()))
+            .unwrap_or_default();
+        let stem = original
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        let mut seq = 1;
+        loop {
+            let mut name = OsString::from(render(template, photo, &stem, seq));
+            name.push(&extension);
+            let path = dir.join(name);
+            if !self.taken.contains(&path) && !is_taken_by_other_file(&path, photo, self.index) {
+                self.taken.insert(path.clone());
+                return path;
+            }
+            seq += 1;
+        }
+    }
+}
+
+/// Fails for templates with unknown placeholders or path separators, and for templates which
+/// could name a file with nothing but its extension.
+pub fn check_template(template: &str) -> Result<(), PsError> {
+    let invalid = |reason: String| {
+        PsError::new(
+            PsErrorKind::Unsupported,
+            format!("Invalid name template {}: {}", template, reason),
+        )
+    };
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end + 1,
+            None => rest.len(),
+        };
+        let placeholder = &rest[start..end];
+        if !PLACEHOLDERS.contains(&placeholder) {
+            return Err(invalid(format!(
+                "unknown placeholder {}, expected one of: {}",
+                placeholder,
+                PLACEHOLDERS.join(", ")
+            )));
+        }
+        rest = &rest[end..];
+    }
+    if template.contains(&['/', '\\'][..]) {
+        return Err(invalid("names can't have path separators".to_string()));
+    }
+    if !NEVER_EMPTY.iter().any(|p| template.contains(p)) {
+        return Err(invalid(format!(
+            "needs at least one of: {}",
+            NEVER_EMPTY.join(", ")
+        )));
+    }
+    return Ok(());
+}
+
+fn is_taken_by_other_file(path: &Path, photo: &Photo, index: Option<&Index>) -> bool {
+    if !path.exists() {
+        return false;
+    }
+    return match photo.path() {
+        Some(source) => !matches!(is_already_sorted(source, path, index), Ok(true)),
+        None => true,
+    };
+}
+
+/// Camera model, or make if the model isn't known, as a short code for file names.
+pub fn camera_code(camera: &Camera) -> String {
+    let name = camera
+        .model
+        .as_ref()
+        .or(camera.make.as_ref())
+        .map(String::as_str)
+        .unwrap_or("");
+    return name.chars().filter(|c| c.is_ascii_alphanumeric()).collect();
+}
+
+fn render(template: &str, photo: &Photo, stem: &str, seq: usize) -> String {
+    // Photos are named only once their date is known.
+    let date = photo.date().unwrap();
+    let mut name = template
+        .replace("{date}", &date.format("%Y%m%d").to_string())
+        .replace("{time}", &date.format("%H%M%S").to_string())
+        .replace("{subsec}", photo.subsec().as_deref().unwrap_or(""))
+        .replace("{camera}", &camera_code(photo.camera()))
+        .replace("{name}", stem);
+    if template.contains("{seq}") {
+        name = name.replace("{seq}", &format!("{:02}", seq));
+    } else if seq > 1 {
+        name = format!("{}_{:02}", name, seq);
+    }
+
+    // Empty placeholders leave separators next to each other or at either end.
+    let mut collapsed = String::with_capacity(name.len());
+    for c in name.chars() {
+        let separator = c == '_' || c == '-';
+        if separator && collapsed.ends_with(&['_', '-'][..]) {
+            continue;
+        }
+        collapsed.push(c);
+    }
+    return collapsed.trim_matches(&['_', '-'][..]).to_string();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::photo::PhotoBuilder;
+    use chrono::NaiveDate;
+
+    fn photo(path: &Path) -> Photo {
+        return PhotoBuilder::new()
+            .with_date(NaiveDate::from_ymd(2021, 3, 6).and_hms(16, 47, 13))
+            .with_os_path(path)
+            .build();
+    }
+
+    #[test]
+    fn test_name_from_template() {
+        let mut namer = Namer::new(Some(DEFAULT_TEMPLATE.to_string()));
+        let dir = Path::new("/nonexistent/2021/03/06");
+        let first = photo(Path::new("a/IMG_0001.JPG"));
+        let second = photo(Path::new("b/IMG_0001.JPG"));
+        assert_eq!(
+            namer.name(dir, &first, OsStr::new("IMG_0001.JPG")),
+            dir.join("20210306_164713_01.jpg")
+        );
+        assert_eq!(
+            namer.name(dir, &second, OsStr::new("IMG_0001.JPG")),
+            dir.join("20210306_164713_02.jpg")
+        );
+
+        let mut with_camera = photo(Path::new("c/DSC_0001.NEF"));
+        with_camera.set_subsec(Some("25".to_string()));
+        with_camera.set_camera(Camera {
+            make: Some("NIKON CORPORATION".to_string()),
+            model: Some("NIKON D70".to_string()),
+            serial: None,
+        });
+        let mut namer = Namer::new(Some("{date}_{time}_{subsec}_{camera}".to_string()));
+        assert_eq!(
+            namer.name(dir, &with_camera, OsStr::new("DSC_0001.NEF")),
+            dir.join("20210306_164713_25_NIKOND70.nef")
+        );
+        // Without subsecond and camera, and a counter only on collisions.
+        assert_eq!(
+            namer.name(dir, &first, OsStr::new("IMG_0001.JPG")),
+            dir.join("20210306_164713.jpg")
+        );
+        assert_eq!(
+            namer.name(dir, &second, OsStr::new("IMG_0001.JPG")),
+            dir.join("20210306_164713_02.jpg")
+        );
+
+        let mut namer = Namer::new(None);
+        assert_eq!(
+            namer.name(dir, &first, OsStr::new("IMG_0001.JPG")),
+            dir.join("IMG_0001.JPG")
+        );
+    }
+
+    #[test]
+    fn test_check_template() {
+        assert!(check_template(DEFAULT_TEMPLATE).is_ok());
+        assert!(check_template("{name}_{camera}").is_ok());
+        for template in &[
+            "{date}_{model}",
+            "{year}{seq}",
+            "{date}_{time",
+            "../{date}",
+            "{date}/{time}",
+            "{camera}",
+            "{time}_{subsec}",
+        ] {
+            assert!(check_template(template).is_err(), "{}", template);
+        }
+    }
+
+    #[test]
+    fn test_name_skips_other_files_in_library() -> std::io::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let source = temp_dir.path().join("IMG_0001.JPG");
+        std::fs::write(&source, "photo")?;
+        std::fs::write(
+            temp_dir.path().join("20210306_164713_01.jpg"),
+            "other photo",
+        )?;
+        std::fs::write(temp_dir.path().join("20210306_164713_02.jpg"), "photo")?;
+
+        // The identical file already in the library keeps its name.
+        let mut namer = Namer::new(Some(DEFAULT_TEMPLATE.to_string()));
+        assert_eq!(
+            namer.name(temp_dir.path(), &photo(&source), OsStr::new("IMG_0001.JPG")),
+            temp_dir.path().join("20210306_164713_02.jpg")
+        );
+        return Ok(());
+    }
+}
@@ -1,12 +1,114 @@
 use chrono::NaiveDateTime;
-use std::convert::AsRef;
-use std::path::Path;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
 
-#[derive(Default, Debug, Clone, Eq, PartialEq)]
+/// A date found in a file but not used because it looks wrong, e.g. set by a reset camera clock.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RejectedDate {
+    /// Where the date came from, e.g. "EXIF DateTimeOriginal".
+    pub source: String,
+    pub date: NaiveDateTime,
+    pub reason: String,
+}
+
+impl std::fmt::Display for RejectedDate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} is {}", self.source, self.date, self.reason)
+    }
+}
+
+/// Device which took the photo, as far as its metadata tells.
+#[derive(Serialize, Default, Debug, Clone, Eq, PartialEq)]
+pub struct Camera {
+    pub make: Option<String>,
+    pub model: Option<String>,
+    pub serial: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaKind {
+    Image,
+    Video,
+    Audio,
+}
+
+/// Where the photo was taken, in decimal degrees.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct Gps {
+    /// Negative south of the equator.
+    pub latitude: f64,
+    /// Negative west of Greenwich.
+    pub longitude: f64,
+    /// Metres, negative below sea level.
+    pub altitude: Option<f64>,
+}
+
+/// What discovery found out about a file besides its date, so that nothing else has to open it
+/// again. Any field can be missing, as not every file records everything.
+#[derive(Serialize, Default, Debug, Clone, PartialEq)]
+pub struct Metadata {
+    pub kind: Option<MediaKind>,
+    pub camera: Camera,
+    pub lens: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// EXIF orientation, 1 to 8.
+    pub orientation: Option<u32>,
+    pub gps: Option<Gps>,
+    pub title: Option<String>,
+    pub caption: Option<String>,
+    /// Who took the photo.
+    pub byline: Option<String>,
+    pub keywords: Vec<String>,
+    /// Rating from 1 to 5 stars, 0 for unrated and -1 for rejected.
+    pub rating: Option<i32>,
+    pub file_size: Option<u64>,
+    /// SHA-256 of the file as discovered, hex-encoded.
+    pub hash: Option<String>,
+}
+
+impl Metadata {
+    /// Fills in whatever is missing here from `other`.
+    pub fn fill_from(&mut self, other: Metadata) {
+        self.kind = self.kind.or(other.kind);
+        self.camera.make = self.camera.make.take().or(other.camera.make);
+        self.camera.model = self.camera.model.take().or(other.camera.model);
+        self.camera.serial = self.camera.serial.take().or(other.camera.serial);
+        self.lens = self.lens.take().or(other.lens);
+        self.width = self.width.or(other.width);
+        self.height = self.height.or(other.height);
+        self.orientation = self.orientation.or(other.orientation);
+        self.gps = self.gps.or(other.gps);
+        self.title = self.title.take().or(other.title);
+        self.caption = self.caption.take().or(other.caption);
+        self.byline = self.byline.take().or(other.byline);
+        if self.keywords.is_empty() {
+            self.keywords = other.keywords;
+        }
+        self.rating = self.rating.or(other.rating);
+        self.file_size = self.file_size.or(other.file_size);
+        self.hash = self.hash.take().or(other.hash);
+    }
+}
+
+/// Paths are kept as `PathBuf` rather than `String`, so that files with names which aren't valid
+/// UTF-8 (e.g. Latin-1 names from old Windows shares) are handled without loss.
+#[derive(Default, Debug, Clone, PartialEq)]
 pub struct Photo {
     date: Option<NaiveDateTime>,
-    path: Option<String>,
-    new_path: Option<String>,
+    path: Option<PathBuf>,
+    new_path: Option<PathBuf>,
+    /// Suspicious dates skipped in favour of `date`.
+    rejected_dates: Vec<RejectedDate>,
+    metadata: Metadata,
+    /// Whether `date` has been shifted by a clock rule.
+    date_corrected: bool,
+    /// Fraction of a second of `date`, digits as recorded in EXIF `SubSecTimeOriginal`.
+    subsec: Option<String>,
+    /// Whether `date` was inferred from the file name or modification time rather than read from
+    /// the file's metadata.
+    date_inferred: bool,
 }
 
 #[derive(Debug, PartialEq)]
@@ -15,19 +117,16 @@ pub struct PhotoBuilder {
 }
 
 impl Photo {
-    pub fn new() -> Photo {
-        return Photo {
-            date: None,
-            path: None,
-            new_path: None,
-        };
-    }
-
-    pub fn from(path: String, date: NaiveDateTime) -> Photo {
+    pub fn from(path: PathBuf, date: NaiveDateTime) -> Photo {
         return Photo {
             date: Option::from(date),
             path: Option::from(path),
             new_path: None,
+            rejected_dates: Vec::new(),
+            metadata: Metadata::default(),
+            date_corrected: false,
+            subsec: None,
+            date_inferred: false,
         };
     }
 
@@ -39,21 +138,68 @@ impl Photo {
         return self.date;
     }
 
-    pub fn set_path(&mut self, path: String) {
+    pub fn set_path(&mut self, path: PathBuf) {
         self.path = Option::from(path);
     }
 
-    pub fn path(&self) -> &Option<String> {
+    pub fn path(&self) -> &Option<PathBuf> {
         return &self.path;
     }
 
-    pub fn set_new_path(&mut self, new_path: String) {
+    pub fn set_new_path(&mut self, new_path: PathBuf) {
         self.new_path = Option::from(new_path);
     }
 
-    pub fn new_path(&self) -> &Option<String> {
+    pub fn new_path(&self) -> &Option<PathBuf> {
         return &self.new_path;
     }
+
+    pub fn set_rejected_dates(&mut self, rejected_dates: Vec<RejectedDate>) {
+        self.rejected_dates = rejected_dates;
+    }
+
+    pub fn rejected_dates(&self) -> &Vec<RejectedDate> {
+        return &self.rejected_dates;
+    }
+
+    #[cfg(test)]
+    pub fn set_camera(&mut self, camera: Camera) {
+        self.metadata.camera = camera;
+    }
+
+    pub fn camera(&self) -> &Camera {
+        return &self.metadata.camera;
+    }
+
+    pub fn set_metadata(&mut self, metadata: Metadata) {
+        self.metadata = metadata;
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        return &self.metadata;
+    }
+
+    pub fn set_subsec(&mut self, subsec: Option<String>) {
+        self.subsec = subsec;
+    }
+
+    pub fn subsec(&self) -> &Option<String> {
+        return &self.subsec;
+    }
+
+    pub fn set_date_corrected(&mut self, date_corrected: bool) {
+        self.date_corrected = date_corrected;
+    }
+
+    pub fn set_date_inferred(&mut self, date_inferred: bool) {
+        self.date_inferred = date_inferred;
+    }
+
+    /// Whether the date wasn't taken from the file's own metadata as it is, so that other tools
+    /// reading the file see no date or a wrong one.
+    pub fn date_is_fallback(&self) -> bool {
+        return self.date_corrected || self.date_inferred || !self.rejected_dates.is_empty();
+    }
 }
 
 impl PhotoBuilder {
@@ -63,28 +209,40 @@ impl PhotoBuilder {
                 date: None,
                 path: None,
                 new_path: None,
+                rejected_dates: Vec::new(),
+                metadata: Metadata::default(),
+                date_corrected: false,
+                subsec: None,
+                date_inferred: false,
             },
         };
     }
 
+    #[cfg(test)]
     pub fn with_date(&mut self, date: NaiveDateTime) -> &mut PhotoBuilder {
         self.photo.set_date(date);
         return self;
     }
 
     pub fn with_os_path(&mut self, path: &Path) -> &mut PhotoBuilder {
-        let str_path: String = path.to_str().unwrap().to_string();
-        self.photo.set_path(str_path);
+        self.photo.set_path(path.to_path_buf());
+        return self;
+    }
+
+    #[cfg(test)]
+    pub fn with_path<P: Into<PathBuf>>(&mut self, path: P) -> &mut PhotoBuilder {
+        self.photo.set_path(path.into());
         return self;
     }
 
-    pub fn with_path(&mut self, path: String) -> &mut PhotoBuilder {
-        self.photo.set_path(path);
+    pub fn with_new_path<P: Into<PathBuf>>(&mut self, new_path: P) -> &mut PhotoBuilder {
+        self.photo.set_new_path(new_path.into());
         return self;
     }
 
-    pub fn with_new_path(&mut self, new_path: String) -> &mut PhotoBuilder {
-        self.photo.set_new_path(new_path);
+    #[cfg(test)]
+    pub fn with_metadata(&mut self, metadata: Metadata) -> &mut PhotoBuilder {
+        self.photo.set_metadata(metadata);
         return self;
     }
 
@@ -106,4 +264,16 @@ mod tests {
 
         assert_eq!(Path::new(&photo.path().as_ref().unwrap()), path);
     }
+
+    #[test]
+    fn test_with_non_utf8_os_path() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        // "café.jpg" in Latin-1
+        let path = Path::new(OsStr::from_bytes(b"/photos/caf\xe9.jpg"));
+        let photo = PhotoBuilder::new().with_os_path(path).build();
+
+        assert_eq!(photo.path().as_deref(), Some(path));
+    }
 }
@@ -1,8 +1,11 @@
 pub mod error {
     use std::fmt::{Debug, Formatter, Result};
+    use std::path::{Path, PathBuf};
     use zip::result::ZipError;
 
-    #[derive(Debug, Eq, PartialEq)]
+    type Cause = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+    #[derive(Debug, Eq, PartialEq, Clone, Copy, Hash, PartialOrd, Ord)]
     pub enum PsErrorKind {
         Unknown,
         NoExif,
@@ -11,54 +14,186 @@ pub mod error {
         FormatError,
         NoDateField,
         ZipError,
+        IndexError,
+        Locked,
+        /// Not allowed to read the source or write the destination.
+        PermissionDenied,
+        /// A different file is already where this one should go.
+        Conflict,
+        /// File is damaged or truncated.
+        Corrupt,
+        /// File is recognised, but the operation isn't supported for it.
+        Unsupported,
+    }
+
+    /// Part of the pipeline where an error happened.
+    #[derive(Debug, Eq, PartialEq, Clone, Copy)]
+    pub enum Stage {
+        /// Listing files in the source.
+        Walk,
+        /// Checking that a file isn't damaged or truncated.
+        Verify,
+        /// Reading metadata of a file.
+        Discover,
+        /// Working out where a file should go.
+        Plan,
+        /// Moving or copying a file into the destination.
+        Transfer,
+    }
+
+    impl std::fmt::Display for Stage {
+        fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+            let name = match self {
+                Stage::Walk => "walk",
+                Stage::Verify => "verify",
+                Stage::Discover => "discover",
+                Stage::Plan => "plan",
+                Stage::Transfer => "transfer",
+            };
+            write!(f, "{}", name)
+        }
     }
 
-    #[derive(Debug, Eq, PartialEq)]
+    #[derive(Debug)]
     pub struct PsError {
         kind: PsErrorKind,
         msg: String,
+        path: Option<PathBuf>,
+        stage: Option<Stage>,
+        source: Option<Cause>,
     }
 
     impl std::fmt::Display for PsError {
         fn fmt(&self, f: &mut Formatter<'_>) -> Result {
-            write!(f, "PsError {:?}: {}", self.kind, self.msg)
+            write!(f, "PsError {:?}: {}", self.kind, self.msg)?;
+            if let Some(path) = &self.path {
+                write!(f, " ({})", path.display())?;
+            }
+            if let Some(stage) = &self.stage {
+                write!(f, " during {}", stage)?;
+            }
+            Ok(())
+        }
+    }
+
+    impl std::error::Error for PsError {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            return match &self.source {
+                Some(source) => Some(source.as_ref()),
+                None => None,
+            };
+        }
+    }
+
+    /// Errors are equal if they describe the same failure; underlying causes aren't compared.
+    impl PartialEq for PsError {
+        fn eq(&self, other: &Self) -> bool {
+            return self.kind == other.kind
+                && self.msg == other.msg
+                && self.path == other.path
+                && self.stage == other.stage;
         }
     }
 
+    impl Eq for PsError {}
+
     impl PsError {
         pub fn new(kind: PsErrorKind, msg: String) -> PsError {
-            return PsError { kind, msg };
+            return PsError {
+                kind,
+                msg,
+                path: None,
+                stage: None,
+                source: None,
+            };
+        }
+
+        fn caused_by<E>(kind: PsErrorKind, err: E) -> PsError
+        where
+            E: std::error::Error + Send + Sync + 'static,
+        {
+            let mut error = PsError::new(kind, err.to_string());
+            error.source = Some(Box::new(err));
+            return error;
+        }
+
+        /// Sets the file this error is about.
+        pub fn with_path<P: AsRef<Path>>(mut self, path: P) -> PsError {
+            self.path = Some(path.as_ref().to_path_buf());
+            return self;
+        }
+
+        /// Sets the pipeline stage this error happened in.
+        pub fn at_stage(mut self, stage: Stage) -> PsError {
+            self.stage = Some(stage);
+            return self;
+        }
+
+        pub fn kind(&self) -> &PsErrorKind {
+            return &self.kind;
+        }
+
+        pub fn message(&self) -> &str {
+            return &self.msg;
+        }
+
+        pub fn path(&self) -> Option<&Path> {
+            return self.path.as_deref();
+        }
+
+        pub fn stage(&self) -> Option<Stage> {
+            return self.stage;
         }
     }
 
     impl From<std::io::Error> for PsError {
         fn from(err: std::io::Error) -> Self {
-            PsError {
-                kind: PsErrorKind::IoError,
-                msg: err.to_string(),
-            }
+            let kind = match err.kind() {
+                std::io::ErrorKind::PermissionDenied => PsErrorKind::PermissionDenied,
+                std::io::ErrorKind::AlreadyExists => PsErrorKind::Conflict,
+                _ => PsErrorKind::IoError,
+            };
+            PsError::caused_by(kind, err)
         }
     }
 
     impl From<ZipError> for PsError {
         fn from(err: ZipError) -> Self {
-            PsError {
-                kind: PsErrorKind::ZipError,
-                msg: err.to_string(),
-            }
+            let kind = match err {
+                ZipError::Io(_) => PsErrorKind::IoError,
+                ZipError::InvalidArchive(_) => PsErrorKind::Corrupt,
+                ZipError::UnsupportedArchive(_) => PsErrorKind::Unsupported,
+                _ => PsErrorKind::ZipError,
+            };
+            PsError::caused_by(kind, err)
+        }
+    }
+
+    impl From<rusqlite::Error> for PsError {
+        fn from(err: rusqlite::Error) -> Self {
+            PsError::caused_by(PsErrorKind::IndexError, err)
+        }
+    }
+
+    impl From<notify::Error> for PsError {
+        fn from(err: notify::Error) -> Self {
+            PsError::caused_by(PsErrorKind::IoError, err)
         }
     }
 
+    #[cfg(feature = "ffmpeg")]
     impl From<ffmpeg::Error> for PsError {
         fn from(e: ffmpeg::Error) -> Self {
-            return PsError::new(PsErrorKind::FormatError, e.to_string());
+            return PsError::caused_by(PsErrorKind::FormatError, e);
         }
     }
 
     #[cfg(test)]
     mod tests {
 
-        use crate::pserror::error::{PsError, PsErrorKind};
+        use crate::pserror::error::{PsError, PsErrorKind, Stage};
+        use std::error::Error;
+        use std::path::Path;
 
         #[test]
         fn test_create_error() {
@@ -69,8 +204,28 @@ pub mod error {
             let expected = PsError {
                 kind: PsErrorKind::FileNotSupported,
                 msg: "File not supported".to_string(),
+                path: None,
+                stage: None,
+                source: None,
             };
             assert_eq!(error, expected);
         }
+
+        #[test]
+        fn test_io_error_context() {
+            let io_error = std::io::Error::new(std::io::ErrorKind::PermissionDenied, "denied");
+            let error = PsError::from(io_error)
+                .with_path("/photos/IMG_0001.JPG")
+                .at_stage(Stage::Transfer);
+
+            assert_eq!(error.kind(), &PsErrorKind::PermissionDenied);
+            assert_eq!(error.path(), Some(Path::new("/photos/IMG_0001.JPG")));
+            assert_eq!(error.stage(), Some(Stage::Transfer));
+            assert_eq!(error.source().unwrap().to_string(), "denied");
+            assert_eq!(
+                error.to_string(),
+                "PsError PermissionDenied: denied (/photos/IMG_0001.JPG) during transfer"
+            );
+        }
     }
 }
@@ -0,0 +1,263 @@
+use crate::clock::Correction;
+use crate::photo::Photo;
+use crate::pserror::error::{PsError, PsErrorKind};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Exit code when some, but not all, files failed.
+pub const EXIT_PARTIAL_FAILURE: i32 = 2;
+/// Exit code when every file which was attempted failed.
+pub const EXIT_TOTAL_FAILURE: i32 = 3;
+
+/// What happened to a single file which didn't fail.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Outcome {
+    Moved,
+    Copied,
+    /// Not transferred on purpose, e.g. in dry-run mode or not a supported file.
+    Skipped,
+    /// An identical file already exists at the destination.
+    Duplicate,
+    /// Date couldn't be discovered, the file went into the undated directory.
+    Undated,
+    /// File is corrupt or not supported, it went into the quarantine directory.
+    Quarantined,
+}
+
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct Failure {
+    pub path: String,
+    /// Pipeline stage the failure happened in, if known.
+    pub stage: Option<String>,
+    pub message: String,
+}
+
+/// A file which couldn't be sorted and was put aside instead, together with the reason.
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct SetAside {
+    pub path: String,
+    pub destination: String,
+    pub reason: String,
+}
+
+/// A file whose date looked wrong, and was skipped in favour of another one.
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct SuspiciousDate {
+    pub path: String,
+    /// Where the rejected date came from.
+    pub source: String,
+    pub rejected: String,
+    pub reason: String,
+}
+
+/// A date shifted by a clock rule.
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct ClockCorrection {
+    pub path: String,
+    pub original: String,
+    pub corrected: String,
+    pub rule: String,
+}
+
+/// Where files of one kind went, see `--dst-for`.
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct Destination {
+    pub path: String,
+    pub files: usize,
+}
+
+/// Summary of a run, printed at the end and optionally written as JSON.
+#[derive(Serialize, Debug, Default)]
+pub struct RunReport {
+    pub processed: usize,
+    pub moved: usize,
+    pub copied: usize,
+    pub skipped: usize,
+    pub duplicates: usize,
+    pub undated: usize,
+    pub quarantined: usize,
+    pub failed: usize,
+    /// Files left for a later `--resume` because the run was interrupted.
+    pub remaining: usize,
+    /// Failures grouped by `PsErrorKind`.
+    pub failures: BTreeMap<String, Vec<Failure>>,
+    /// Files which went into the undated or quarantine directory.
+    pub set_aside: Vec<SetAside>,
+    /// Suspicious dates which weren't used, a file may have several.
+    pub date_suspicious: Vec<SuspiciousDate>,
+    pub clock_corrections: Vec<ClockCorrection>,
+    /// Sorted files whose date was written into EXIF or a sidecar, see `--write-dates`.
+    pub dates_written: usize,
+    /// Sorted files by kind, e.g. "videos", and where they went. In dry-run mode, where they would
+    /// go.
+    pub destinations: BTreeMap<String, Destination>,
+}
+
+impl RunReport {
+    pub fn new() -> RunReport {
+        return RunReport::default();
+    }
+
+    pub fn record(&mut self, outcome: Outcome) {
+        self.processed += 1;
+        match outcome {
+            Outcome::Moved => self.moved += 1,
+            Outcome::Copied => self.copied += 1,
+            Outcome::Skipped => self.skipped += 1,
+            Outcome::Duplicate => self.duplicates += 1,
+            Outcome::Undated => self.undated += 1,
+            Outcome::Quarantined => self.quarantined += 1,
+        }
+    }
+
+    /// Remembers why a file was set aside; the outcome itself is counted by `record`.
+    pub fn add_set_aside(&mut self, path: &Path, destination: &Path, reason: &PsError) {
+        self.set_aside.push(SetAside {
+            path: path.display().to_string(),
+            destination: destination.display().to_string(),
+            reason: reason.to_string(),
+        });
+    }
+
+    pub fn record_failure(&mut self, path: &Path, err: &PsError) {
+        self.processed += 1;
+        self.failed += 1;
+        self.failures
+            .entry(format!("{:?}", err.kind()))
+            .or_default()
+            .push(Failure {
+                path: path.display().to_string(),
+                stage: err.stage().map(|s| s.to_string()),
+                message: err.message().to_string(),
+            });
+    }
+
+    /// Flags the photo if any of its dates were rejected as suspicious.
+    pub fn add_rejected_dates(&mut self, path: &Path, photo: &Photo) {
+        for rejected in photo.rejected_dates() {
+            self.date_suspicious.push(SuspiciousDate {
+                path: path.display().to_string(),
+                source: rejected.source.clone(),
+                rejected: rejected.date.to_string(),
+                reason: rejected.reason.clone(),
+            });
+        }
+    }
+
+    pub fn add_destination(&mut self, kind: &str, path: &Path) {
+        self.destinations
+            .entry(kind.to_string())
+            .or_insert_with(|| Destination {
+                path: path.display().to_string(),
+                files: 0,
+            })
+            .files += 1;
+    }
+
+    pub fn add_clock_correction(&mut self, path: &Path, correction: &Correction) {
+        self.clock_corrections.push(ClockCorrection {
+            path: path.display().to_string(),
+            original: correction.original.to_string(),
+            corrected: correction.corrected.to_string(),
+            rule: correction.rule.clone(),
+        });
+    }
+
+    /// 0 if nothing failed, otherwise whether the failure was partial or total.
+    pub fn exit_code(&self) -> i32 {
+        if self.failed == 0 {
+            return 0;
+        }
+        if self.failed + self.skipped == self.processed {
+            return EXIT_TOTAL_FAILURE;
+        }
+        return EXIT_PARTIAL_FAILURE;
+    }
+
+    /// Human-readable summary for the terminal.
+    pub fn summary(&self) -> String {
+        let mut summary = format!(
+            "Processed {} files: {} moved, {} copied, {} skipped, {} duplicates, {} undated, \
+             {} quarantined, {} failed\n",
+            self.processed,
+            self.moved,
+            self.copied,
+            self.skipped,
+            self.duplicates,
+            self.undated,
+            self.quarantined,
+            self.failed
+        );
+        for (kind, destination) in &self.destinations {
+            summary.push_str(&format!(
+                "  {}: {} in {}\n",
+                kind, destination.files, destination.path
+            ));
+        }
+        for (kind, failures) in &self.failures {
+            summary.push_str(&format!("  {}: {}\n", kind, failures.len()));
+        }
+        if !self.date_suspicious.is_empty() {
+            summary.push_str(&format!(
+                "  date suspicious: {}\n",
+                self.date_suspicious.len()
+            ));
+        }
+        if !self.clock_corrections.is_empty() {
+            summary.push_str(&format!(
+                "  clock corrected: {}\n",
+                self.clock_corrections.len()
+            ));
+        }
+        if self.dates_written > 0 {
+            summary.push_str(&format!("  dates written: {}\n", self.dates_written));
+        }
+        if self.remaining > 0 {
+            summary.push_str(&format!(
+                "Interrupted with {} files remaining\n",
+                self.remaining
+            ));
+        }
+        return summary;
+    }
+
+    pub fn write_json(&self, path: &str) -> Result<(), PsError> {
+        let file = std::fs::File::create(path)?;
+        serde_json::to_writer_pretty(file, self)
+            .map_err(|e| PsError::new(PsErrorKind::IoError, e.to_string()))?;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_codes() {
+        let mut report = RunReport::new();
+        assert_eq!(report.exit_code(), 0);
+
+        report.record(Outcome::Skipped);
+        report.record_failure(
+            Path::new("a.jpg"),
+            &PsError::new(PsErrorKind::NoDateField, "No date".to_string()),
+        );
+        assert_eq!(report.exit_code(), EXIT_TOTAL_FAILURE);
+
+        report.record(Outcome::Copied);
+        assert_eq!(report.exit_code(), EXIT_PARTIAL_FAILURE);
+        assert_eq!(report.failures["NoDateField"][0].path, "a.jpg");
+    }
+
+    #[test]
+    fn test_destinations() {
+        let mut report = RunReport::new();
+        report.add_destination("photos", Path::new("LIB"));
+        report.add_destination("videos", Path::new("/mnt/videos"));
+        report.add_destination("photos", Path::new("LIB"));
+        assert_eq!(report.destinations["photos"].files, 2);
+        assert!(report.summary().contains("  videos: 1 in /mnt/videos\n"));
+    }
+}
@@ -0,0 +1,134 @@
+use crate::pserror::error::{PsError, PsErrorKind};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+/// Name of the file recording unfinished work, kept in the root of the destination library.
+pub const RESUME_FILE_NAME: &str = ".photosort.resume";
+
+/// Registers SIGINT/SIGTERM handlers and returns the flag they set.
+///
+/// The first signal only asks photosort to stop after the current file; a second one terminates
+/// the process immediately, in case stopping takes too long.
+pub fn register_shutdown() -> Result<Arc<AtomicBool>, PsError> {
+    let shutdown = Arc::new(AtomicBool::new(false));
+    for signal in &[signal_hook::consts::SIGTERM, signal_hook::consts::SIGINT] {
+        signal_hook::flag::register_conditional_shutdown(*signal, 1, Arc::clone(&shutdown))?;
+        signal_hook::flag::register(*signal, Arc::clone(&shutdown))?;
+    }
+    return Ok(shutdown);
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct ResumeState {
+    source: String,
+    /// Files (or zip entries) which haven't been processed yet. Kept as `OsString`, which unlike
+    /// `PathBuf` serializes names which aren't valid UTF-8 without loss.
+    remaining: Vec<OsString>,
+}
+
+/// Remembers which files of an interrupted run are still to be processed.
+pub struct Journal {
+    path: PathBuf,
+    source: String,
+}
+
+impl Journal {
+    pub fn new<P: AsRef<Path>, S: AsRef<Path>>(destination: P, source: S) -> Journal {
+        return Journal {
+            path: destination.as_ref().join(RESUME_FILE_NAME),
+            // Only compared with the next run's source, so lossy is good enough.
+            source: source.as_ref().to_string_lossy().to_string(),
+        };
+    }
+
+    /// Returns files left over by an interrupted run, or None if there was no such run.
+    pub fn load_remaining(&self) -> Result<Option<Vec<PathBuf>>, PsError> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+
+        let state: ResumeState = serde_json::from_reader(std::fs::File::open(&self.path)?)
+            .map_err(|e| {
+                PsError::new(
+                    PsErrorKind::FormatError,
+                    format!("Couldn't read {:?}: {}", self.path, e),
+                )
+            })?;
+        if state.source != self.source {
+            return Err(PsError::new(
+                PsErrorKind::Unknown,
+                format!(
+                    "Interrupted run was sorting {}, not {}",
+                    state.source, self.source
+                ),
+            ));
+        }
+
+        info!(
+            "Resuming interrupted run, {} files remaining",
+            state.remaining.len()
+        );
+        return Ok(Some(
+            state.remaining.into_iter().map(PathBuf::from).collect(),
+        ));
+    }
+
+    pub fn save_remaining(&self, remaining: Vec<PathBuf>) -> Result<(), PsError> {
+        let state = ResumeState {
+            source: self.source.clone(),
+            remaining: remaining.into_iter().map(PathBuf::into_os_string).collect(),
+        };
+        let json = serde_json::to_string_pretty(&state)
+            .map_err(|e| PsError::new(PsErrorKind::FormatError, e.to_string()))?;
+
+        // Written aside and renamed so that being killed here doesn't lose the previous state.
+        let temp_path = self.path.with_extension("resume.partial");
+        std::fs::write(&temp_path, json)?;
+        std::fs::rename(&temp_path, &self.path)?;
+        return Ok(());
+    }
+
+    /// Forgets about the interrupted run once everything has been processed.
+    pub fn clear(&self) -> Result<(), PsError> {
+        if self.path.exists() {
+            std::fs::remove_file(&self.path)?;
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    #[test]
+    fn test_save_and_load_remaining() -> Result<(), PsError> {
+        let temp_dir = tempfile::tempdir()?;
+        let destination = temp_dir.path().to_str().unwrap();
+        let journal = Journal::new(destination, "/mnt/card");
+        assert_eq!(journal.load_remaining()?, None);
+
+        let remaining = vec![
+            PathBuf::from("/mnt/card/a.jpg"),
+            // Latin-1 name, must survive the round trip unchanged.
+            PathBuf::from(OsStr::from_bytes(b"/mnt/card/caf\xe9.jpg")),
+        ];
+        journal.save_remaining(remaining.clone())?;
+        assert_eq!(journal.load_remaining()?, Some(remaining));
+
+        // Resuming a different source must not pick up somebody else's leftovers.
+        assert!(Journal::new(destination, "/mnt/other")
+            .load_remaining()
+            .is_err());
+
+        journal.clear()?;
+        assert_eq!(journal.load_remaining()?, None);
+        return Ok(());
+    }
+}
@@ -3,16 +3,23 @@ mod tests {
     use crate::discovery::*;
     use chrono::NaiveDate;
     use std::io;
-    use std::path::Path;
+    use std::path::{Path, PathBuf};
 
-    use crate::pserror::error::PsError;
-    use crate::{move_photo, photo::PhotoBuilder, update_new_path, Photo};
+    use crate::extract::Registry;
+    use crate::layout::{Category, Destinations};
+    use crate::naming::Namer;
+    use crate::photo::MediaKind;
+    use crate::pserror::error::{PsError, PsErrorKind};
+    use crate::report::Outcome;
+    use crate::{
+        move_across_devices, move_photo, photo::PhotoBuilder, sort_files, update_new_path,
+    };
 
     #[test]
     fn test_update_path() {
         use chrono::NaiveDate;
 
-        let dest_dir = String::from("TEST_DIR");
+        let mut destinations = Destinations::new("TEST_DIR");
 
         let mut photos = vec![
             PhotoBuilder::new()
@@ -25,14 +32,47 @@ mod tests {
                 .build(),
         ];
 
-        update_new_path(&dest_dir, &mut photos);
+        update_new_path(&destinations, &mut photos, &mut Namer::new(None));
         assert_eq!(
             photos[0].new_path().as_ref().unwrap(),
-            "TEST_DIR/2021/03/06/path.jpg"
+            Path::new("TEST_DIR/2021/03/06/path.jpg")
         );
         assert_eq!(
             photos[1].new_path().as_ref().unwrap(),
-            "TEST_DIR/2002/02/06/another_path.jpg"
+            Path::new("TEST_DIR/2002/02/06/another_path.jpg")
+        );
+
+        let mut recording = PhotoBuilder::new()
+            .with_date(NaiveDate::from_ymd(2021, 3, 6).and_hms(16, 50, 2))
+            .with_path("my/current/memo.m4a".to_string())
+            .build();
+        let mut metadata = recording.metadata().clone();
+        metadata.kind = Some(MediaKind::Audio);
+        recording.set_metadata(metadata);
+        photos.push(recording);
+        photos.push(
+            PhotoBuilder::new()
+                .with_date(NaiveDate::from_ymd(2021, 3, 6).and_hms(16, 52, 0))
+                .with_path("my/current/Screenshot_20210306-165200.png".to_string())
+                .build(),
+        );
+        destinations.set_dir(Category::Audio, "audio");
+        destinations.set_dir(Category::Screenshots, "/screenshots");
+        destinations
+            .set_layout(Some(Category::Screenshots), "{year}")
+            .unwrap();
+        update_new_path(&destinations, &mut photos, &mut Namer::new(None));
+        assert_eq!(
+            photos[0].new_path().as_ref().unwrap(),
+            Path::new("TEST_DIR/2021/03/06/path.jpg")
+        );
+        assert_eq!(
+            photos[2].new_path().as_ref().unwrap(),
+            Path::new("TEST_DIR/audio/2021/03/06/memo.m4a")
+        );
+        assert_eq!(
+            photos[3].new_path().as_ref().unwrap(),
+            Path::new("/screenshots/2021/Screenshot_20210306-165200.png")
         );
     }
 
@@ -51,7 +91,7 @@ mod tests {
 
         println!("Moving {:?}", photo);
         assert!(move_photo(
-            &photo, false, /* copying */
+            &photo, None, false, /* copying */
             false  /* no dry run */
         )
         .is_ok());
@@ -61,4 +101,331 @@ mod tests {
 
         return Ok(());
     }
+
+    #[test]
+    fn move_photo_with_sidecars_test() -> Result<(), PsError> {
+        let source = tempfile::tempdir()?;
+        let destination = tempfile::tempdir()?;
+        let path = source.path().join("IMG_0001.JPG");
+        std::fs::copy("./test-assets/jpg/Canon_40D.jpg", &path)?;
+        std::fs::write(source.path().join("IMG_0001.JPG.xmp"), "darktable")?;
+        std::fs::write(source.path().join("IMG_0001.xmp"), "lightroom")?;
+        let new_path = destination.path().join("20080530_155601.jpg");
+        let photo = PhotoBuilder::new()
+            .with_path(path.clone())
+            .with_new_path(new_path.to_str().unwrap().to_string())
+            .build();
+
+        assert_eq!(move_photo(&photo, None, true, false)?, Outcome::Moved);
+        assert!(!path.exists());
+        assert_eq!(
+            std::fs::read_to_string(destination.path().join("20080530_155601.jpg.xmp"))?,
+            "darktable"
+        );
+        assert_eq!(
+            std::fs::read_to_string(destination.path().join("20080530_155601.xmp"))?,
+            "lightroom"
+        );
+        assert_eq!(std::fs::read_dir(source.path())?.count(), 0);
+        return Ok(());
+    }
+
+    #[test]
+    fn move_across_devices_test() -> Result<(), PsError> {
+        let source = tempfile::tempdir()?;
+        let destination = tempfile::tempdir()?;
+        let path = source.path().join("IMG_0001.JPG");
+        std::fs::copy("./test-assets/jpg/Canon_40D.jpg", &path)?;
+        let new_path = destination.path().join("IMG_0001.JPG");
+
+        move_across_devices(&path, &new_path)?;
+        assert!(!path.exists());
+        assert_eq!(
+            std::fs::read(&new_path)?,
+            std::fs::read("./test-assets/jpg/Canon_40D.jpg")?
+        );
+        assert_eq!(std::fs::read_dir(destination.path())?.count(), 1);
+
+        // Nothing is removed when the copy fails.
+        std::fs::write(&path, "photo")?;
+        assert!(
+            move_across_devices(&path, &destination.path().join("missing/IMG_0001.JPG")).is_err()
+        );
+        assert!(path.exists());
+        return Ok(());
+    }
+
+    #[test]
+    fn move_photo_conflict_test() -> Result<(), PsError> {
+        let tempdir = tempfile::tempdir()?;
+        let new_path = tempdir.path().join("new_path.jpg");
+        std::fs::write(&new_path, "another photo")?;
+        let photo = PhotoBuilder::new()
+            .with_path(String::from("./test-assets/jpg/Canon_40D.jpg"))
+            .with_date(NaiveDate::from_ymd(2008, 5, 30).and_hms(15, 56, 1))
+            .with_new_path(new_path.to_str().unwrap().to_string())
+            .build();
+
+        // The file already there is left alone.
+        let err = move_photo(&photo, None, false, false).unwrap_err();
+        assert_eq!(err.kind(), &PsErrorKind::Conflict);
+        assert_eq!(std::fs::read(&new_path)?, b"another photo");
+        return Ok(());
+    }
+
+    #[test]
+    fn sort_files_interrupted_test() {
+        use crate::config::configurator::{Command, Config};
+        use crate::report::RunReport;
+        use std::sync::atomic::AtomicBool;
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let config = Config {
+            source: PathBuf::from("./test-assets/jpg"),
+            destination: tempdir.path().to_path_buf(),
+            logfile: None,
+            report: None,
+            dry_run: false,
+            copy: true,
+            resume: false,
+            undated_dir: "_undated".to_string(),
+            quarantine_dir: "_quarantine".to_string(),
+            destinations: Destinations::new(tempdir.path()),
+            min_date: discovery::default_min_date(),
+            clock_rules: Vec::new(),
+            write_dates: false,
+            name_template: None,
+            extractors: Registry::builtin(),
+            command: Command::Sort,
+        };
+        let files = vec![
+            PathBuf::from("./test-assets/jpg/Canon_40D.jpg"),
+            PathBuf::from("./test-assets/jpg/Nikon_D70.jpg"),
+        ];
+
+        // Shutdown requested before anything was moved: all files are still to be done.
+        let shutdown = AtomicBool::new(true);
+        let mut remaining = sort_files(&config, &files, None, &shutdown, &mut RunReport::new());
+        remaining.sort();
+        assert_eq!(remaining, files);
+        assert_eq!(std::fs::read_dir(tempdir.path()).unwrap().count(), 0);
+
+        let shutdown = AtomicBool::new(false);
+        let mut report = RunReport::new();
+        assert!(sort_files(&config, &files, None, &shutdown, &mut report).is_empty());
+        assert!(tempdir.path().join("2008/05/30/Canon_40D.jpg").exists());
+        assert_eq!(report.copied, 2);
+
+        // Sorting the same files again finds them already in place.
+        let mut report = RunReport::new();
+        sort_files(&config, &files, None, &shutdown, &mut report);
+        assert_eq!(report.duplicates, 2);
+        assert_eq!(report.exit_code(), 0);
+    }
+
+    #[test]
+    fn sort_files_renames_test() {
+        use crate::config::configurator::{Command, Config};
+        use crate::naming::DEFAULT_TEMPLATE;
+        use crate::report::RunReport;
+        use std::sync::atomic::AtomicBool;
+
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        // Same shot from two cards, but not the same file.
+        let mut data = std::fs::read("./test-assets/jpg/Canon_40D.jpg").unwrap();
+        let files = vec![
+            source.path().join("b/IMG_0001.JPG"),
+            source.path().join("a/IMG_0001.JPG"),
+        ];
+        for file in &files {
+            std::fs::create_dir(file.parent().unwrap()).unwrap();
+            std::fs::write(file, &data).unwrap();
+            data.push(0);
+        }
+
+        let config = Config {
+            source: source.path().to_path_buf(),
+            destination: destination.path().to_path_buf(),
+            logfile: None,
+            report: None,
+            dry_run: false,
+            copy: true,
+            resume: false,
+            undated_dir: "_undated".to_string(),
+            quarantine_dir: "_quarantine".to_string(),
+            destinations: Destinations::new(destination.path()),
+            min_date: discovery::default_min_date(),
+            clock_rules: Vec::new(),
+            write_dates: false,
+            name_template: Some(DEFAULT_TEMPLATE.to_string()),
+            extractors: Registry::builtin(),
+            command: Command::Sort,
+        };
+        let shutdown = AtomicBool::new(false);
+        sort_files(&config, &files, None, &shutdown, &mut RunReport::new());
+        // Numbered by source path, not by the order files were listed in.
+        let day = destination.path().join("2008/05/30");
+        assert_eq!(
+            std::fs::read(day.join("20080530_155601_01.jpg")).unwrap(),
+            std::fs::read(&files[1]).unwrap()
+        );
+        assert_eq!(
+            std::fs::read(day.join("20080530_155601_02.jpg")).unwrap(),
+            std::fs::read(&files[0]).unwrap()
+        );
+
+        // Running again gives the same names, so nothing new is copied.
+        let mut report = RunReport::new();
+        sort_files(&config, &files, None, &shutdown, &mut report);
+        assert_eq!(report.duplicates, 2);
+    }
+
+    #[test]
+    fn sort_files_after_write_dates_test() {
+        use crate::clock::{ClockRule, ClockRuleSpec};
+        use crate::config::configurator::{Command, Config};
+        use crate::index::Index;
+        use crate::naming::DEFAULT_TEMPLATE;
+        use crate::report::RunReport;
+        use std::sync::atomic::AtomicBool;
+
+        let files = vec![PathBuf::from("./test-assets/jpg/Canon_40D.jpg")];
+        let shutdown = AtomicBool::new(false);
+        // Whether files are renamed or keep their names, the corrected date written into the copy
+        // doesn't make it look like a different file the next time.
+        for name_template in &[Some(DEFAULT_TEMPLATE.to_string()), None] {
+            let destination = tempfile::tempdir().unwrap();
+            let rule = ClockRule::new(ClockRuleSpec {
+                model: Some("Canon EOS 40D".to_string()),
+                offset: "+01:00".to_string(),
+                ..Default::default()
+            })
+            .unwrap();
+            let config = Config {
+                source: PathBuf::from("./test-assets/jpg"),
+                destination: destination.path().to_path_buf(),
+                logfile: None,
+                report: None,
+                dry_run: false,
+                copy: true,
+                resume: false,
+                undated_dir: "_undated".to_string(),
+                quarantine_dir: "_quarantine".to_string(),
+                destinations: Destinations::new(destination.path()),
+                min_date: discovery::default_min_date(),
+                clock_rules: vec![rule],
+                write_dates: true,
+                name_template: name_template.clone(),
+                extractors: Registry::builtin(),
+                command: Command::Sort,
+            };
+            let index = Index::open(destination.path()).unwrap();
+
+            let mut report = RunReport::new();
+            sort_files(&config, &files, Some(&index), &shutdown, &mut report);
+            assert_eq!((report.copied, report.dates_written), (1, 1));
+            assert_eq!(report.destinations["photos"].files, 1);
+
+            let mut report = RunReport::new();
+            sort_files(&config, &files, Some(&index), &shutdown, &mut report);
+            assert_eq!(report.duplicates, 1);
+            assert!(report.destinations.is_empty());
+            assert_eq!(report.exit_code(), 0);
+            let day = destination.path().join("2008/05/30");
+            assert_eq!(std::fs::read_dir(&day).unwrap().count(), 1);
+        }
+    }
+
+    #[test]
+    fn convert_files_sets_aside_test() {
+        use crate::config::configurator::{Command, Config};
+        use crate::convert_files;
+
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        std::fs::create_dir(source.path().join("card")).unwrap();
+        std::fs::copy(
+            "./test-assets/jpg/Canon_40D.jpg",
+            source.path().join("card/a.jpg"),
+        )
+        .unwrap();
+        // A JPEG with nothing but start and end markers has no date anywhere.
+        std::fs::write(source.path().join("card/blank.jpg"), b"\xff\xd8\xff\xd9").unwrap();
+        std::fs::write(source.path().join("notes.txt"), "hello").unwrap();
+        // Neither hidden files nor the structure of an AVCHD card are set aside.
+        std::fs::write(source.path().join(".DS_Store"), "").unwrap();
+        std::fs::create_dir_all(source.path().join("card/BDMV/PLAYLIST")).unwrap();
+        std::fs::write(source.path().join("card/BDMV/PLAYLIST/00000.MPL"), "MPLS").unwrap();
+
+        let config = Config {
+            source: source.path().to_path_buf(),
+            destination: destination.path().to_path_buf(),
+            logfile: None,
+            report: None,
+            dry_run: false,
+            copy: false,
+            resume: false,
+            undated_dir: "_undated".to_string(),
+            quarantine_dir: "_quarantine".to_string(),
+            destinations: Destinations::new(destination.path()),
+            min_date: discovery::default_min_date(),
+            clock_rules: Vec::new(),
+            write_dates: false,
+            name_template: None,
+            extractors: Registry::builtin(),
+            command: Command::Sort,
+        };
+        let report = convert_files(&config).unwrap();
+
+        assert_eq!(report.moved, 1);
+        assert_eq!(report.undated, 1);
+        assert_eq!(report.quarantined, 1);
+        assert_eq!(report.set_aside.len(), 2);
+        assert_eq!(report.exit_code(), 0);
+        assert!(destination.path().join("2008/05/30/a.jpg").exists());
+        assert!(destination.path().join("_undated/card/blank.jpg").exists());
+        assert!(destination.path().join("_quarantine/notes.txt").exists());
+        // Nothing is left behind in move mode.
+        assert!(!source.path().join("card/blank.jpg").exists());
+        assert!(!source.path().join("notes.txt").exists());
+        assert!(source.path().join(".DS_Store").exists());
+        assert!(source.path().join("card/BDMV/PLAYLIST/00000.MPL").exists());
+    }
+
+    #[test]
+    fn index_rebuild_with_disabled_extractor_test() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::config::configurator::{get_config, OutputFormat, QueryOptions};
+        use crate::index::Index;
+
+        let all = QueryOptions {
+            camera: None,
+            from: None,
+            to: None,
+            format: OutputFormat::Paths,
+        };
+        // no_exif.jpg is only dated by its embedded XMP.
+        for (options, dated) in &[(vec![], true), (vec!["--disable-extractor", "xmp"], false)] {
+            let library = tempfile::tempdir()?;
+            std::fs::copy(
+                "./test-assets/jpg/xmp/no_exif.jpg",
+                library.path().join("no_exif.jpg"),
+            )?;
+            let mut args = vec!["photosort"];
+            args.extend(options);
+            args.extend(&[
+                "index",
+                "rebuild",
+                "--dst",
+                library.path().to_str().unwrap(),
+            ]);
+            let config = get_config(Some(&args))?;
+            crate::run_index_command(&config)?;
+
+            let entries = Index::open(library.path())?.query(&all)?;
+            assert_eq!(entries.len(), 1);
+            assert_eq!(entries[0].date.is_some(), *dated, "{:?}", options);
+        }
+        return Ok(());
+    }
 }
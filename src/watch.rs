@@ -0,0 +1,225 @@
+use crate::config::configurator::{Config, WatchOptions};
+use crate::discovery::discovery;
+use crate::index::Index;
+use crate::pserror::error::{PsError, PsErrorKind};
+use crate::report::RunReport;
+use crate::{resume, sort_files, zipfiles};
+use log::{info, warn};
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+/// How long inotify events are coalesced before being delivered to us.
+const DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// How often pending files are re-checked and the shutdown flag polled.
+const TICK: Duration = Duration::from_secs(1);
+
+struct Pending {
+    size: u64,
+    since: Instant,
+}
+
+/// Files which have been seen but may still be written to, e.g. by a sync client.
+#[derive(Default)]
+struct PendingFiles {
+    files: HashMap<PathBuf, Pending>,
+}
+
+impl PendingFiles {
+    /// Records activity on `path`, restarting its settle period.
+    fn touch(&mut self, path: PathBuf, now: Instant) {
+        let size = file_size(&path).unwrap_or(0);
+        self.files.insert(path, Pending { size, since: now });
+    }
+
+    /// Removes and returns files whose size hasn't changed for at least `settle`.
+    fn take_stable(&mut self, now: Instant, settle: Duration) -> Vec<PathBuf> {
+        let mut stable = Vec::new();
+        self.files.retain(|path, pending| match file_size(path) {
+            // Deleted or renamed away before it settled.
+            None => false,
+            Some(size) if size != pending.size => {
+                pending.size = size;
+                pending.since = now;
+                true
+            }
+            Some(_) if now.duration_since(pending.since) >= settle => {
+                stable.push(path.clone());
+                false
+            }
+            Some(_) => true,
+        });
+        stable.sort();
+        return stable;
+    }
+
+    fn is_empty(&self) -> bool {
+        return self.files.is_empty();
+    }
+}
+
+fn file_size(path: &Path) -> Option<u64> {
+    return std::fs::metadata(path)
+        .ok()
+        .filter(|m| m.is_file())
+        .map(|m| m.len());
+}
+
+fn is_interesting(path: &Path) -> bool {
+    // Lossy conversion is good enough to look at the extension.
+    let name = path.to_string_lossy();
+    return discovery::is_supported_file(&name) || name.to_lowercase().ends_with(".zip");
+}
+
+/// Watches `config.source` and sorts files once they stop changing, until SIGTERM or SIGINT.
+/// Returns the report covering everything sorted while watching.
+pub fn watch(
+    config: &Config,
+    options: &WatchOptions,
+    index: Option<Index>,
+) -> Result<RunReport, PsError> {
+    let mut report = RunReport::new();
+    let shutdown = resume::register_shutdown()?;
+
+    let (tx, rx) = channel();
+    let mut watcher = notify::watcher(tx, DEBOUNCE)?;
+    watcher.watch(&config.source, RecursiveMode::Recursive)?;
+    info!("Watching {} for new files", config.source.display());
+
+    let settle = Duration::from_secs(options.settle_secs);
+    let mut pending = PendingFiles::default();
+
+    // Anything already sitting in the inbox is treated as just arrived.
+    let now = Instant::now();
+    for file in discovery::list_all_files(&config.source)? {
+        pending.touch(file, now);
+    }
+
+    while !shutdown.load(Ordering::Relaxed) {
+        match rx.recv_timeout(TICK) {
+            Ok(event) => match event {
+                DebouncedEvent::Create(path)
+                | DebouncedEvent::Write(path)
+                | DebouncedEvent::Rename(_, path)
+                    if is_interesting(&path) =>
+                {
+                    pending.touch(path, Instant::now());
+                }
+                DebouncedEvent::Error(err, path) => {
+                    warn!("Watch error for {:?}: {}", path, err);
+                }
+                _ => {}
+            },
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(PsError::new(
+                    PsErrorKind::IoError,
+                    format!("Stopped receiving events for {}", config.source.display()),
+                ));
+            }
+        }
+
+        if pending.is_empty() {
+            continue;
+        }
+
+        let ready = pending.take_stable(Instant::now(), settle);
+        if !ready.is_empty() {
+            process_ready(config, ready, index.as_ref(), &shutdown, &mut report);
+        }
+    }
+
+    info!(
+        "Received shutdown signal, stopped watching {}",
+        config.source.display()
+    );
+    return Ok(report);
+}
+
+/// Files left unprocessed on shutdown stay in the inbox and are picked up on the next start.
+fn process_ready(
+    config: &Config,
+    ready: Vec<PathBuf>,
+    index: Option<&Index>,
+    shutdown: &AtomicBool,
+    report: &mut RunReport,
+) {
+    info!("Sorting {} new files", ready.len());
+    let (zips, files): (Vec<PathBuf>, Vec<PathBuf>) = ready
+        .into_iter()
+        .partition(|p| p.to_string_lossy().to_lowercase().ends_with(".zip"));
+
+    for zip in zips {
+        if let Err(err) = zipfiles::process_zip_file(&zip, config, index, None, shutdown, report) {
+            warn!("Failed to process {:?}: {}", zip, err);
+            report.record_failure(&zip, &err);
+        }
+    }
+
+    if !files.is_empty() {
+        sort_files(config, &files, index, shutdown, report);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_take_stable_waits_for_size_to_settle() -> std::io::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("IMG_0001.jpg");
+        let mut file = std::fs::File::create(&path)?;
+        file.write_all(b"partial")?;
+
+        let settle = Duration::from_secs(2);
+        let start = Instant::now();
+        let mut pending = PendingFiles::default();
+        pending.touch(path.clone(), start);
+
+        assert!(pending
+            .take_stable(start + Duration::from_secs(1), settle)
+            .is_empty());
+
+        // Still being written: the settle period starts again.
+        file.write_all(b" and the rest")?;
+        assert!(pending
+            .take_stable(start + Duration::from_secs(3), settle)
+            .is_empty());
+        assert!(pending
+            .take_stable(start + Duration::from_secs(4), settle)
+            .is_empty());
+
+        assert_eq!(
+            pending.take_stable(start + Duration::from_secs(5), settle),
+            vec![path]
+        );
+        assert!(pending.is_empty());
+
+        return Ok(());
+    }
+
+    #[test]
+    fn test_take_stable_forgets_removed_files() -> std::io::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("IMG_0002.jpg");
+        std::fs::write(&path, b"data")?;
+
+        let start = Instant::now();
+        let mut pending = PendingFiles::default();
+        pending.touch(path.clone(), start);
+        std::fs::remove_file(&path)?;
+
+        assert!(pending
+            .take_stable(start + Duration::from_secs(10), Duration::from_secs(1))
+            .is_empty());
+        assert!(pending.is_empty());
+
+        return Ok(());
+    }
+}
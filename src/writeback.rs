@@ -0,0 +1,440 @@
+use crate::pserror::error::{PsError, PsErrorKind};
+use chrono::NaiveDateTime;
+use log::{info, warn};
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+const EXIF_IFD_POINTER: u16 = 0x8769;
+const DATE_TIME_ORIGINAL: u16 = 0x9003;
+const ASCII: u16 = 2;
+const LONG: u16 = 4;
+
+/// Largest JPEG segment, its length included.
+const MAX_SEGMENT_LEN: usize = 0xFFFF;
+
+/// Where a date has been written.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Written {
+    /// Into the file's own EXIF DateTimeOriginal.
+    Exif,
+    /// Into an XMP sidecar next to the file.
+    Sidecar(PathBuf),
+}
+
+fn unsupported(msg: &str) -> PsError {
+    return PsError::new(PsErrorKind::Unsupported, msg.to_string());
+}
+
+/// Writes `date` as the capture date of the file at `path`.
+///
+/// JPEG and TIFF files which already have an EXIF DateTimeOriginal get it overwritten in place: the
+/// value has a fixed size, so nothing else in the file moves and the image isn't re-encoded. JPEGs
+/// without one get it added to their EXIF, or a new EXIF segment, the image data is copied as it
+/// is. Any other file gets an XMP sidecar, `<file name>.xmp`, unless it already has one.
+pub fn write_date(path: &Path, date: NaiveDateTime) -> Result<Written, PsError> {
+    match patch_exif_date(path, date) {
+        Ok(()) => {
+            info!("Wrote date {} into EXIF of {}", date, path.display());
+            return Ok(Written::Exif);
+        }
+        Err(err) if err.kind() == &PsErrorKind::Unsupported => {
+            info!("Can't write EXIF of {}: {}", path.display(), err)
+        }
+        Err(err) => return Err(err.with_path(path)),
+    }
+
+    let sidecar = sidecar_path(path);
+    if sidecar.exists() {
+        warn!("Not overwriting existing sidecar {}", sidecar.display());
+        return Err(PsError::new(
+            PsErrorKind::Conflict,
+            format!("Sidecar {} already exists", sidecar.display()),
+        )
+        .with_path(path));
+    }
+    std::fs::write(&sidecar, xmp_packet(date))?;
+    info!("Wrote date {} into {}", date, sidecar.display());
+    return Ok(Written::Sidecar(sidecar));
+}
+
+pub fn sidecar_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".xmp");
+    return PathBuf::from(name);
+}
+
+fn xmp_packet(date: NaiveDateTime) -> String {
+    let date = date.format("%Y-%m-%dT%H:%M:%S");
+    return format!(
+        "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n\
+         <x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\n \
+         <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n  \
+         <rdf:Description rdf:about=\"\"\n    \
+         xmlns:exif=\"http://ns.adobe.com/exif/1.0/\"\n    \
+         xmlns:photoshop=\"http://ns.adobe.com/photoshop/1.0/\"\n    \
+         exif:DateTimeOriginal=\"{0}\"\n    \
+         photoshop:DateCreated=\"{0}\"/>\n \
+         </rdf:RDF>\n\
+         </x:xmpmeta>\n\
+         <?xpacket end=\"w\"?>\n",
+        date
+    );
+}
+
+/// Byte order of a TIFF structure.
+#[derive(Clone, Copy)]
+struct Tiff<'a> {
+    data: &'a [u8],
+    little_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    fn u16(&self, at: usize) -> Option<u16> {
+        let b = self.data.get(at..at + 2)?;
+        return Some(if self.little_endian {
+            u16::from_le_bytes([b[0], b[1]])
+        } else {
+            u16::from_be_bytes([b[0], b[1]])
+        });
+    }
+
+    fn u32(&self, at: usize) -> Option<u32> {
+        let b = self.data.get(at..at + 4)?;
+        return Some(if self.little_endian {
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+        } else {
+            u32::from_be_bytes([b[0], b[1], b[2], b[3]])
+        });
+    }
+
+    fn bytes16(&self, value: u16) -> [u8; 2] {
+        return if self.little_endian {
+            value.to_le_bytes()
+        } else {
+            value.to_be_bytes()
+        };
+    }
+
+    fn bytes32(&self, value: u32) -> [u8; 4] {
+        return if self.little_endian {
+            value.to_le_bytes()
+        } else {
+            value.to_be_bytes()
+        };
+    }
+
+    /// Returns where the entry of `tag` in the IFD at `ifd` is.
+    fn entry_position(&self, ifd: usize, tag: u16) -> Option<usize> {
+        let count = self.u16(ifd)? as usize;
+        for i in 0..count {
+            let entry = ifd + 2 + i * 12;
+            if self.u16(entry)? == tag {
+                return Some(entry);
+            }
+        }
+        return None;
+    }
+
+    /// Returns type, count and value (or offset of the value) of `tag` in the IFD at `ifd`.
+    fn find_entry(&self, ifd: usize, tag: u16) -> Option<(u16, u32, u32)> {
+        let entry = self.entry_position(ifd, tag)?;
+        return Some((
+            self.u16(entry + 2)?,
+            self.u32(entry + 4)?,
+            self.u32(entry + 8)?,
+        ));
+    }
+
+    fn entry(&self, tag: u16, field_type: u16, count: u32, value: u32) -> [u8; 12] {
+        let mut entry = [0u8; 12];
+        entry[..2].copy_from_slice(&self.bytes16(tag));
+        entry[2..4].copy_from_slice(&self.bytes16(field_type));
+        entry[4..8].copy_from_slice(&self.bytes32(count));
+        entry[8..].copy_from_slice(&self.bytes32(value));
+        return entry;
+    }
+
+    fn ifd(&self, entries: &[[u8; 12]], next: u32) -> Vec<u8> {
+        let mut ifd = self.bytes16(entries.len() as u16).to_vec();
+        for entry in entries {
+            ifd.extend_from_slice(entry);
+        }
+        ifd.extend_from_slice(&self.bytes32(next));
+        return ifd;
+    }
+
+    /// Appends a copy of the IFD at `ifd` to `out`, with `extra` instead of any entry of its tag,
+    /// and returns where the copy is. The values the entries point to stay where they are.
+    fn append_ifd(&self, out: &mut Vec<u8>, ifd: usize, extra: [u8; 12]) -> Option<u32> {
+        let count = self.u16(ifd)? as usize;
+        let tag = |entry: &[u8; 12]| {
+            Tiff {
+                data: entry,
+                little_endian: self.little_endian,
+            }
+            .u16(0)
+        };
+        let mut entries = Vec::new();
+        for i in 0..count {
+            let mut entry = [0u8; 12];
+            entry.copy_from_slice(self.data.get(ifd + 2 + i * 12..ifd + 14 + i * 12)?);
+            if tag(&entry) != tag(&extra) {
+                entries.push(entry);
+            }
+        }
+        entries.push(extra);
+        // Entries have to be sorted by tag.
+        entries.sort_by_key(|entry| tag(entry));
+        let next = self.u32(ifd + 2 + count * 12)?;
+        return Some(append(out, &self.ifd(&entries, next)));
+    }
+}
+
+/// Appends `bytes` to a TIFF structure at the next word boundary, returning where they are.
+fn append(out: &mut Vec<u8>, bytes: &[u8]) -> u32 {
+    if out.len() % 2 == 1 {
+        out.push(0);
+    }
+    let offset = out.len() as u32;
+    out.extend_from_slice(bytes);
+    return offset;
+}
+
+/// Copy of the TIFF structure `data` with `date` as DateTimeOriginal. Nothing already there moves:
+/// the date, a new or extended EXIF IFD and, if it didn't point to an EXIF IFD yet, IFD0 are added
+/// to the end.
+fn add_date_time_original(data: &[u8], date: NaiveDateTime) -> Option<Vec<u8>> {
+    if !data.starts_with(b"II*\0") && !data.starts_with(b"MM\0*") {
+        return None;
+    }
+    let tiff = Tiff {
+        data,
+        little_endian: data.starts_with(b"II"),
+    };
+    let ifd0 = tiff.u32(4)? as usize;
+    let mut out = data.to_vec();
+    let value = format!("{}\0", date.format("%Y:%m:%d %H:%M:%S"));
+    let value_offset = append(&mut out, value.as_bytes());
+    let date_entry = tiff.entry(DATE_TIME_ORIGINAL, ASCII, value.len() as u32, value_offset);
+
+    match tiff.find_entry(ifd0, EXIF_IFD_POINTER) {
+        Some((_, _, exif_ifd)) => {
+            let exif_ifd = tiff.append_ifd(&mut out, exif_ifd as usize, date_entry)?;
+            let pointer = tiff.entry_position(ifd0, EXIF_IFD_POINTER)? + 8;
+            out[pointer..pointer + 4].copy_from_slice(&tiff.bytes32(exif_ifd));
+        }
+        None => {
+            let exif_ifd = append(&mut out, &tiff.ifd(&[date_entry], 0));
+            let pointer = tiff.entry(EXIF_IFD_POINTER, LONG, 1, exif_ifd);
+            let ifd0 = tiff.append_ifd(&mut out, ifd0, pointer)?;
+            out[4..8].copy_from_slice(&tiff.bytes32(ifd0));
+        }
+    }
+    return Some(out);
+}
+
+/// Markers and extents, length included, of the JPEG segments before the image data.
+fn jpeg_segments(data: &[u8]) -> Vec<(u8, Range<usize>)> {
+    let mut segments = Vec::new();
+    let mut pos = 2;
+    while pos + 4 <= data.len() && data[pos] == 0xFF && data[pos + 1] != 0xDA {
+        let length = (data[pos + 2] as usize) << 8 | data[pos + 3] as usize;
+        if pos + 2 + length > data.len() {
+            break;
+        }
+        segments.push((data[pos + 1], pos..pos + 2 + length));
+        pos += 2 + length;
+    }
+    return segments;
+}
+
+/// Copy of the JPEG `data` with `date` as DateTimeOriginal, added to its EXIF segment or to a new
+/// one, which goes after the JFIF segment if there is one.
+fn add_exif_date(data: &[u8], date: NaiveDateTime) -> Option<Vec<u8>> {
+    let segments = jpeg_segments(data);
+    let exif = segments.iter().find(|(marker, range)| {
+        *marker == 0xE1 && data[range.start + 4..].starts_with(b"Exif\0\0")
+    });
+    let (tiff, replaced) = match exif {
+        Some((_, range)) => (
+            add_date_time_original(&data[range.start + 10..range.end], date)?,
+            range.clone(),
+        ),
+        None => {
+            let at = match segments.first() {
+                Some((0xE0, range)) => range.end,
+                _ => 2,
+            };
+            // Big-endian TIFF header and an empty IFD0.
+            let empty = b"MM\0*\0\0\0\x08\0\0\0\0\0\0";
+            (add_date_time_original(empty, date)?, at..at)
+        }
+    };
+    let length = 2 + 6 + tiff.len();
+    if length > MAX_SEGMENT_LEN {
+        return None;
+    }
+
+    let mut result = data[..replaced.start].to_vec();
+    result.extend_from_slice(&[0xFF, 0xE1]);
+    result.extend_from_slice(&(length as u16).to_be_bytes());
+    result.extend_from_slice(b"Exif\0\0");
+    result.extend(tiff);
+    result.extend_from_slice(&data[replaced.end..]);
+    return Some(result);
+}
+
+/// Returns where the TIFF structure holding EXIF starts in `data`.
+fn find_tiff(data: &[u8]) -> Option<usize> {
+    if data.starts_with(b"II*\0") || data.starts_with(b"MM\0*") {
+        return Some(0);
+    }
+    if !data.starts_with(b"\xff\xd8") {
+        return None;
+    }
+    let mut pos = 2;
+    // Segments up to the start of scan, looking for APP1 with EXIF.
+    while pos + 4 <= data.len() && data[pos] == 0xFF {
+        let marker = data[pos + 1];
+        let length = (data[pos + 2] as usize) << 8 | data[pos + 3] as usize;
+        if marker == 0xDA {
+            return None;
+        }
+        let segment = data.get(pos + 4..pos + 2 + length)?;
+        if marker == 0xE1 && segment.starts_with(b"Exif\0\0") {
+            return Some(pos + 4 + 6);
+        }
+        pos += 2 + length;
+    }
+    return None;
+}
+
+/// Finds the offset of the DateTimeOriginal value in `data`, if it can be overwritten in place.
+fn find_date_time_original(data: &[u8]) -> Option<usize> {
+    let start = find_tiff(data)?;
+    let tiff = Tiff {
+        data: &data[start..],
+        little_endian: data.get(start..start + 2)? == b"II",
+    };
+    let ifd0 = tiff.u32(4)? as usize;
+    let (_, _, exif_ifd) = tiff.find_entry(ifd0, EXIF_IFD_POINTER)?;
+    let (field_type, count, offset) = tiff.find_entry(exif_ifd as usize, DATE_TIME_ORIGINAL)?;
+    // "YYYY:MM:DD HH:MM:SS" and NUL, which never fits into the entry itself.
+    if field_type != ASCII || count < 20 {
+        return None;
+    }
+    let offset = offset as usize;
+    tiff.data.get(offset..offset + 19)?;
+    return Some(start + offset);
+}
+
+fn patch_exif_date(path: &Path, date: NaiveDateTime) -> Result<(), PsError> {
+    let data = std::fs::read(path)?;
+    let offset = match find_date_time_original(&data) {
+        Some(offset) => offset,
+        None if data.starts_with(b"\xff\xd8") => {
+            let updated = match add_exif_date(&data, date) {
+                Some(updated) => updated,
+                None => return Err(unsupported("Can't add EXIF DateTimeOriginal")),
+            };
+            // Written aside and renamed, so that the file is never left half-written.
+            let mut partial_path = path.as_os_str().to_os_string();
+            partial_path.push(".partial");
+            std::fs::write(&partial_path, updated)?;
+            std::fs::rename(&partial_path, path)?;
+            return Ok(());
+        }
+        None => return Err(unsupported("No EXIF DateTimeOriginal to overwrite")),
+    };
+
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    file.seek(SeekFrom::Start(offset as u64))?;
+    file.write_all(date.format("%Y:%m:%d %H:%M:%S").to_string().as_bytes())?;
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use exif::{In, Tag};
+
+    #[test]
+    fn test_write_date_into_exif() -> Result<(), PsError> {
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("Canon_40D.jpg");
+        std::fs::copy("./test-assets/jpg/Canon_40D.jpg", &path)?;
+        let size = std::fs::metadata(&path)?.len();
+
+        let date = NaiveDate::from_ymd(2021, 3, 6).and_hms(16, 47, 13);
+        assert_eq!(write_date(&path, date)?, Written::Exif);
+
+        let exif = read_exif(&path);
+        let field = exif.get_field(Tag::DateTimeOriginal, In::PRIMARY).unwrap();
+        assert_eq!(field.display_value().to_string(), "2021-03-06 16:47:13");
+        assert_eq!(std::fs::metadata(&path)?.len(), size);
+        return Ok(());
+    }
+
+    fn read_exif(path: &Path) -> exif::Exif {
+        let file = std::fs::File::open(path).unwrap();
+        return exif::Reader::new()
+            .read_from_container(&mut std::io::BufReader::new(file))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_add_date_to_exif() -> Result<(), PsError> {
+        let temp_dir = tempfile::tempdir()?;
+        let date = NaiveDate::from_ymd(2021, 3, 6).and_hms(16, 47, 13);
+        let jfif = b"\xff\xe0\0\x10JFIF\0\x01\x01\0\0\x01\0\x01\0\0";
+        let scan = b"\xff\xda\0\x02image data\xff\xd9";
+
+        // No EXIF at all, as in screenshots and photos from messengers.
+        let path = temp_dir.path().join("screenshot.jpg");
+        std::fs::write(&path, [&b"\xff\xd8"[..], jfif, scan].concat())?;
+        assert_eq!(write_date(&path, date)?, Written::Exif);
+        let exif = read_exif(&path);
+        let field = exif.get_field(Tag::DateTimeOriginal, In::PRIMARY).unwrap();
+        assert_eq!(field.display_value().to_string(), "2021-03-06 16:47:13");
+        let data = std::fs::read(&path)?;
+        assert!(data[2..].starts_with(jfif));
+        assert!(data.ends_with(scan));
+
+        // EXIF with an orientation, but no EXIF IFD.
+        let mut tiff = b"II*\0\x08\0\0\0\x01\0".to_vec();
+        tiff.extend_from_slice(&[0x12, 0x01, 3, 0, 1, 0, 0, 0, 6, 0, 0, 0, 0, 0, 0, 0]);
+        let mut app1 = vec![0xFF, 0xE1, 0, (2 + 6 + tiff.len()) as u8];
+        app1.extend_from_slice(b"Exif\0\0");
+        app1.extend(tiff);
+        let path = temp_dir.path().join("rotated.jpg");
+        std::fs::write(&path, [&b"\xff\xd8"[..], &app1, scan].concat())?;
+        assert_eq!(write_date(&path, date)?, Written::Exif);
+        let exif = read_exif(&path);
+        assert!(exif.get_field(Tag::DateTimeOriginal, In::PRIMARY).is_some());
+        let orientation = exif.get_field(Tag::Orientation, In::PRIMARY).unwrap();
+        assert_eq!(orientation.value.get_uint(0), Some(6));
+        assert!(!temp_dir.path().join("rotated.jpg.xmp").exists());
+        return Ok(());
+    }
+
+    #[test]
+    fn test_write_date_into_sidecar() -> Result<(), PsError> {
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("clip.mp4");
+        std::fs::write(&path, "not really a video")?;
+
+        let date = NaiveDate::from_ymd(2021, 3, 6).and_hms(16, 47, 13);
+        let sidecar = temp_dir.path().join("clip.mp4.xmp");
+        assert_eq!(write_date(&path, date)?, Written::Sidecar(sidecar.clone()));
+        assert!(std::fs::read_to_string(&sidecar)?
+            .contains("exif:DateTimeOriginal=\"2021-03-06T16:47:13\""));
+        assert_eq!(std::fs::read_to_string(&path)?, "not really a video");
+
+        // An existing sidecar is left alone.
+        assert!(write_date(&path, date).is_err());
+        return Ok(());
+    }
+}
@@ -0,0 +1,355 @@
+use crate::bmff;
+use crate::discovery::discovery::media_kind;
+use crate::extract::Extracted;
+use crate::photo::{MediaKind, Metadata};
+use crate::pserror::error::{PsError, PsErrorKind};
+use crate::writeback::sidecar_path;
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+use log::{info, warn};
+use roxmltree::{Document, Node};
+use std::path::{Path, PathBuf};
+
+const RDF: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#";
+const XML: &str = "http://www.w3.org/XML/1998/namespace";
+const XMP: &str = "http://ns.adobe.com/xap/1.0/";
+const EXIF: &str = "http://ns.adobe.com/exif/1.0/";
+const TIFF: &str = "http://ns.adobe.com/tiff/1.0/";
+const PHOTOSHOP: &str = "http://ns.adobe.com/photoshop/1.0/";
+const DC: &str = "http://purl.org/dc/elements/1.1/";
+
+/// Date properties in order of preference, with the prefix they are reported under.
+const DATES: [(&str, &str, &str); 3] = [
+    (EXIF, "exif", "DateTimeOriginal"),
+    (PHOTOSHOP, "photoshop", "DateCreated"),
+    (XMP, "xmp", "CreateDate"),
+];
+
+/// Start and end of an XMP packet. Older writers use `xapmeta`, and some sidecars have nothing
+/// but the RDF.
+const PACKET_BOUNDS: [(&[u8], &[u8]); 3] = [
+    (b"<x:xmpmeta", b"</x:xmpmeta>"),
+    (b"<x:xapmeta", b"</x:xapmeta>"),
+    (b"<rdf:RDF", b"</rdf:RDF>"),
+];
+
+fn find(data: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    return data
+        .get(from..)?
+        .windows(needle.len())
+        .position(|w| w == needle)
+        .map(|at| at + from);
+}
+
+/// The first XMP packet in `data`. JPEG (APP1), PNG (uncompressed `iTXt`), TIFF (tag 700) and GIF
+/// (application extension) all store it as plain text, so it's found by looking for it.
+fn find_packet(data: &[u8]) -> Option<&[u8]> {
+    for (start, end) in &PACKET_BOUNDS {
+        if let Some(from) = find(data, start, 0) {
+            if let Some(to) = find(data, end, from) {
+                return Some(&data[from..to + end.len()]);
+            }
+        }
+    }
+    return None;
+}
+
+/// Sidecars which may hold XMP of the file at `path`: `IMG_0001.JPG.xmp`, as photosort and
+/// darktable write them, and `IMG_0001.xmp`, as Lightroom does.
+pub fn sidecar_paths(path: &Path) -> Vec<PathBuf> {
+    if let Some(extension) = path.extension() {
+        if extension.eq_ignore_ascii_case("xmp") {
+            return Vec::new();
+        }
+    }
+    return vec![sidecar_path(path), path.with_extension("xmp")];
+}
+
+/// The XMP packet embedded in the file itself, if it has one.
+fn embedded_packet(path: &Path) -> Result<Option<Vec<u8>>, PsError> {
+    let kind = media_kind(&path.to_string_lossy());
+    if kind == Some(MediaKind::Video) || kind == Some(MediaKind::Audio) {
+        return match bmff::read_xmp_packet(path) {
+            Ok(packet) => Ok(packet),
+            Err(err) => {
+                info!("Can't look for XMP in {:?}: {}", path, err);
+                Ok(None)
+            }
+        };
+    }
+    let data = std::fs::read(path)?;
+    return Ok(find_packet(&data).map(|packet| packet.to_vec()));
+}
+
+/// Reads dates and metadata from the XMP sidecar of the file and from XMP embedded in it. The
+/// sidecar comes first, as that is where editors keep what has been changed since the file was
+/// taken; anything it doesn't tell is taken from the file.
+pub fn read_xmp(path: &Path) -> Result<Extracted, PsError> {
+    let mut packets = Vec::new();
+    for sidecar in sidecar_paths(path) {
+        if sidecar.is_file() {
+            packets.push(("XMP sidecar", std::fs::read(&sidecar)?));
+        }
+    }
+    if let Some(packet) = embedded_packet(path)? {
+        packets.push(("XMP", packet));
+    }
+    if packets.is_empty() {
+        return Err(PsError::new(
+            PsErrorKind::NoDateField,
+            format!("No XMP in {}", path.display()),
+        ));
+    }
+
+    let mut extracted = Extracted::default();
+    for (source, packet) in packets {
+        let packet = match find_packet(&packet) {
+            Some(packet) => String::from_utf8_lossy(packet),
+            None => {
+                warn!("No XMP packet in {} of {:?}", source, path);
+                continue;
+            }
+        };
+        match parse_packet(&packet, source) {
+            Ok(found) => {
+                if extracted.dates.is_empty() {
+                    extracted.subsec = found.subsec;
+                }
+                extracted.dates.extend(found.dates);
+                extracted.metadata.fill_from(found.metadata);
+            }
+            Err(err) => warn!("Couldn't read {} of {:?}: {}", source, path, err),
+        }
+    }
+    return Ok(extracted);
+}
+
+/// Values of a property of any `rdf:Description`, written either as an attribute or as an element
+/// holding text or an `rdf:Bag`, `rdf:Seq` or `rdf:Alt` of items. Of the items of an `rdf:Alt` the
+/// default language comes first.
+fn property(doc: &Document, namespace: &str, name: &str) -> Vec<String> {
+    let descriptions = doc
+        .descendants()
+        .filter(|n| n.has_tag_name((RDF, "Description")));
+    for description in descriptions {
+        if let Some(value) = description.attribute((namespace, name)) {
+            return vec![value.trim().to_string()];
+        }
+        let element = description
+            .children()
+            .find(|n| n.has_tag_name((namespace, name)));
+        if let Some(element) = element {
+            let mut items: Vec<Node> = element
+                .descendants()
+                .filter(|n| n.has_tag_name((RDF, "li")))
+                .collect();
+            if items.is_empty() {
+                return element
+                    .text()
+                    .map(|t| t.trim().to_string())
+                    .into_iter()
+                    .collect();
+            }
+            items.sort_by_key(|item| item.attribute((XML, "lang")) != Some("x-default"));
+            return items
+                .iter()
+                .filter_map(|item| item.text())
+                .map(|t| t.trim().to_string())
+                .filter(|t| !t.is_empty())
+                .collect();
+        }
+    }
+    return Vec::new();
+}
+
+/// Reads an XMP packet, naming the dates it has after `source`.
+fn parse_packet(packet: &str, source: &str) -> Result<Extracted, PsError> {
+    let doc = Document::parse(packet).map_err(|e| {
+        PsError::new(
+            PsErrorKind::FormatError,
+            format!("Error parsing XMP: {}", e),
+        )
+    })?;
+    let first = |namespace, name| property(&doc, namespace, name).into_iter().next();
+
+    let mut extracted = Extracted {
+        metadata: Metadata {
+            title: first(DC, "title"),
+            keywords: property(&doc, DC, "subject"),
+            rating: first(XMP, "Rating")
+                .and_then(|r| r.parse::<f64>().ok())
+                .map(|r| r.round() as i32),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    extracted.metadata.camera.make = first(TIFF, "Make");
+    extracted.metadata.camera.model = first(TIFF, "Model");
+
+    // Dates with a time are preferred over those with only a day, whatever property they're in.
+    let mut dates = Vec::new();
+    for (namespace, prefix, name) in &DATES {
+        if let Some(value) = first(namespace, name) {
+            match parse_xmp_date(&value) {
+                Some((date, has_time, subsec)) => dates.push((
+                    !has_time,
+                    format!("{} {}:{}", source, prefix, name),
+                    date,
+                    subsec,
+                )),
+                None => warn!("Couldn't parse XMP {}:{} {}", prefix, name, value),
+            }
+        }
+    }
+    dates.sort_by_key(|(day_only, _, _, _)| *day_only);
+    extracted.subsec = dates.first().and_then(|(_, _, _, subsec)| subsec.clone());
+    extracted.dates = dates
+        .into_iter()
+        .map(|(_, source, date, _)| (source, date))
+        .collect();
+    return Ok(extracted);
+}
+
+/// Parses an XMP date, `YYYY-MM-DD` optionally followed by `Thh:mm`, seconds, a fraction and a
+/// time zone. The time zone is dropped, keeping the local time as EXIF does. Returns the date,
+/// whether it has a time and the digits of the fraction of a second.
+pub fn parse_xmp_date(value: &str) -> Option<(NaiveDateTime, bool, Option<String>)> {
+    let value = value.trim();
+    let (day, time) = match value.find('T') {
+        Some(at) => (&value[..at], Some(&value[at + 1..])),
+        None => (value, None),
+    };
+    let day = NaiveDate::parse_from_str(day, "%Y-%m-%d").ok()?;
+    let time = match time {
+        Some(time) => time,
+        None => return Some((day.and_hms(0, 0, 0), false, None)),
+    };
+    let time = match time.find(&['Z', '+', '-'][..]) {
+        Some(at) => &time[..at],
+        None => time,
+    };
+    let (time, subsec) = match time.find('.') {
+        Some(at) => (&time[..at], Some(time[at + 1..].to_string())),
+        None => (time, None),
+    };
+    let time = NaiveTime::parse_from_str(time, "%H:%M:%S")
+        .or_else(|_| NaiveTime::parse_from_str(time, "%H:%M"))
+        .ok()?;
+    let subsec = subsec.filter(|s| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit()));
+    return Some((day.and_time(time), true, subsec));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bmff::tests::mp4_box;
+
+    const PACKET: &str = r#"<?xpacket begin="" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""
+    xmlns:xmp="http://ns.adobe.com/xap/1.0/"
+    xmlns:dc="http://purl.org/dc/elements/1.1/"
+    xmlns:ps="http://ns.adobe.com/photoshop/1.0/"
+    xmp:Rating="4"
+    ps:DateCreated="2019-07-14">
+   <xmp:CreateDate>2019-07-14T18:30:05.25+02:00</xmp:CreateDate>
+   <dc:title>
+    <rdf:Alt>
+     <rdf:li xml:lang="de">Seeufer</rdf:li>
+     <rdf:li xml:lang="x-default">Lakeside</rdf:li>
+    </rdf:Alt>
+   </dc:title>
+   <dc:subject>
+    <rdf:Bag><rdf:li>lake</rdf:li><rdf:li>sunset</rdf:li></rdf:Bag>
+   </dc:subject>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>
+<?xpacket end="w"?>"#;
+
+    #[test]
+    fn test_parse_packet() -> Result<(), PsError> {
+        let extracted = parse_packet(PACKET, "XMP")?;
+        // The day-only photoshop:DateCreated comes after the full xmp:CreateDate.
+        assert_eq!(
+            extracted.dates,
+            vec![
+                (
+                    "XMP xmp:CreateDate".to_string(),
+                    NaiveDate::from_ymd(2019, 7, 14).and_hms(18, 30, 5)
+                ),
+                (
+                    "XMP photoshop:DateCreated".to_string(),
+                    NaiveDate::from_ymd(2019, 7, 14).and_hms(0, 0, 0)
+                ),
+            ]
+        );
+        assert_eq!(extracted.subsec.as_deref(), Some("25"));
+        assert_eq!(extracted.metadata.title.as_deref(), Some("Lakeside"));
+        assert_eq!(extracted.metadata.keywords, vec!["lake", "sunset"]);
+        assert_eq!(extracted.metadata.rating, Some(4));
+        return Ok(());
+    }
+
+    #[test]
+    fn test_parse_xmp_date() {
+        let date = NaiveDate::from_ymd(2021, 3, 6).and_hms(16, 47, 13);
+        assert_eq!(
+            parse_xmp_date("2021-03-06T16:47:13-05:00"),
+            Some((date, true, None))
+        );
+        assert_eq!(
+            parse_xmp_date("2021-03-06T16:47:13.120Z"),
+            Some((date, true, Some("120".to_string())))
+        );
+        assert_eq!(
+            parse_xmp_date("2021-03-06T16:47"),
+            Some((date.date().and_hms(16, 47, 0), true, None))
+        );
+        assert_eq!(parse_xmp_date("2021"), None);
+    }
+
+    #[test]
+    fn test_read_xmp() -> Result<(), PsError> {
+        // Dated only by XMP, no EXIF at all.
+        let extracted = read_xmp(Path::new("./test-assets/jpg/xmp/no_exif.jpg"))?;
+        assert_eq!(
+            extracted.dates.first(),
+            Some(&(
+                "XMP xmp:CreateDate".to_string(),
+                NaiveDate::from_ymd(2013, 9, 23).and_hms(10, 9, 46)
+            ))
+        );
+
+        // A sidecar is read before the XMP of the file.
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("clip.mp4");
+        let mut uuid = bmff::XMP_UUID.to_vec();
+        uuid.extend_from_slice(PACKET.as_bytes());
+        let mut data = mp4_box(b"ftyp", b"isom\0\0\0\0");
+        data.extend(mp4_box(b"uuid", &uuid));
+        std::fs::write(&path, data)?;
+        std::fs::write(
+            temp_dir.path().join("clip.xmp"),
+            PACKET.replace("2019-07-14T18", "2020-01-02T10"),
+        )?;
+        let extracted = read_xmp(&path)?;
+        let sources: Vec<_> = extracted.dates.iter().map(|(s, _)| s.as_str()).collect();
+        assert_eq!(
+            sources,
+            vec![
+                "XMP sidecar xmp:CreateDate",
+                "XMP sidecar photoshop:DateCreated",
+                "XMP xmp:CreateDate",
+                "XMP photoshop:DateCreated"
+            ]
+        );
+        assert_eq!(
+            extracted.dates[0].1,
+            NaiveDate::from_ymd(2020, 1, 2).and_hms(10, 30, 5)
+        );
+
+        assert!(read_xmp(&temp_dir.path().join("missing.jpg")).is_err());
+        return Ok(());
+    }
+}
@@ -1,19 +1,38 @@
 use crate::config::configurator::Config;
 use crate::discovery::discovery;
 use crate::discovery::discovery::discover_file;
-use crate::pserror::error::{PsError, PsErrorKind};
-use crate::{move_photo, update_photo_new_path};
+use crate::index::Index;
+use crate::integrity;
+use crate::naming::Namer;
+use crate::pserror::error::{PsError, PsErrorKind, Stage};
+use crate::report::{Outcome, RunReport};
+use crate::{
+    apply_clock_rules, move_photo, record_destination, record_in_index, set_aside,
+    update_photo_new_path, write_back_date,
+};
+use chrono::{Local, NaiveDate, TimeZone};
 use futures::Future;
 use indicatif::{ProgressBar, ProgressStyle};
 use log::{info, warn};
 use std::fs::File;
-use std::path::Path;
-use tempfile::{NamedTempFile, TempDir};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
 use zip::ZipArchive;
 
-pub fn process_zip_file(file_path: &str, cfg: &Config) -> Result<u64, ()> {
-    let file = File::open(Path::new(file_path)).unwrap();
-    let mut zf = zip::ZipArchive::new(file).unwrap();
+/// Sorts supported files from the zip archive, returning how many were copied together with names
+/// of entries left unprocessed because `shutdown` was set. When `only` is given, entries not in it
+/// are skipped, which is how an interrupted run is resumed.
+pub fn process_zip_file(
+    file_path: &Path,
+    cfg: &Config,
+    index: Option<&Index>,
+    only: Option<&Vec<String>>,
+    shutdown: &AtomicBool,
+    report: &mut RunReport,
+) -> Result<(u64, Vec<String>), PsError> {
+    let file = File::open(file_path)?;
+    let mut zf = zip::ZipArchive::new(file).map_err(|e| PsError::from(e).with_path(file_path))?;
 
     let bar = ProgressBar::new(zf.len() as u64);
 
@@ -25,120 +44,325 @@ pub fn process_zip_file(file_path: &str, cfg: &Config) -> Result<u64, ()> {
     );
 
     let mut num_files_copied = 0;
-    let temp_dir = TempDir::new().unwrap();
-    let temp_dir_path = temp_dir.path();
+    // Entries are named in the order they are stored in, which is the same every time.
+    let mut namer = Namer::new(cfg.name_template.clone()).with_index(index);
 
     for i in 0..zf.len() {
+        if shutdown.load(Ordering::Relaxed) {
+            bar.abandon_with_message("Interrupted");
+            let remaining = (i..zf.len())
+                .filter_map(|j| zf.by_index(j).ok().map(|f| f.name().to_string()))
+                .filter(|name| match only {
+                    Some(only) => only.contains(name),
+                    None => true,
+                })
+                .collect();
+            return Ok((num_files_copied, remaining));
+        }
+
         bar.inc(1);
-        let result = process_ith_file(&cfg, &mut zf, i);
+        if let Some(only) = only {
+            match zf.by_index(i) {
+                Ok(entry) if only.iter().any(|name| name == entry.name()) => {}
+                _ => continue,
+            }
+        }
+
+        let result = process_ith_file(&cfg, &mut zf, i, file_path, index, &mut namer, report);
         match result {
-            Ok(u) => {
-                info!("Processed {}-th file", i);
-                num_files_copied += u as u64;
+            Ok(outcome) => {
+                info!("Processed {}-th file: {:?}", i, outcome);
+                if outcome == Outcome::Copied {
+                    num_files_copied += 1;
+                }
+                report.record(outcome);
             }
             Err(e) => {
                 warn!("Failed to process file {}: {:?}", i, e);
+                let name = match zf.by_index(i) {
+                    Ok(entry) => format!("{}:{}", file_path.display(), entry.name()),
+                    Err(_) => format!("{}:#{}", file_path.display(), i),
+                };
+                report.record_failure(Path::new(&name), &e);
             }
         }
     }
 
     bar.finish();
 
-    Ok((num_files_copied))
+    Ok((num_files_copied, Vec::new()))
 }
 
-fn process_ith_file(cfg: &&Config, zf: &mut ZipArchive<File>, i: usize) -> Result<u8, PsError> {
-    let mut file = zf.by_index(i).unwrap();
-    if file.name().ends_with("/") || !discovery::is_supported_file(file.name()) {
+fn process_ith_file(
+    cfg: &&Config,
+    zf: &mut ZipArchive<File>,
+    i: usize,
+    zip_path: &Path,
+    index: Option<&Index>,
+    namer: &mut Namer,
+    report: &mut RunReport,
+) -> Result<Outcome, PsError> {
+    let mut file = zf.by_index(i)?;
+    if file.name().ends_with("/") {
         // Directory, not interesting
-        return Err(PsError::new(
-            PsErrorKind::FileNotSupported,
-            format!("File not supported: {}", file.name()),
-        ));
+        return Ok(Outcome::Skipped);
+    }
+    let supported = discovery::is_supported_file(file.name());
+    if !supported {
+        info!("File not supported: {}", file.name());
     }
 
-    let mut temp_file = NamedTempFile::new();
+    // Entry names come from the archive, so only names which stay inside it are used as paths.
+    let (entry_name, file_name) = match file.enclosed_name() {
+        Some(name) if name.file_name().is_some() => {
+            (name.to_path_buf(), name.file_name().unwrap().to_os_string())
+        }
+        _ => {
+            return Err(PsError::new(
+                PsErrorKind::FileNotSupported,
+                format!("No usable file name in {}", file.name()),
+            ));
+        }
+    };
 
-    if temp_file.is_err() {
-        return Err(PsError::new(
-            PsErrorKind::IoError,
-            format!("IO Error: {:?}", temp_file.err().unwrap()),
-        ));
+    // The entry is extracted under its own name and modification time, which the filename and
+    // mtime fallbacks go by.
+    let temp_dir = tempfile::tempdir()?;
+    let temp_file_path = temp_dir.path().join(file_name);
+    let mut temp_file = File::create(&temp_file_path)?;
+    // A damaged archive fails here rather than half way through discovery.
+    let written = std::io::copy(&mut file, &mut temp_file)
+        .map_err(|e| PsError::from(e).at_stage(Stage::Discover))?;
+    if let Some(modified) = modification_time(&file.last_modified()) {
+        if let Err(err) = temp_file.set_modified(modified) {
+            warn!(
+                "Failed to set modification time of {}: {}",
+                file.name(),
+                err
+            );
+        }
     }
-
-    let mut temp_file = temp_file.unwrap();
-    let written = std::io::copy(&mut file, &mut temp_file).unwrap();
-    let temp_file_path = temp_file.path();
+    drop(temp_file);
+    let temp_file_path = temp_file_path.as_path();
 
     info!(
         "Extracted {} -> {}, {} bytes written",
         file.name(),
-        temp_file_path.to_str().unwrap(),
+        temp_file_path.display(),
         written
     );
 
-    let photo = discover_file(temp_file_path);
-    let new_path = Option::from(
-        Path::new(file.name())
-            .file_name()
-            .unwrap()
-            .to_str()
-            .unwrap(),
-    );
+    let photo = if supported {
+        integrity::check_file(temp_file_path, file.name()).and_then(|_| {
+            discover_file(temp_file_path, &cfg.extractors, cfg.min_date)
+                .map_err(|e| e.at_stage(Stage::Discover))
+        })
+    } else {
+        Err(PsError::new(
+            PsErrorKind::FileNotSupported,
+            "File type is not supported".to_string(),
+        )
+        .at_stage(Stage::Discover))
+    };
     return match photo {
         Ok(mut photo) => {
-            update_photo_new_path(&cfg.destination, &mut photo, new_path);
+            let source = PathBuf::from(format!("{}:{}", zip_path.display(), file.name()));
+            report.add_rejected_dates(&source, &photo);
+            apply_clock_rules(cfg, &mut photo, Some(&source), report);
+            update_photo_new_path(&cfg.destinations, &mut photo, entry_name.file_name(), namer);
             let move_file_result = move_photo(
                 &photo,
+                index,
                 // note that copy/move flag is ignored here as we
                 // are creating temp file which we move later
                 false,
                 cfg.dry_run,
             );
             match move_file_result {
-                Ok(_) => Ok(1),
+                Ok(outcome) => {
+                    if outcome == Outcome::Copied {
+                        let original_hash = write_back_date(cfg, &photo, report);
+                        let source = format!("{}:{}", zip_path.display(), file.name());
+                        record_in_index(index, &photo, &source, original_hash);
+                    }
+                    record_destination(cfg, &photo, entry_name.file_name(), outcome, report);
+                    Ok(outcome)
+                }
                 Err(e) => {
-                    warn!("Failed to move file to {}", new_path.unwrap());
-                    Err(e.into())
+                    warn!("Failed to move file to {:?}", photo.new_path());
+                    Err(e.at_stage(Stage::Transfer))
                 }
             }
         }
         Err(err) => {
             info!("Couldn't discover file {}: {:?}", file.name(), err);
-            Err(err)
+            let source = format!("{}:{}", zip_path.display(), file.name());
+            let err = err.with_path(source);
+            match set_aside(cfg, temp_file_path, &entry_name, false, &err, report) {
+                Ok(Some(outcome)) => Ok(outcome),
+                Ok(None) => Err(err),
+                Err(e) => Err(e.at_stage(Stage::Transfer)),
+            }
         }
     };
 }
 
+/// Zip entries store the local time they were last modified at, to two seconds. None if the entry
+/// has no valid time.
+fn modification_time(modified: &zip::DateTime) -> Option<SystemTime> {
+    let date = NaiveDate::from_ymd_opt(
+        modified.year() as i32,
+        modified.month() as u32,
+        modified.day() as u32,
+    )?
+    .and_hms_opt(
+        modified.hour() as u32,
+        modified.minute() as u32,
+        modified.second() as u32,
+    )?;
+    return Local
+        .from_local_datetime(&date)
+        .earliest()
+        .map(SystemTime::from);
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::config::configurator::Config;
+    use crate::config::configurator::{Command, Config};
+    use crate::discovery::discovery::default_min_date;
+    use crate::extract::Registry;
+    use crate::layout::Destinations;
+    use crate::report::RunReport;
     use crate::zipfiles::process_zip_file;
     use log::LevelFilter;
+    use std::path::Path;
+    use std::sync::atomic::AtomicBool;
     use walkdir::DirEntry;
 
+    fn test_config(source: &Path, destination: &Path) -> Config {
+        return Config {
+            source: source.to_path_buf(),
+            destination: destination.to_path_buf(),
+            logfile: None,
+            report: None,
+            dry_run: false,
+            copy: true,
+            resume: false,
+            undated_dir: "_undated".to_string(),
+            quarantine_dir: "_quarantine".to_string(),
+            destinations: Destinations::new(destination),
+            min_date: default_min_date(),
+            clock_rules: Vec::new(),
+            write_dates: false,
+            name_template: None,
+            extractors: Registry::builtin(),
+            command: Command::Sort,
+        };
+    }
+
     #[test]
     fn test_process_zip_file() {
         simple_logging::log_to_stderr(LevelFilter::Info);
 
         let temp_dir = tempfile::tempdir().unwrap();
         let source = "./test-assets/assets.zip";
-        let cfg = Config {
-            source: source.to_string(),
-            destination: temp_dir.path().to_str().unwrap().to_string(),
-            logfile: None,
-            dry_run: false,
-            copy: true,
-        };
+        let cfg = test_config(Path::new(source), temp_dir.path());
 
-        let num_files_copied = process_zip_file(source, &cfg).unwrap();
-        assert_eq!(num_files_copied, 55);
+        let shutdown = AtomicBool::new(false);
+        let mut report = RunReport::new();
+        let (num_files_copied, remaining) =
+            process_zip_file(Path::new(source), &cfg, None, None, &shutdown, &mut report).unwrap();
+        assert!(remaining.is_empty());
+        // corrupted.jpg is quarantined, and WWL_(Polaroid)_ION230.jpg is dated 2026-11-24, which
+        // is suspicious until then.
+        let future = report
+            .set_aside
+            .iter()
+            .filter(|s| s.reason.contains("in the future"))
+            .count();
+        assert_eq!(num_files_copied as usize + future, 54);
         let result: Vec<DirEntry> = walkdir::WalkDir::new(cfg.destination)
             .into_iter()
             .map(|e| e.unwrap())
             .filter(|e| e.path().is_file())
+            // Entries which couldn't be sorted are set aside, not copied.
+            .filter(|e| !e.path().to_string_lossy().contains("/_"))
             .collect();
 
         assert_eq!(num_files_copied, result.len() as u64);
     }
+
+    #[test]
+    fn test_process_zip_file_formats() {
+        use crate::discovery::discovery::tests::exif_samples;
+        use std::io::Write;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let source = temp_dir.path().join("export.zip");
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&source).unwrap());
+        for (name, data) in exif_samples() {
+            zip.start_file(name, zip::write::FileOptions::default())
+                .unwrap();
+            zip.write_all(&data).unwrap();
+        }
+        zip.finish().unwrap();
+
+        let destination = temp_dir.path().join("library");
+        let cfg = test_config(&source, &destination);
+
+        let shutdown = AtomicBool::new(false);
+        let mut report = RunReport::new();
+        let (num_files_copied, _) =
+            process_zip_file(&source, &cfg, None, None, &shutdown, &mut report).unwrap();
+        assert_eq!(num_files_copied, 4);
+        for extension in &["tif", "webp", "avif", "jxl"] {
+            let path = destination.join(format!("2008/05/30/Canon_40D.{}", extension));
+            assert!(path.exists(), "{}", path.display());
+        }
+    }
+
+    #[test]
+    fn test_process_zip_file_fallbacks() {
+        use std::io::Write;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let source = temp_dir.path().join("export.zip");
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&source).unwrap());
+        let modified = zip::DateTime::from_date_and_time(2019, 7, 1, 12, 0, 0).unwrap();
+        for name in &["Screenshot_20210306-164713.gif", "meme.gif"] {
+            let options = zip::write::FileOptions::default().last_modified_time(modified);
+            zip.start_file(*name, options).unwrap();
+            zip.write_all(b"GIF89a").unwrap();
+        }
+        zip.finish().unwrap();
+
+        let destination = temp_dir.path().join("library");
+        let cfg = test_config(&source, &destination);
+
+        let shutdown = AtomicBool::new(false);
+        let mut report = RunReport::new();
+        let (num_files_copied, _) =
+            process_zip_file(&source, &cfg, None, None, &shutdown, &mut report).unwrap();
+        assert_eq!(num_files_copied, 2);
+        for path in &[
+            "2021/03/06/Screenshot_20210306-164713.gif",
+            "2019/07/01/meme.gif",
+        ] {
+            let path = destination.join(path);
+            assert!(path.exists(), "{}", path.display());
+        }
+    }
+
+    #[test]
+    fn test_process_broken_zip_file() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let source = "./test-assets/jpg/corrupted.jpg";
+        let cfg = test_config(Path::new(source), temp_dir.path());
+
+        let shutdown = AtomicBool::new(false);
+        let mut report = RunReport::new();
+        assert!(
+            process_zip_file(Path::new(source), &cfg, None, None, &shutdown, &mut report).is_err()
+        );
+    }
 }